@@ -1,20 +1,26 @@
 //!`auxiliar` is a collection of tools to set acquisition conditions.
 use crate::errorlib::Tp3ErrorKind;
-use std::net::{TcpListener, TcpStream, SocketAddr};
-use std::io::{Read, Write};
+use crate::detectorlib::DetectorLayout;
+use crate::configlib::{Tp3Config, TdcConfig, CoincidenceConfig};
+use crate::tdclib::TdcType;
+use crate::packetlib::Packet;
+use crate::controllib::{self, AcquisitionControl};
+use serde::Deserialize;
+use std::net::TcpListener;
+use std::io::Write;
 use std::fs::File;
 //use std::{fs::{File, OpenOptions, create_dir_all}, path::Path};
 
-const CONFIG_SIZE: usize = 16;
+pub(crate) const CONFIG_SIZE: usize = 16;
 
-///Configures the detector for acquisition. Each new measurement must send 20 bytes
-///containing instructions.
-struct BytesConfig {
+///Configures the detector for acquisition. Each new measurement must send 16 bytes
+///containing instructions. This is the legacy message of `controllib`.
+pub(crate) struct BytesConfig {
     pub data: [u8; CONFIG_SIZE],
 }
 
 impl BytesConfig {
-    ///Set binning mode for 1x4 detector. `\x00` for unbinned and `\x01` for binned. Panics otherwise. Byte[0].
+    ///Set binning mode for 1x4 detector. `\x00` for unbinned and `\x01` for binned. Returns an error otherwise. Byte[0].
     fn bin(&self) -> Result<bool, Tp3ErrorKind> {
         match self.data[0] {
             0 => {
@@ -25,11 +31,11 @@ impl BytesConfig {
                 println!("Bin is True.");
                 Ok(true)
             },
-            _ => Err(Tp3ErrorKind::SetBin),
+            _ => Err(Tp3ErrorKind::SetBin(self.data[0])),
         }
     }
 
-    ///Set bytedepth. `\x00` for 1, `\x01` for 2 and `\x02` for 4. Panics otherwise. Byte[1].
+    ///Set bytedepth. `\x00` for 1, `\x01` for 2 and `\x02` for 4. Returns an error otherwise. Byte[1].
     fn bytedepth(&self) -> Result<usize, Tp3ErrorKind> {
         match self.data[1] {
             0 => {
@@ -44,11 +50,11 @@ impl BytesConfig {
                 println!("Bitdepth is 32.");
                 Ok(4)
             },
-            _ => Err(Tp3ErrorKind::SetByteDepth),
+            _ => Err(Tp3ErrorKind::SetByteDepth(self.data[1] as usize)),
         }
     }
 
-    ///Sums all arriving data. `\x00` for False, `\x01` for True. Panics otherwise. Byte[2].
+    ///Sums all arriving data. `\x00` for False, `\x01` for True. Returns an error otherwise. Byte[2].
     fn cumul(&self) -> Result<bool, Tp3ErrorKind> {
         match self.data[2] {
             0 => {
@@ -59,11 +65,11 @@ impl BytesConfig {
                 println!("Cumulation mode is ON.");
                 Ok(true)
             },
-            _ => Err(Tp3ErrorKind::SetCumul),
+            _ => Err(Tp3ErrorKind::SetCumul(self.data[2])),
         }
     }
 
-    ///Acquisition Mode. `\x00` for normal, `\x01` for spectral image and `\x02` for time-resolved. Panics otherwise. Byte[2..4].
+    ///Acquisition Mode. See `modelib::MODES` for the available modes. Byte[2..4].
     fn mode(&self) -> Result<u8, Tp3ErrorKind> {
         println!("Mode is: {}", self.data[3]);
         Ok(self.data[3])
@@ -97,7 +103,7 @@ impl BytesConfig {
                 println!("Entering in Chrono Mode.");
                 Ok(self.data[3])
             },
-            _ => Err(Tp3ErrorKind::SetMode),
+            _ => Err(Tp3ErrorKind::SetMode(self.data[3])),
         }*/
     }
 
@@ -181,8 +187,9 @@ impl BytesConfig {
     }
 
 
-    ///Create Settings struct from BytesConfig
-    fn create_settings(&self) -> Result<Settings, Tp3ErrorKind> {
+    ///Create Settings struct from BytesConfig. Fields absent from the legacy message take their
+    ///historical values, and the TDCs come from the configuration.
+    pub(crate) fn create_settings(&self, layout: DetectorLayout, tdc: TdcConfig) -> Result<Settings, Tp3ErrorKind> {
         let my_set = Settings {
             bin: self.bin()?,
             bytedepth: self.bytedepth()?,
@@ -196,7 +203,15 @@ impl BytesConfig {
             time_width: self.time_width(),
             spimoverscanx: self.spimoverscanx()?,
             spimoverscany: self.spimoverscany()?,
+            layout,
+            frame_tdc: tdc.frame,
+            spim_tdc: tdc.spim,
+            ref_tdc: tdc.reference,
+            laser_tdc: tdc.laser,
+            roi: None,
+            output: OutputFormat::HeaderAndFrame,
         };
+        println!("Received settings is {:?}. Mode is {}.", self.data, my_set.mode);
         Ok(my_set)
     }
 
@@ -214,6 +229,30 @@ impl Write for DebugIO {
 }
 
 
+///Region of interest, in pixels of the assembled image. Bounds are inclusive.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Roi {
+    pub x: (usize, usize),
+    pub y: (usize, usize),
+}
+
+impl Roi {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x.0 && x <= self.x.1 && y >= self.y.0 && y <= self.y.1
+    }
+}
+
+///What is sent to the client for each frame of the spectral modes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    ///A JSON header followed by the frame.
+    #[default]
+    HeaderAndFrame,
+    ///The frame only.
+    Frame,
+}
+
 ///`Settings` contains all relevant parameters for a given acquistion
 #[derive(Copy, Clone, Debug)]
 pub struct Settings {
@@ -229,65 +268,64 @@ pub struct Settings {
     pub time_width: usize,
     pub spimoverscanx: usize,
     pub spimoverscany: usize,
+    pub layout: DetectorLayout,
+    pub frame_tdc: TdcType,
+    pub spim_tdc: TdcType,
+    pub ref_tdc: TdcType,
+    pub laser_tdc: TdcType,
+    pub roi: Option<Roi>,
+    pub output: OutputFormat,
 }
 
 impl Settings {
 
-    ///Create Settings structure reading from a TCP.
-    pub fn create_settings(host_computer: [u8; 4], port: u16) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>), Tp3ErrorKind> {
+    ///Whether the electron is inside the region of interest. Always true without ROI.
+    #[inline]
+    pub fn in_roi<T: Packet + ?Sized>(&self, packet: &T) -> bool {
+        match self.roi {
+            Some(roi) => self.layout.x_y(packet).is_some_and(|(x, y)| roi.contains(x, y)),
+            None => true,
+        }
+    }
+
+    ///Create Settings structure reading from a TCP. The client can then control the acquisition
+    ///from the same socket. Debug mode is used if the client address can not be bound.
+    pub fn create_settings(config: &Tp3Config) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>, AcquisitionControl), Tp3ErrorKind> {
     
-        let mut _sock_vec: Vec<TcpStream> = Vec::new();
-        
-        let addrs = [
-            SocketAddr::from((host_computer, port)),
-            SocketAddr::from(([127, 0, 0, 1], port)),
-        ];
-        
-        let pack_listener = TcpListener::bind("127.0.0.1:8098").expect("Could not bind to TP3.");
-        let ns_listener = TcpListener::bind(&addrs[..]).expect("Could not bind to NS.");
+        let pack_listener = TcpListener::bind(&config.tp3_address).map_err(|e| Tp3ErrorKind::SocketBind(config.tp3_address.clone(), e))?;
+        let ns_listener = if config.debug {None} else {TcpListener::bind(&config.nionswift_address).ok()};
+        let (ns_listener, debug) = match ns_listener {
+            Some(listener) => (listener, false),
+            None => (TcpListener::bind(&config.debug_address).map_err(|e| Tp3ErrorKind::SocketBind(config.debug_address.clone(), e))?, true),
+        };
         println!("Packet Tcp socket connected at: {:?}", pack_listener);
         println!("Nionswift Tcp socket connected at: {:?}", ns_listener);
 
-        let debug: bool = match ns_listener.local_addr() {
-            Ok(val) if val == addrs[1] => true,
-            _ => false,
-        };
-
-        let (mut ns_sock, ns_addr) = ns_listener.accept().expect("Could not connect to Nionswift.");
+        let (mut ns_sock, ns_addr) = ns_listener.accept().map_err(|e| Tp3ErrorKind::SocketAccept("Nionswift", e))?;
         println!("Nionswift connected at {:?} and {:?}.", ns_addr, ns_sock);
         
-        let mut cam_settings = [0_u8; CONFIG_SIZE];
-        let my_config = {
-            match ns_sock.read(&mut cam_settings){
-                Ok(size) => {
-                    println!("Received {} bytes from NS.", size);
-                    BytesConfig{data: cam_settings}
-                },
-                Err(_) => panic!("Could not read cam initial settings."),
-            }
-        };
-        let my_settings = my_config.create_settings()?;
-        println!("Received settings is {:?}. Mode is {}.", cam_settings, my_settings.mode);
+        let my_settings = controllib::receive_settings(&mut ns_sock, config)?;
+        let control = AcquisitionControl::listen(&ns_sock)?;
 
         match debug {
             false => {
-                let (pack_sock, packet_addr) = pack_listener.accept().expect("Could not connect to TP3.");
+                let (pack_sock, packet_addr) = pack_listener.accept().map_err(|e| Tp3ErrorKind::SocketAccept("TP3", e))?;
                 println!("Localhost TP3 detected at {:?} and {:?}.", packet_addr, pack_sock);
-                Ok((my_settings, Box::new(pack_sock), Box::new(ns_sock)))
+                Ok((my_settings, Box::new(pack_sock), Box::new(ns_sock), control))
             },
             true => {
-                let file = match File::open("bin/Data/raw000000.tpx3") {
+                let file = match File::open(&config.debug_file) {
                     Ok(file) => file,
-                    Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
+                    Err(e) => return Err(Tp3ErrorKind::SetNoReadFile(config.debug_file.clone(), e)),
                 };
                 println!("Debug mode. Will one file a single time.");
-                Ok((my_settings, Box::new(file), Box::new(ns_sock)))
+                Ok((my_settings, Box::new(file), Box::new(ns_sock), control))
             },
         }
 
     }
 
-    fn create_spec_debug_settings(_config: &ConfigAcquisition) -> Settings  {
+    fn create_spec_debug_settings(config: &ConfigAcquisition) -> Settings  {
         Settings {
             bin: false,
             bytedepth: 4,
@@ -301,6 +339,13 @@ impl Settings {
             time_width: 1000,
             spimoverscanx: 1,
             spimoverscany: 1,
+            layout: config.layout,
+            frame_tdc: config.tdc.frame,
+            spim_tdc: config.tdc.spim,
+            ref_tdc: config.tdc.reference,
+            laser_tdc: config.tdc.laser,
+            roi: None,
+            output: OutputFormat::HeaderAndFrame,
         }
     }
     
@@ -318,11 +363,18 @@ impl Settings {
             time_width: 1000,
             spimoverscanx: 1,
             spimoverscany: 1,
+            layout: config.layout,
+            frame_tdc: config.tdc.frame,
+            spim_tdc: config.tdc.spim,
+            ref_tdc: config.tdc.reference,
+            laser_tdc: config.tdc.laser,
+            roi: None,
+            output: OutputFormat::HeaderAndFrame,
         }
     }
 
     
-    pub fn create_debug_settings(config: &ConfigAcquisition) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>), Tp3ErrorKind> {
+    pub fn create_debug_settings(config: &ConfigAcquisition) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>, AcquisitionControl), Tp3ErrorKind> {
     
         let my_settings = match config.is_spim {
             true => Settings::create_spim_debug_settings(config),
@@ -333,11 +385,11 @@ impl Settings {
 
         let in_file = match File::open(&config.file) {
             Ok(file) => file,
-            Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
+            Err(e) => return Err(Tp3ErrorKind::SetNoReadFile(config.file.clone(), e)),
         };
 
         println!("Spectra Debug mode. Will one file a single time.");
-        Ok((my_settings, Box::new(in_file), Box::new(DebugIO{})))
+        Ok((my_settings, Box::new(in_file), Box::new(DebugIO{}), AcquisitionControl::none()))
     }
     
 }
@@ -349,6 +401,9 @@ pub struct ConfigAcquisition {
     pub is_spim: bool,
     pub xspim: usize,
     pub yspim: usize,
+    pub layout: DetectorLayout,
+    pub tdc: TdcConfig,
+    pub coincidence: CoincidenceConfig,
 }
 
 impl ConfigAcquisition {
@@ -356,23 +411,32 @@ impl ConfigAcquisition {
         &self.file
     }
 
-    pub fn new(args: &[String]) -> Self {
-        if args.len() != 4+1 {
-            panic!("One must provide 04 ({} detected) arguments (file, is_spim, xspim, yspim).", args.len()-1);
+    ///Positional arguments, once `Tp3Config::load` removed the flags. The optional layout file
+    ///takes precedence over the one of the configuration.
+    pub fn new(args: &[String], config: &Tp3Config) -> Result<Self, Tp3ErrorKind> {
+        if args.len() != 4+1 && args.len() != 5+1 {
+            return Err(Tp3ErrorKind::SetArgument(format!("One must provide 04 or 05 ({} detected) arguments (file or folder, is_spim, xspim, yspim, [layout file]).", args.len()-1)));
         }
         let file = args[1].clone();
         let is_spim = args[2] == "1";
-        let xspim = args[3].parse::<usize>().unwrap();
-        let yspim = args[4].parse::<usize>().unwrap();
+        let xspim = args[3].parse::<usize>().map_err(|_| Tp3ErrorKind::SetArgument(format!("xspim must be a positive integer. Received {}.", args[3])))?;
+        let yspim = args[4].parse::<usize>().map_err(|_| Tp3ErrorKind::SetArgument(format!("yspim must be a positive integer. Received {}.", args[4])))?;
+        let layout = match args.get(5) {
+            Some(layout_file) => DetectorLayout::from_file(layout_file),
+            None => config.layout(),
+        }?;
         let my_config = 
         ConfigAcquisition {
             file,
             is_spim,
             xspim,
             yspim,
+            layout,
+            tdc: config.tdc,
+            coincidence: config.coincidence.clone(),
         };
         println!("Configuration for the coincidence measurement is {:?}", my_config);
-        my_config
+        Ok(my_config)
     }
 }
 
@@ -385,8 +449,8 @@ pub mod simple_log {
     use std::io;
     use crate::errorlib::Tp3ErrorKind;
 
-    pub fn start() -> io::Result<File> {
-        let dir = Path::new("Microscope/Log/");
+    pub fn start(log_dir: &str) -> io::Result<File> {
+        let dir = Path::new(log_dir);
         create_dir_all(&dir)?;
         let date = Local::now().format("%Y-%m-%d").to_string() + ".txt";
         let file_path = dir.join(&date);
@@ -411,7 +475,7 @@ pub mod simple_log {
         let date = Local::now().to_string();
         file.write_all(date.as_bytes())?;
         file.write_all(b" - ERROR ")?;
-        let error = format!("{:?}", error);
+        let error = format!("{}", error);
         file.write_all(error.as_bytes())?;
         file.write_all(b"\n")?;
         Ok(())
@@ -420,10 +484,11 @@ pub mod simple_log {
 
 ///`misc` are miscellaneous functions.
 pub mod misc {
-    use std::io::Read;
+    use std::io::{self, Read, Write, BufWriter};
     use crate::errorlib::Tp3ErrorKind;
     use std::net::TcpStream;
     use std::fs::File;
+    use std::path::Path;
 
     pub fn default_read_exact<R: Read + ?Sized>(this: &mut R, mut buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
         let mut size = 0;
@@ -435,13 +500,13 @@ pub mod misc {
                     let tmp = buf;
                     buf = &mut tmp[n..];
                 }
-                Err(_) => return Err(Tp3ErrorKind::TimepixReadLoop),
+                Err(e) => return Err(Tp3ErrorKind::TimepixReadLoop(e)),
             };
         };
         if size != 0 && size % 8 == 0 {
             Ok(size)
         } else {
-            Err(Tp3ErrorKind::TimepixReadOver)
+            Err(Tp3ErrorKind::TimepixReadOver(size))
         }
     }
 
@@ -474,4 +539,28 @@ pub mod misc {
     impl<R: Read + ?Sized> TimepixRead for Box<R> {}
     impl TimepixRead for TcpStream {}
     impl TimepixRead for File {}
+
+    ///`TeeRead` writes everything read from `reader` in `file`, so the raw data of an acquisition
+    ///can be saved while it is processed.
+    pub struct TeeRead<R> {
+        reader: R,
+        file: BufWriter<File>,
+    }
+
+    impl<R: Read> TeeRead<R> {
+        pub fn new(reader: R, path: &Path) -> Result<Self, Tp3ErrorKind> {
+            let file = File::create(path).map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))?;
+            Ok(TeeRead {reader, file: BufWriter::new(file)})
+        }
+    }
+
+    impl<R: Read> Read for TeeRead<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.reader.read(buf)?;
+            self.file.write_all(&buf[..size])?;
+            Ok(size)
+        }
+    }
+
+    impl<R: Read> TimepixRead for TeeRead<R> {}
 }
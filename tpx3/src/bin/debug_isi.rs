@@ -1,17 +1,27 @@
 use timepix3::tdclib::isi_box;
-use std::{thread, time};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::configlib::Tp3Config;
+use std::{env, thread, time};
 
-fn main() {
+fn debug_session(config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    let mut handler = isi_box::IsiBoxHandler::new(17, config.layout()?);
+    handler.bind_and_connect(&config.isi_box_address)?;
+    handler.configure_scan_parameters(32, 32, 8334);
+    let time = time::Duration::from_millis(100);
+    handler.start_index_threads();
+    for _ in 0..5 {
+        thread::sleep(time);
+        handler.send_to_external_socket()?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Tp3ErrorKind> {
+    let (config, _) = Tp3Config::load(&env::args().collect::<Vec<String>>())?;
     loop {
         println!("Starting new debug session...");
-        let mut handler = isi_box::IsiBoxHandler::new(17);
-        handler.bind_and_connect();
-        handler.configure_scan_parameters(32, 32, 8334);
-        let time = time::Duration::from_millis(100);
-        handler.start_index_threads();
-        for _ in 0..5 {
-            thread::sleep(time);
-            handler.send_to_external_socket();
+        if let Err(e) = debug_session(&config) {
+            println!("Error in the debug session. Message is: {}", e);
         }
     }
 }
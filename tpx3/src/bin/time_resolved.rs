@@ -1,23 +1,23 @@
 use timepix3::postlib::ntime_resolved::*;
-use timepix3::tdclib::TdcType;
-use std::fs;
+use timepix3::postlib::dataset::Dataset;
+use timepix3::configlib::Tp3Config;
+use std::env;
 
 fn main() -> Result<(), ErrorType> {
     let number_frames = 100; //Number of frames you wish to integrate;
     let spim_size = 32; //Size of the spim;
     
+    let (config, _) = Tp3Config::load(&env::args().collect::<Vec<String>>())?;
+    let layout = config.layout()?;
+    
     let mut my_vec: Vec<Box<dyn TimeTypes>> = Vec::new();
-    my_vec.push(Box::new(TimeSpectralSpatial::new(number_frames, spim_size, spim_size, true, TdcType::TdcOneFallingEdge, String::from("test/results"))?));
+    my_vec.push(Box::new(TimeSpectralSpatial::new(number_frames, spim_size, spim_size, true, config.tdc.spim, String::from("test/results"), layout)?));
     let mut specs = TimeSet {
         set: my_vec,
     };
 
-    let mut entries = fs::read_dir("backupPaper").expect("Could not read the directory.");
-    while let Some(x) = entries.next() {
-        let path = x.unwrap().path();
-        let dir = path.to_str().unwrap();
-        analyze_data(dir, &mut specs);
-    }
+    let dataset = Dataset::new("backupPaper")?;
+    analyze_data(&dataset, &mut specs)?;
 
     for spec in specs.set.iter_mut() {
         spec.display_info()?;
@@ -0,0 +1,27 @@
+use timepix3::postlib::calibration::*;
+use timepix3::postlib::dataset::Dataset;
+use timepix3::calibrationlib::TIME_CALIBRATION_FILE;
+use timepix3::configlib::Tp3Config;
+use timepix3::errorlib::Tp3ErrorKind;
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    let args: Vec<String> = env::args().collect();
+    let (config, args) = Tp3Config::load(&args)?;
+    if args.len() < 2 {
+        return Err(Tp3ErrorKind::SetArgument(String::from("One must provide at least one file or folder to derive the time calibration.")).into());
+    }
+
+    let mut calib_data = TimeCalibrationData::new(config.tdc.reference, config.coincidence.delay_time());
+    for path in &args[1..] {
+        println!("Looping over dataset {:?}", path);
+        calib_data.add_dataset(&Dataset::new(path)?)?;
+    }
+    
+    let calibration = calib_data.build()?;
+    calibration.to_file(TIME_CALIBRATION_FILE)?;
+    println!("Time calibration saved under {} name.", TIME_CALIBRATION_FILE);
+
+    Ok(())
+}
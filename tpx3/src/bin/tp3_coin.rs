@@ -1,28 +1,38 @@
 use timepix3::postlib::coincidence::*;
+use timepix3::postlib::dataset::Dataset;
 use timepix3::auxiliar::ConfigAcquisition;
+use timepix3::configlib::Tp3Config;
 use std::env;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args: Vec<String> = env::args().collect();
-    let config_set = ConfigAcquisition::new(&args);
-    let mut coinc_data = ElectronData::new(&config_set);
-    search_coincidence(&config_set.file(), &mut coinc_data)?;
-    
-    //let mut entries = fs::read_dir("DataCoinc")?;
-    //while let Some(x) = entries.next() {
-    //    let path = x?.path();
-    //    let dir = path.to_str().unwrap();
-    //    println!("Looping over file {:?}", dir);
-    //    search_coincidence(dir, &mut coinc_data)?;
-    //}
+    let (config, args) = Tp3Config::load(&args)?;
+    let config_set = ConfigAcquisition::new(&args, &config)?;
+    let dataset = Dataset::new(config_set.file())?;
+    let mut coinc_data = ElectronData::new(&config_set)?;
+    if config_set.coincidence.calibrate_delay {
+        calibrate_delay(&dataset, &mut coinc_data)?;
+    }
+    search_coincidence(&dataset, &mut coinc_data)?;
 
-    coinc_data.output_spectrum(true);
-    coinc_data.output_corr_spectrum(false);
-    coinc_data.output_relative_time();
-    coinc_data.output_dispersive();
-    coinc_data.output_non_dispersive();
-    coinc_data.output_spim_index();
+    coinc_data.output_spectrum(true)?;
+    coinc_data.output_corr_spectrum(false)?;
+    coinc_data.output_acc_spectrum(false)?;
+    coinc_data.output_subtracted_spectrum(false)?;
+    coinc_data.output_g2()?;
+    coinc_data.output_channel_spectra(false)?;
+    coinc_data.output_cross_channels(false)?;
+    coinc_data.output_channel()?;
+    coinc_data.output_relative_time()?;
+    coinc_data.output_dispersive()?;
+    coinc_data.output_non_dispersive()?;
+    coinc_data.output_spim_index()?;
+    if config_set.is_spim {coinc_data.output_subtracted_spim()?;}
+    coinc_data.output_energy()?;
+    coinc_data.output_tot()?;
+    coinc_data.output_cluster_size()?;
+    coinc_data.output_metadata()?;
 
     Ok(())
 }
@@ -0,0 +1,61 @@
+//!Writes a synthetic acquisition to a file or streams it to the acquisition socket.
+//!
+//!Usage: `tp3_simulator <file or "socket"> <duration (ms)> <electrons per second> <xspim> <yspim>`.
+//!A spim is scanned if `xspim` and `yspim` are greater than zero. Photons are sent on the reference
+//!TDC, `coincidence.delay` after their electron, if `simulation.coincidence_fraction` (for instance
+//!`--simulation-coincidence-fraction 0.5`) is greater than zero.
+
+use timepix3::simulationlib::*;
+use timepix3::packetlib::Timestamp;
+use timepix3::configlib::Tp3Config;
+use std::net::TcpStream;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().collect();
+    let (tp3_config, args) = Tp3Config::load(&args)?;
+    let output = args.get(1).map(|x| x.as_str()).unwrap_or("raw000000.tpx3");
+    let duration: u64 = args.get(2).map_or(Ok(1_000), |x| x.parse())?;
+    let electron_rate: f64 = args.get(3).map_or(Ok(1_000_000.0), |x| x.parse())?;
+    let xspim: usize = args.get(4).map_or(Ok(0), |x| x.parse())?;
+    let yspim: usize = args.get(5).map_or(Ok(0), |x| x.parse())?;
+    let coincidence_fraction = tp3_config.simulation.coincidence_fraction;
+
+    let layout = tp3_config.layout()?;
+    let config = SyntheticConfig {
+        layout,
+        duration: Timestamp::from_nanoseconds(duration * 1_000_000),
+        electron_rate,
+        spectrum: SpectrumShape::Peaks(vec![(128.0, 3.0, 0.1), (638.0, 10.0, 0.9)]),
+        scan: if xspim > 0 && yspim > 0 {
+            Some(ScanConfig {
+                xspim,
+                yspim,
+                pixel_time: Timestamp::from_nanoseconds(1_000),
+                flyback: Timestamp::from_nanoseconds(10_000),
+                frame_tdc: None,
+            })
+        } else {None},
+        photons: if coincidence_fraction > 0.0 {
+            Some(PhotonConfig {
+                tdc: tp3_config.tdc.reference,
+                coincidence_fraction,
+                delay: tp3_config.coincidence.delay_time(),
+                jitter: Timestamp::new(2),
+                dark_rate: 1_000.0,
+            })
+        } else {None},
+        ..SyntheticConfig::default()
+    };
+
+    let writer: Box<dyn Write> = match output {
+        "socket" => Box::new(TcpStream::connect(&tp3_config.tp3_address)?),
+        _ => Box::new(File::create(output)?),
+    };
+    let mut writer = Tpx3Writer::new(BufWriter::new(writer), layout);
+    let nevents = SyntheticStream::new(config).write_to(&mut writer)?;
+    println!("Synthetic events written to {}: {}. Number of packets: {}.", output, nevents, writer.packets());
+    Ok(())
+}
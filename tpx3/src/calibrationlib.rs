@@ -0,0 +1,220 @@
+//!`calibrationlib` holds the per-pixel calibration tables of the detector. Tables are indexed by
+//!the chip address of the pixel (see `pixel_index`), so they do not depend on the
+//!`detectorlib::DetectorLayout` in use.
+
+use crate::packetlib::{Packet, ELECTRON_ROLLOVER};
+use crate::detectorlib::{DetectorLayout, CHIP_SIZE};
+use crate::errorlib::Tp3ErrorKind;
+use std::fs;
+use std::io;
+
+///Time calibration file read by the post-processing when present in the working directory.
+pub const TIME_CALIBRATION_FILE: &str = "time_calibration.txt";
+///Number of pixels of a single chip.
+pub const PIXELS_PER_CHIP: usize = CHIP_SIZE * CHIP_SIZE;
+///Number of possible ToT values (10 bits).
+pub const TOT_VALUES: usize = 1024;
+
+///Index of the pixel in the calibration tables: chip by chip, row by row.
+#[inline]
+pub fn pixel_index<T: Packet + ?Sized>(packet: &T) -> usize {
+    packet.ci() * PIXELS_PER_CHIP + packet.y_raw() * CHIP_SIZE + packet.x_raw()
+}
+
+///`TimeCalibration` corrects the electron ToA of each pixel by a fixed offset and by a time-walk
+///that depends on the ToT of the hit. Both are given in units of 640 MHz (1.5625 ns) and are
+///subtracted from the measured time.
+///
+///The file holds two lines of comma separated values. The first one has the ToA offsets of every
+///pixel, ordered as in `pixel_index`, and the second one has the time-walk of each ToT value.
+#[derive(Clone)]
+pub struct TimeCalibration {
+    toa_offset: Vec<i32>,
+    time_walk: Vec<i32>,
+}
+
+impl TimeCalibration {
+    pub fn new(toa_offset: Vec<i32>, time_walk: Vec<i32>) -> Result<Self, Tp3ErrorKind> {
+        if !toa_offset.len().is_multiple_of(PIXELS_PER_CHIP) || time_walk.len() != TOT_VALUES {
+            return Err(Tp3ErrorKind::CalibrationBadFormat(format!("time calibration needs {} offsets per chip and {} time-walk values. Received {} and {}", PIXELS_PER_CHIP, TOT_VALUES, toa_offset.len(), time_walk.len())));
+        }
+        Ok(TimeCalibration {
+            toa_offset,
+            time_walk,
+        })
+    }
+
+    ///No correction at all.
+    pub fn empty() -> Self {
+        TimeCalibration {
+            toa_offset: Vec::new(),
+            time_walk: vec![0; TOT_VALUES],
+        }
+    }
+
+    ///Measured offsets of the EELS camera: a few column groups arrive 16 ticks late.
+    pub fn eels() -> Self {
+        let layout = DetectorLayout::eels();
+        let mut toa_offset = vec![0; layout.nchips() * PIXELS_PER_CHIP];
+        for (index, offset) in toa_offset.iter_mut().enumerate() {
+            let chip = layout.chip(index / PIXELS_PER_CHIP).unwrap();
+            let (x, _y) = chip.map(index % CHIP_SIZE, (index % PIXELS_PER_CHIP) / CHIP_SIZE);
+            if let 52..=61 | 308..=317 | 560..=575 | 580..=581 | 584..=585 | 592..=593 | 820..=829 = x {
+                *offset = 16;
+            }
+        }
+        TimeCalibration {
+            toa_offset,
+            time_walk: vec![0; TOT_VALUES],
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Tp3ErrorKind> {
+        let content = fs::read_to_string(path).map_err(|e| Tp3ErrorKind::CalibrationNoReadFile(path.to_string(), e))?;
+        let mut lines = content.lines();
+        let mut read_line = || -> Result<Vec<i32>, Tp3ErrorKind> {
+            lines.next()
+                .ok_or_else(|| Tp3ErrorKind::CalibrationBadFormat(format!("{} must have two lines", path)))?
+                .split(',')
+                .map(|val| val.trim().parse::<i32>().map_err(|_| Tp3ErrorKind::CalibrationBadFormat(format!("{} has an invalid value {}", path, val))))
+                .collect()
+        };
+        let toa_offset = read_line()?;
+        let time_walk = read_line()?;
+        TimeCalibration::new(toa_offset, time_walk)
+    }
+
+    ///Reads the calibration from `path` if it exists. The EELS camera offsets are used otherwise.
+    pub fn from_file_or_default(path: &str) -> Result<Self, Tp3ErrorKind> {
+        match fs::metadata(path) {
+            Ok(_) => {
+                let calibration = TimeCalibration::from_file(path)?;
+                println!("***Calibration Lib***: Time calibration read from {}. Number of chips is {}.", path, calibration.toa_offset.len() / PIXELS_PER_CHIP);
+                Ok(calibration)
+            },
+            Err(_) => Ok(TimeCalibration::default()),
+        }
+    }
+
+    pub fn to_file(&self, path: &str) -> io::Result<()> {
+        let toa_offset = self.toa_offset.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+        let time_walk = self.time_walk.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+        fs::write(path, toa_offset + "\n" + &time_walk + "\n")
+    }
+
+    pub fn toa_offset(&self) -> &[i32] {
+        &self.toa_offset
+    }
+
+    pub fn time_walk(&self) -> &[i32] {
+        &self.time_walk
+    }
+
+    ///Total correction of a hit, in units of 640 MHz.
+    #[inline]
+    pub fn correction<T: Packet + ?Sized>(&self, packet: &T) -> i32 {
+        let offset = self.toa_offset.get(pixel_index(packet)).copied().unwrap_or(0);
+        offset + self.time_walk[packet.tot() as usize]
+    }
+
+    ///Corrects an electron `time`, given modulo `ELECTRON_ROLLOVER`.
+    #[inline]
+    pub fn correct<T: Packet + ?Sized>(&self, time: usize, packet: &T) -> usize {
+        (time as i64 - self.correction(packet) as i64).rem_euclid(ELECTRON_ROLLOVER as i64) as usize
+    }
+}
+
+impl Default for TimeCalibration {
+    fn default() -> Self {
+        TimeCalibration::eels()
+    }
+}
+
+///Calibration maps read by the post-processing when present in the working directory, in the
+///order a, b, c and t.
+pub const ENERGY_CALIBRATION_FILES: [&str; 4] = ["a.txt", "b.txt", "c.txt", "t.txt"];
+
+///`EnergyCalibration` converts the ToT of each pixel to deposited energy using the surrogate
+///function `ToT = a*E + b - c / (E - t)`, with the four parameters measured per pixel.
+///
+///Each parameter is read from its own map. A map is a text matrix with one row per pixel row of
+///the chip, chips being stacked one after the other in chip index order. Without calibration, the
+///energy is the ToT itself.
+#[derive(Clone, Default)]
+pub struct EnergyCalibration {
+    parameters: Vec<[f32; 4]>,
+}
+
+impl EnergyCalibration {
+    pub fn new(a: Vec<f32>, b: Vec<f32>, c: Vec<f32>, t: Vec<f32>) -> Result<Self, Tp3ErrorKind> {
+        if !a.len().is_multiple_of(PIXELS_PER_CHIP) || a.len() != b.len() || a.len() != c.len() || a.len() != t.len() {
+            return Err(Tp3ErrorKind::CalibrationBadFormat(format!("energy calibration maps must have the same size, multiple of {}. Received {}, {}, {} and {}", PIXELS_PER_CHIP, a.len(), b.len(), c.len(), t.len())));
+        }
+        let parameters = a.iter().zip(b.iter()).zip(c.iter()).zip(t.iter())
+            .map(|(((a, b), c), t)| [*a, *b, *c, *t])
+            .collect();
+        Ok(EnergyCalibration {
+            parameters,
+        })
+    }
+
+    ///Energy equals ToT.
+    pub fn empty() -> Self {
+        EnergyCalibration {
+            parameters: Vec::new(),
+        }
+    }
+
+    fn read_map(path: &str) -> Result<Vec<f32>, Tp3ErrorKind> {
+        let content = fs::read_to_string(path).map_err(|e| Tp3ErrorKind::CalibrationNoReadFile(path.to_string(), e))?;
+        content.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|val| !val.is_empty())
+            .map(|val| val.parse::<f32>().map_err(|_| Tp3ErrorKind::CalibrationBadFormat(format!("{} has an invalid value {}", path, val))))
+            .collect()
+    }
+
+    ///Reads the a, b, c and t maps, in this order.
+    pub fn from_files(paths: [&str; 4]) -> Result<Self, Tp3ErrorKind> {
+        let a = EnergyCalibration::read_map(paths[0])?;
+        let b = EnergyCalibration::read_map(paths[1])?;
+        let c = EnergyCalibration::read_map(paths[2])?;
+        let t = EnergyCalibration::read_map(paths[3])?;
+        EnergyCalibration::new(a, b, c, t)
+    }
+
+    ///Reads the maps if they all exist. Energy equals ToT otherwise.
+    pub fn from_files_or_default(paths: [&str; 4]) -> Result<Self, Tp3ErrorKind> {
+        if paths.iter().all(|path| fs::metadata(path).is_ok()) {
+            let calibration = EnergyCalibration::from_files(paths)?;
+            println!("***Calibration Lib***: Energy calibration read from {:?}. Number of chips is {}.", paths, calibration.parameters.len() / PIXELS_PER_CHIP);
+            Ok(calibration)
+        } else {
+            Ok(EnergyCalibration::default())
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty()
+    }
+
+    ///Energy, in keV, of a hit with the given `tot` in the pixel `index`. Uncalibrated pixels
+    ///return the ToT and dead pixels (a = 0) return zero.
+    #[inline]
+    pub fn energy_from_tot(&self, index: usize, tot: u16) -> f32 {
+        let tot = tot as f32;
+        match self.parameters.get(index) {
+            None => tot,
+            Some([a, _, _, _]) if *a == 0.0 => 0.0,
+            Some([a, b, c, t]) => {
+                let p = a * t + tot - b;
+                let delta = (b + a * t - tot).powi(2) + 4.0 * a * c;
+                (p + delta.max(0.0).sqrt()) / (2.0 * a)
+            },
+        }
+    }
+
+    #[inline]
+    pub fn energy<T: Packet + ?Sized>(&self, packet: &T) -> f32 {
+        self.energy_from_tot(pixel_index(packet), packet.tot())
+    }
+}
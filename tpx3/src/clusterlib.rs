@@ -1,17 +1,20 @@
 //!`clusterlib` is a collection of tools to identify and manipulate TPX3 cluster.
 
 pub mod cluster {
-    use crate::spimlib::SPIM_PIXELS;
-    use crate::packetlib::Packet;
+    use crate::packetlib::{Packet, Timestamp};
+    use crate::detectorlib::DetectorLayout;
+    use crate::calibrationlib::EnergyCalibration;
     use crate::spimlib;
     use crate::tdclib::PeriodicTdcRef;
     use std::fs::OpenOptions;
     use std::io::Write;
     use rayon::prelude::*;
+    use std::cmp;
     
-    const VIDEO_TIME: usize = 3_200; //Video time for spim (in 640 Mhz or 1.5625 ns).
-    const CLUSTER_DET:usize = 128; //Cluster time window (in 640 Mhz or 1.5625).
+    const VIDEO_TIME: Timestamp = Timestamp::new(3_200); //Video time for spim (in 640 Mhz or 1.5625 ns).
+    const CLUSTER_DET: Timestamp = Timestamp::new(128); //Cluster time window (in 640 Mhz or 1.5625).
     const CLUSTER_SPATIAL: isize = 2; // If electron hit position in both X or Y > CLUSTER_SPATIAL, then we have a new cluster.
+    const SEGMENTS_PER_THREAD: usize = 4; //Segments clustered by each thread, for a better balance.
 
     #[derive(Debug)]
     pub struct CollectionElectron {
@@ -49,10 +52,71 @@ pub mod cluster {
         pub fn add_electron(&mut self, electron: SingleElectron) {
             self.data.push(electron);
         }
+
+        pub fn len(&self) -> usize {
+            self.data.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.data.is_empty()
+        }
+
+        pub fn as_slice(&self) -> &[SingleElectron] {
+            &self.data
+        }
+
+        ///First index, from `index`, that does not cut a cluster. Electrons must be sorted.
+        fn next_gap(&self, mut index: usize) -> usize {
+            while index > 0 && index < self.data.len() && self.data[index].time() <= self.data[index-1].time() + CLUSTER_DET {
+                index += 1;
+            }
+            index
+        }
+
+        ///Splits the sorted electrons in at most `nsegments` time-ordered collections, without
+        ///cutting a cluster.
+        fn split_at_gaps(&mut self, nsegments: usize) -> Vec<CollectionElectron> {
+            let len = self.data.len();
+            let mut bounds: Vec<usize> = vec![0];
+            for segment in 1..nsegments {
+                let index = self.next_gap(cmp::max(len * segment / nsegments, bounds[bounds.len()-1]));
+                bounds.push(index);
+            }
+            bounds.push(len);
+            bounds.dedup();
+            let data = std::mem::take(&mut self.data);
+            bounds.windows(2).map(|bound| CollectionElectron {data: data[bound[0]..bound[1]].to_vec()}).collect()
+        }
+
+        ///Clusters are identified in parallel over time-ordered segments. As no cluster is shared
+        ///by two segments, the result does not depend on the number of threads.
+        fn par_remove_clusters(&mut self) {
+            let mut segments = self.split_at_gaps(rayon::current_num_threads() * SEGMENTS_PER_THREAD);
+            segments.par_iter_mut().for_each(|segment| {
+                for _x in 0..2 {
+                    segment.remove_clusters();
+                }
+            });
+            self.data = segments.into_iter().flat_map(|segment| segment.data).collect();
+        }
+
+        ///Sorts the electrons and moves the ones arriving after `time` to the returned collection.
+        ///The split is moved forward until a gap larger than `CLUSTER_DET`, so a cluster is never
+        ///cut in two.
+        pub fn split_off_after(&mut self, time: Timestamp) -> CollectionElectron {
+            self.sort();
+            let index = self.next_gap(self.data.partition_point(|se| se.time() < time));
+            CollectionElectron {
+                data: self.data.split_off(index),
+            }
+        }
         fn remove_clusters(&mut self) {
             let mut nelist: Vec<SingleElectron> = Vec::new();
 
-            let mut last: SingleElectron = self.first_value();
+            let mut last: SingleElectron = match self.data.iter().find(|x| x.cluster_size() == 1) {
+                Some(first) => *first,
+                None => return,
+            };
             let mut cluster_vec: Vec<SingleElectron> = Vec::new();
             for x in self.values() {
                     if x.cluster_size() == 1 {
@@ -67,6 +131,9 @@ pub mod cluster {
                         nelist.push(*x);
                     }
             }
+            if !cluster_vec.is_empty() {
+                nelist.push(SingleElectron::new_from_cluster(&cluster_vec));
+            }
             self.data = nelist;
         }
 
@@ -74,20 +141,24 @@ pub mod cluster {
             self.data.par_sort_unstable_by(|a, b| (a.data).partial_cmp(&b.data).unwrap());
         }
 
+        ///Sorts the electrons and replaces each cluster by a single electron. The result is sorted,
+        ///as the mean time of a cluster can come after the hits of the next one.
         pub fn clean(&mut self) {
             self.sort();
-            for _x in 0..2 {
-                self.remove_clusters();
-            }
+            self.par_remove_clusters();
+            self.sort();
+        }
+
+        ///Keeps only the electrons (or clusters) whose energy is within `min` and `max`.
+        pub fn retain_energy(&mut self, min: f32, max: f32) {
+            self.data.retain(|se| se.energy() > min && se.energy() < max);
         }
 
         pub fn try_clean(&mut self, min_size: usize, remove: bool) -> bool {
             if self.data.len() > min_size && remove {
                 let nelectrons = self.data.len();
                 self.sort();
-                for _x in 0..2 {
-                    self.remove_clusters();
-                }
+                self.par_remove_clusters();
                 self.sort();
                 let new_nelectrons = self.data.len();
                 println!("Number of electrons: {}. Number of clusters: {}. Electrons per cluster: {}", nelectrons, new_nelectrons, nelectrons as f32/new_nelectrons as f32); 
@@ -96,12 +167,13 @@ pub mod cluster {
             !remove
         }
 
-        pub fn output_data(&self, filename: String, slice: usize) {
+        ///Outputs the electrons of `slice` whose energy is within `energy` (min, max).
+        pub fn output_data(&self, filename: String, slice: usize, energy: (f32, f32)) {
             let mut tfile = OpenOptions::new()
                 .append(true)
                 .create(true)
                 .open(&filename).expect("Could not output time histogram.");
-            let out: Vec<String> = self.data.iter().filter(|se| se.spim_slice()==slice && se.tot() > 60 && se.tot() < 220).map(|x| x.to_string()).collect::<Vec<String>>();
+            let out: Vec<String> = self.data.iter().filter(|se| se.spim_slice()==slice && se.energy() > energy.0 && se.energy() < energy.1).map(|x| x.to_string()).collect::<Vec<String>>();
             if out.len() > 0 {
                 println!("Outputting data for slice {}. Number of electrons: {}", slice, out.len());
                 let out_str: String = out.join("");
@@ -168,22 +240,22 @@ pub mod cluster {
         */
     }
 
-    ///ToA, X, Y, Spim dT, Spim Slice, ToT, Cluster Size
+    ///ToA, X, Y, Spim dT, Spim Slice, ToT, Cluster Size, Energy
     #[derive(Copy, Clone, Debug)]
     pub struct SingleElectron {
-        data: (usize, usize, usize, usize, usize, u16, usize),
+        data: (Timestamp, usize, usize, Timestamp, usize, u16, usize, f32),
     }
 
     impl ToString for SingleElectron {
         fn to_string(&self) -> String {
 
-            let mut val = String::from(self.time().to_string());
+            let mut val = String::from(self.time().ticks().to_string());
             val.push_str(",");
             val.push_str(&self.x().to_string());
             val.push_str(",");
             val.push_str(&self.y().to_string());
             val.push_str(",");
-            val.push_str(&self.frame_dt().to_string());
+            val.push_str(&self.frame_dt().ticks().to_string());
             val.push_str(",");
             val.push_str(&self.spim_slice().to_string());
             val.push_str(",");
@@ -191,32 +263,39 @@ pub mod cluster {
             val.push_str(",");
             val.push_str(&self.cluster_size().to_string());
             val.push_str(",");
+            val.push_str(&self.energy().to_string());
+            val.push(',');
             
             val
         }
     }
 
     impl SingleElectron {
-        pub fn new<T: Packet>(pack: &T, begin_frame: Option<PeriodicTdcRef>, slice: usize) -> Self {
-            let mut ele_time = pack.electron_time();
+        ///Creates an electron from its packet. `ele_time` is the extended electron time (see
+        ///`packetlib::TimeExtension`), so it can be directly compared to the spim frame time.
+        ///The position is given by `layout` and the energy by `calibration`. None if the chip of
+        ///`pack` is not in `layout`.
+        pub fn new<T: Packet>(pack: &T, ele_time: Timestamp, begin_frame: Option<PeriodicTdcRef>, slice: usize, layout: &DetectorLayout, calibration: &EnergyCalibration) -> Option<Self> {
+            let (x, y) = layout.x_y(pack)?;
+            let energy = pack.energy(calibration);
             match begin_frame {
                 Some(spim_tdc) => {
-                    let frame_time = spim_tdc.begin_frame;
-                    if ele_time < frame_time + VIDEO_TIME {
-                        let factor = (frame_time + VIDEO_TIME - ele_time) / (spim_tdc.period*spim_tdc.ticks_to_frame.unwrap()) + 1;
-                        ele_time += spim_tdc.period*spim_tdc.ticks_to_frame.unwrap() * factor;
-                    }
-                    if ele_time < frame_time + VIDEO_TIME {
-                        println!("Electron time is still below the frame time. This is probably an issue.");
-                    }
-                    SingleElectron {
-                        data: (ele_time, pack.x(), pack.y(), ele_time-frame_time-VIDEO_TIME, slice, pack.tot(), 1),
-                    }
+                    let frame_time = spim_tdc.begin_frame + VIDEO_TIME;
+                    //Electrons read out after the new frame TDC but belonging to the previous frame.
+                    let frame_dt = if ele_time < frame_time {
+                        let frame_period = spim_tdc.period * spim_tdc.ticks_to_frame.unwrap() as u64;
+                        (ele_time + frame_period).saturating_sub(frame_time)
+                    } else {
+                        ele_time - frame_time
+                    };
+                    Some(SingleElectron {
+                        data: (ele_time, x, y, frame_dt, slice, pack.tot(), 1, energy),
+                    })
                 },
                 None => {
-                    SingleElectron {
-                        data: (ele_time, pack.x(), pack.y(), 0, slice, pack.tot(), 1),
-                    }
+                    Some(SingleElectron {
+                        data: (ele_time, x, y, Timestamp::zero(), slice, pack.tot(), 1, energy),
+                    })
                 },
             }
         }
@@ -227,20 +306,24 @@ pub mod cluster {
         pub fn y(&self) -> usize {
             self.data.2
         }
-        pub fn time(&self) -> usize {
+        pub fn time(&self) -> Timestamp {
             self.data.0
         }
         pub fn tot(&self) -> u16 {
             self.data.5
         }
-        pub fn frame_dt(&self) -> usize {
+        ///Deposited energy, in keV. For clusters, the sum over all its hits.
+        pub fn energy(&self) -> f32 {
+            self.data.7
+        }
+        pub fn frame_dt(&self) -> Timestamp {
             self.data.3
         }
-        pub fn image_index(&self) -> usize {
-            self.data.1 + SPIM_PIXELS*self.data.2
+        pub fn image_index(&self, layout: &DetectorLayout) -> usize {
+            self.data.1 + layout.spim_pixels()*self.data.2
         }
-        pub fn relative_time(&self, reference_time: usize) -> isize {
-            self.data.0 as isize - reference_time as isize
+        pub fn relative_time(&self, reference_time: Timestamp) -> isize {
+            self.data.0.signed_diff(reference_time) as isize
         }
         pub fn spim_slice(&self) -> usize {
             self.data.4
@@ -261,22 +344,23 @@ pub mod cluster {
             let cluster_size: usize = cluster.len();
             
 
-            let t_mean:usize = cluster.iter().map(|se| se.time()).sum::<usize>() / cluster_size;
+            let t_mean: Timestamp = cluster.iter().map(|se| se.time()).sum::<Timestamp>() / cluster_size as u64;
             let x_mean:usize = cluster.iter().map(|se| se.x()).sum::<usize>() / cluster_size;
             let y_mean:usize = cluster.iter().map(|se| se.y()).sum::<usize>() / cluster_size;
-            let time_dif: usize = cluster.iter().map(|se| se.frame_dt()).next().unwrap();
+            let time_dif: Timestamp = cluster.iter().map(|se| se.frame_dt()).next().unwrap();
             let slice: usize = cluster.iter().map(|se| se.spim_slice()).next().unwrap();
             let tot_sum: u16 = cluster.iter().map(|se| se.tot() as usize).sum::<usize>() as u16;
+            let energy_sum: f32 = cluster.iter().map(|se| se.energy()).sum::<f32>();
             let cluster_size: usize = cluster_size;
 
             SingleElectron {
-                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size),
+                data: (t_mean, x_mean, y_mean, time_dif, slice, tot_sum, cluster_size, energy_sum),
             }
         }
 
-        pub fn get_or_not_spim_index(&self, spim_tdc: Option<PeriodicTdcRef>, xspim: usize, yspim: usize) -> Option<usize> {
+        pub fn get_or_not_spim_index(&self, spim_tdc: Option<PeriodicTdcRef>, xspim: usize, yspim: usize, layout: &DetectorLayout) -> Option<usize> {
             if let Some(frame_tdc) = spim_tdc {
-                spimlib::get_spimindex(self.x(), self.frame_dt(), &frame_tdc, xspim, yspim)
+                spimlib::get_spimindex(self.x(), self.frame_dt(), &frame_tdc, xspim, yspim, layout.spim_pixels())
             } else {
                 None
             }
@@ -0,0 +1,336 @@
+//!`configlib` gathers the endpoints, files and TDC assignments used by the acquisition and the
+//!analysis binaries. Each value is read, by increasing priority, from the defaults, a TOML file,
+//!the environment and the command line. For a key such as `tp3_address` (or `tdc.frame`), the
+//!environment variable is `TPX3_TP3_ADDRESS` (`TPX3_TDC_FRAME`) and the flag is
+//!`--tp3-address <value>` (`--tdc-frame <value>`). The file itself is chosen with `TPX3_CONFIG`
+//!or `--config <path>`.
+//!
+//!```toml
+//!tp3_address = "127.0.0.1:8098"
+//!nionswift_address = "192.168.199.11:8088"
+//!debug_address = "127.0.0.1:8088"
+//!isi_box_address = "127.0.0.1:9592"
+//!debug = false
+//!debug_file = "bin/Data/raw000000.tpx3"
+//!log_dir = "Microscope/Log/"
+//!save_dir = "Microscope/Data/"
+//!layout_file = "layout.toml"
+//!
+//![tdc]
+//!frame = "tdc_one_rising_edge"
+//!spim = "tdc_one_falling_edge"
+//!reference = "tdc_two_rising_edge"
+//!laser = "tdc_two_rising_edge"
+//!
+//![coincidence]
+//!delay = 160.9375
+//!width = 25.0
+//!search_len = 100
+//!accidental_shift = 1000.0
+//!channels = ["tdc_two_rising_edge", "tdc_two_falling_edge"]
+//!output_dir = "."
+//!output_format = "npy"
+//!calibrate_delay = false
+//!
+//![simulation]
+//!coincidence_fraction = 0.0
+//!```
+use crate::detectorlib::{DetectorLayout, LAYOUT_FILE};
+use crate::errorlib::Tp3ErrorKind;
+use crate::packetlib::{Picoseconds, Timestamp};
+use crate::tdclib::TdcType;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use std::{env, fs};
+
+///Configuration file read when present in the working directory.
+pub const CONFIG_FILE: &str = "tpx3.toml";
+const CONFIG_ENV: &str = "TPX3_CONFIG";
+const CONFIG_FLAG: &str = "--config";
+const KEYS: [&str; 22] = ["tp3_address", "nionswift_address", "debug_address", "isi_box_address", "debug", "debug_file", "log_dir", "save_dir", "layout_file", "tdc.frame", "tdc.spim", "tdc.reference", "tdc.laser", "coincidence.delay", "coincidence.width", "coincidence.search_len", "coincidence.accidental_shift", "coincidence.channels", "coincidence.output_dir", "coincidence.output_format", "coincidence.calibrate_delay", "simulation.coincidence_fraction"];
+
+///TDC inputs used by the measurements. Each one is a role of the modes (see `modelib`).
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct TdcConfig {
+    ///Frame (spectral modes) reference.
+    pub frame: TdcType,
+    ///Line reference of the hyperspectral modes.
+    pub spim: TdcType,
+    ///Photon reference.
+    pub reference: TdcType,
+    ///Laser reference of the time-resolved modes.
+    pub laser: TdcType,
+}
+
+impl Default for TdcConfig {
+    fn default() -> Self {
+        TdcConfig {
+            frame: TdcType::TdcOneRisingEdge,
+            spim: TdcType::TdcOneFallingEdge,
+            reference: TdcType::TdcTwoRisingEdge,
+            laser: TdcType::TdcTwoRisingEdge,
+        }
+    }
+}
+
+///How the analysis results are written.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    ///Comma-separated text files.
+    Text,
+    ///NumPy arrays (see `npylib`).
+    Npy,
+}
+
+///Window in which electrons and photons are paired. Times are in ns.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoincidenceConfig {
+    ///Delay of the photons relative to their electron.
+    pub delay: f64,
+    ///Maximum time difference between an electron and a delayed photon.
+    pub width: f64,
+    ///Number of photons searched for each electron.
+    pub search_len: usize,
+    ///Shift of the off-peak window counting the accidental coincidences.
+    pub accidental_shift: f64,
+    ///Photon detectors, one per TDC. Each one is a channel of the coincidence outputs. The
+    ///reference TDC is the only channel if empty. In flags and variables, the TDCs are separated
+    ///by commas.
+    pub channels: Vec<TdcType>,
+    ///Folder of the results. A `metadata.json` file describes them.
+    pub output_dir: String,
+    pub output_format: OutputFormat,
+    ///Finds the delay from the data before the coincidence search.
+    pub calibrate_delay: bool,
+}
+
+impl Default for CoincidenceConfig {
+    fn default() -> Self {
+        CoincidenceConfig {
+            delay: 160.9375,
+            width: 25.0,
+            search_len: 100,
+            accidental_shift: 1_000.0,
+            channels: Vec::new(),
+            output_dir: String::from("."),
+            output_format: OutputFormat::Text,
+            calibrate_delay: false,
+        }
+    }
+}
+
+fn from_nanoseconds(ns: f64) -> Timestamp {
+    Timestamp::from_picoseconds(Picoseconds::from_whole((ns * 1_000.0).round() as u128))
+}
+
+impl CoincidenceConfig {
+    pub fn delay_time(&self) -> Timestamp {
+        from_nanoseconds(self.delay)
+    }
+
+    pub fn width_time(&self) -> Timestamp {
+        from_nanoseconds(self.width)
+    }
+
+    pub fn accidental_shift_time(&self) -> Timestamp {
+        from_nanoseconds(self.accidental_shift)
+    }
+
+    ///The TDCs of the photon channels.
+    pub fn photon_tdcs(&self, tdc: &TdcConfig) -> Vec<TdcType> {
+        match self.channels.is_empty() {
+            true => vec![tdc.reference],
+            false => self.channels.clone(),
+        }
+    }
+}
+
+///Synthetic data written by `tp3_simulator`.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    ///Fraction of the electrons followed, `coincidence.delay` later, by a photon on the reference
+    ///TDC. No photon is sent if zero.
+    pub coincidence_fraction: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tp3Config {
+    ///Address the TPX3 data arrives to.
+    pub tp3_address: String,
+    ///Address the client (Nionswift) connects to.
+    pub nionswift_address: String,
+    ///Address used when `nionswift_address` can not be bound. The acquisition then runs in debug
+    ///mode, reading `debug_file` instead of the TPX3 data.
+    pub debug_address: String,
+    pub isi_box_address: String,
+    ///Always run in debug mode.
+    pub debug: bool,
+    pub debug_file: String,
+    pub log_dir: String,
+    ///Where the modes saving the raw data write it.
+    pub save_dir: String,
+    pub layout_file: String,
+    pub tdc: TdcConfig,
+    pub coincidence: CoincidenceConfig,
+    pub simulation: SimulationConfig,
+}
+
+impl Default for Tp3Config {
+    fn default() -> Self {
+        Tp3Config {
+            tp3_address: String::from("127.0.0.1:8098"),
+            nionswift_address: String::from("192.168.199.11:8088"),
+            debug_address: String::from("127.0.0.1:8088"),
+            isi_box_address: String::from("127.0.0.1:9592"),
+            debug: false,
+            debug_file: String::from("bin/Data/raw000000.tpx3"),
+            log_dir: String::from("Microscope/Log/"),
+            save_dir: String::from("Microscope/Data/"),
+            layout_file: String::from(LAYOUT_FILE),
+            tdc: TdcConfig::default(),
+            coincidence: CoincidenceConfig::default(),
+            simulation: SimulationConfig::default(),
+        }
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("TPX3_{}", key.replace('.', "_").to_uppercase())
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace(['.', '_'], "-"))
+}
+
+///Whether the configuration file sets `key`.
+fn in_table(table: &toml::Value, key: &str) -> bool {
+    key.split('.').try_fold(table, |table, name| table.get(name)).is_some()
+}
+
+fn parse_tdc(value: &str) -> Result<TdcType, Tp3ErrorKind> {
+    TdcType::deserialize(value.into_deserializer())
+        .map_err(|e: serde::de::value::Error| Tp3ErrorKind::SetArgument(e.to_string()))
+}
+
+fn parse_format(value: &str) -> Result<OutputFormat, Tp3ErrorKind> {
+    OutputFormat::deserialize(value.into_deserializer())
+        .map_err(|e: serde::de::value::Error| Tp3ErrorKind::SetArgument(e.to_string()))
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Tp3ErrorKind> {
+    value.parse().map_err(|_| Tp3ErrorKind::SetArgument(format!("bad value for {}. Received {}.", key, value)))
+}
+
+impl Tp3Config {
+    ///Reads the configuration of `path`, and its content to know which keys it sets.
+    fn read_file(path: &str) -> Result<(Self, toml::Value), Tp3ErrorKind> {
+        let bad_file = |e: toml::de::Error| Tp3ErrorKind::SetArgument(format!("bad configuration file {}: {}", path, e));
+        let content = fs::read_to_string(path).map_err(|e| Tp3ErrorKind::SetNoReadFile(path.to_string(), e))?;
+        let table: toml::Value = toml::from_str(&content).map_err(bad_file)?;
+        let config = table.clone().try_into().map_err(bad_file)?;
+        Ok((config, table))
+    }
+
+    fn read_file_or_default(path: &str) -> Result<(Self, toml::Value), Tp3ErrorKind> {
+        match fs::metadata(path) {
+            Ok(_) => {
+                let read = Tp3Config::read_file(path)?;
+                println!("***Config Lib***: Configuration read from {}.", path);
+                Ok(read)
+            },
+            Err(_) => Ok((Tp3Config::default(), toml::Value::Table(toml::value::Table::new()))),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Tp3ErrorKind> {
+        Ok(Tp3Config::read_file(path)?.0)
+    }
+
+    ///Reads the configuration from `path` if it exists. The defaults are used otherwise.
+    pub fn from_file_or_default(path: &str) -> Result<Self, Tp3ErrorKind> {
+        Ok(Tp3Config::read_file_or_default(path)?.0)
+    }
+
+    ///Sets the value of a key, as named in the configuration file.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Tp3ErrorKind> {
+        match key {
+            "tp3_address" => self.tp3_address = value.to_string(),
+            "nionswift_address" => self.nionswift_address = value.to_string(),
+            "debug_address" => self.debug_address = value.to_string(),
+            "isi_box_address" => self.isi_box_address = value.to_string(),
+            "debug" => self.debug = parse_value(key, value)?,
+            "debug_file" => self.debug_file = value.to_string(),
+            "log_dir" => self.log_dir = value.to_string(),
+            "save_dir" => self.save_dir = value.to_string(),
+            "layout_file" => self.layout_file = value.to_string(),
+            "tdc.frame" => self.tdc.frame = parse_tdc(value)?,
+            "tdc.spim" => self.tdc.spim = parse_tdc(value)?,
+            "tdc.reference" => self.tdc.reference = parse_tdc(value)?,
+            "tdc.laser" => self.tdc.laser = parse_tdc(value)?,
+            "coincidence.delay" => self.coincidence.delay = parse_value(key, value)?,
+            "coincidence.width" => self.coincidence.width = parse_value(key, value)?,
+            "coincidence.search_len" => self.coincidence.search_len = parse_value(key, value)?,
+            "coincidence.accidental_shift" => self.coincidence.accidental_shift = parse_value(key, value)?,
+            "coincidence.output_dir" => self.coincidence.output_dir = value.to_string(),
+            "coincidence.output_format" => self.coincidence.output_format = parse_format(value)?,
+            "coincidence.channels" => self.coincidence.channels = value.split(',').map(|tdc| parse_tdc(tdc.trim())).collect::<Result<_, _>>()?,
+            "coincidence.calibrate_delay" => self.coincidence.calibrate_delay = parse_value(key, value)?,
+            "simulation.coincidence_fraction" => self.simulation.coincidence_fraction = parse_value(key, value)?,
+            _ => return Err(Tp3ErrorKind::SetArgument(format!("unknown configuration key {}.", key))),
+        }
+        Ok(())
+    }
+
+    ///Builds the configuration from the file, the environment and the flags of `args`. The
+    ///remaining arguments (the program name included) are returned for the binary to parse.
+    pub fn load(args: &[String]) -> Result<(Self, Vec<String>), Tp3ErrorKind> {
+        Tp3Config::load_with_defaults(args, &[])
+    }
+
+    ///As `load`, but the keys of `defaults` start from the given values instead of the defaults of
+    ///`Tp3Config`. The file, the environment and the flags still override them.
+    pub fn load_with_defaults(args: &[String], defaults: &[(&str, &str)]) -> Result<(Self, Vec<String>), Tp3ErrorKind> {
+        let mut flags: Vec<(String, String)> = Vec::new();
+        let mut remaining: Vec<String> = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg.starts_with("--") {
+                let value = iter.next().ok_or_else(|| Tp3ErrorKind::SetArgument(format!("{} needs a value.", arg)))?;
+                flags.push((arg.clone(), value.clone()));
+            } else {
+                remaining.push(arg.clone());
+            }
+        }
+
+        let path = flags.iter().rev().find(|(flag, _)| flag == CONFIG_FLAG).map(|(_, value)| value.clone())
+            .or_else(|| env::var(CONFIG_ENV).ok());
+        let (mut config, table) = match path {
+            Some(path) => Tp3Config::read_file(&path)?,
+            None => Tp3Config::read_file_or_default(CONFIG_FILE)?,
+        };
+        for (key, value) in defaults.iter().filter(|(key, _)| !in_table(&table, key)) {
+            config.set(key, value)?;
+        }
+        for key in KEYS.iter() {
+            if let Ok(value) = env::var(env_name(key)) {
+                config.set(key, &value)?;
+            }
+        }
+        for (flag, value) in flags.iter().filter(|(flag, _)| flag != CONFIG_FLAG) {
+            let key = KEYS.iter().find(|key| flag_name(key) == *flag)
+                .ok_or_else(|| Tp3ErrorKind::SetArgument(format!("unknown flag {}.", flag)))?;
+            config.set(key, value)?;
+        }
+        Ok((config, remaining))
+    }
+
+    ///The detector layout of `layout_file`, or the EELS camera if it does not exist.
+    pub fn layout(&self) -> Result<DetectorLayout, Tp3ErrorKind> {
+        DetectorLayout::from_file_or_default(&self.layout_file)
+    }
+}
@@ -0,0 +1,321 @@
+//!`controllib` is the protocol used by the client (Nionswift) to configure an acquisition.
+//!
+//!Two formats are accepted on the control socket:
+//!
+//!* The legacy message: 16 raw bytes, parsed by `BytesConfig`. No reply is sent, so old clients
+//!  keep working unchanged.
+//!* A framed message: an 8-byte header `[b'T', b'P', b'X', b'C', version, kind, len_hi, len_lo]`
+//!  followed by `len` bytes of JSON. The server always replies with a framed message of kind
+//!  `KIND_REPLY`, whose JSON payload is `{"status": "ok", ...}` or `{"status": "error", "message": ...}`.
+//!
+//!The legacy message can not be mistaken with the framed one, as its first byte (bin) is 0 or 1.
+//!
+//!Once the acquisition started, the client can send `KIND_COMMAND` messages (see `Command`). They
+//!are read by a separate thread and applied between two buffers of the acquisition. Commands are
+//!not acknowledged, as the socket is then carrying the data.
+use crate::auxiliar::{Settings, BytesConfig, Roi, OutputFormat, CONFIG_SIZE};
+use crate::detectorlib::DetectorLayout;
+use crate::configlib::{Tp3Config, TdcConfig};
+use crate::errorlib::Tp3ErrorKind;
+use crate::modelib;
+use crate::tdclib::TdcType;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::mpsc;
+use std::thread;
+
+pub const CONTROL_MAGIC: [u8; 4] = *b"TPXC";
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 8;
+pub const KIND_SETTINGS: u8 = 0;
+pub const KIND_REPLY: u8 = 1;
+pub const KIND_COMMAND: u8 = 2;
+
+///A message received on the control socket.
+pub enum ControlMessage {
+    Legacy([u8; CONFIG_SIZE]),
+    Framed {kind: u8, payload: Vec<u8>},
+}
+
+///Reads a full message, waiting for all its bytes to arrive.
+pub fn read_message<R: Read>(sock: &mut R) -> Result<ControlMessage, Tp3ErrorKind> {
+    let mut header = [0_u8; HEADER_SIZE];
+    sock.read_exact(&mut header[0..4]).map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?;
+    if header[0..4] != CONTROL_MAGIC {
+        let mut data = [0_u8; CONFIG_SIZE];
+        data[0..4].copy_from_slice(&header[0..4]);
+        sock.read_exact(&mut data[4..]).map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?;
+        println!("Received {} bytes from NS (legacy message).", CONFIG_SIZE);
+        return Ok(ControlMessage::Legacy(data));
+    }
+    sock.read_exact(&mut header[4..]).map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?;
+    if header[4] != PROTOCOL_VERSION {
+        return Err(Tp3ErrorKind::SetProtocolVersion(header[4]));
+    }
+    let len = (header[6] as usize)<<8 | (header[7] as usize);
+    if len == 0 {
+        return Err(Tp3ErrorKind::SetConfigSize(len));
+    }
+    let mut payload = vec![0_u8; len];
+    sock.read_exact(&mut payload).map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?;
+    println!("Received {} bytes from NS (protocol version {}).", HEADER_SIZE + len, header[4]);
+    Ok(ControlMessage::Framed {kind: header[5], payload})
+}
+
+///Writes a framed message of the given kind.
+pub fn write_message<W: Write>(sock: &mut W, kind: u8, payload: &[u8]) -> Result<(), Tp3ErrorKind> {
+    if payload.len() > u16::MAX as usize {
+        return Err(Tp3ErrorKind::SetConfigSize(payload.len()));
+    }
+    let mut msg = Vec::with_capacity(HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&CONTROL_MAGIC);
+    msg.push(PROTOCOL_VERSION);
+    msg.push(kind);
+    msg.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    msg.extend_from_slice(payload);
+    sock.write_all(&msg).map_err(|e| Tp3ErrorKind::SocketWrite("Nionswift", e))
+}
+
+///Acknowledges (or refuses) a framed message.
+pub fn write_reply<W: Write>(sock: &mut W, result: Result<u8, &Tp3ErrorKind>) -> Result<(), Tp3ErrorKind> {
+    let reply = match result {
+        Ok(mode) => json!({"status": "ok", "version": PROTOCOL_VERSION, "mode": mode}),
+        Err(e) => json!({"status": "error", "version": PROTOCOL_VERSION, "message": e.to_string()}),
+    };
+    write_message(sock, KIND_REPLY, reply.to_string().as_bytes())
+}
+
+///Reads the acquisition settings from the client. Framed messages are always answered, so the
+///client knows whether the acquisition will start. Absent TDCs are the ones of `config`.
+pub fn receive_settings<S: Read + Write>(sock: &mut S, config: &Tp3Config) -> Result<Settings, Tp3ErrorKind> {
+    match read_message(sock) {
+        Ok(ControlMessage::Legacy(data)) => BytesConfig{data}.create_settings(config.layout()?, config.tdc),
+        Ok(ControlMessage::Framed {kind: KIND_SETTINGS, payload}) => {
+            let settings = config.layout()
+                .and_then(|layout| SettingsMessage::from_json(&payload)?.create_settings(layout, config.tdc));
+            write_reply(sock, settings.as_ref().map(|set| set.mode))?;
+            settings
+        },
+        Ok(ControlMessage::Framed {kind, ..}) => {
+            let error = Tp3ErrorKind::SetMessageKind(kind);
+            write_reply(sock, Err(&error))?;
+            Err(error)
+        },
+        Err(error @ Tp3ErrorKind::SetProtocolVersion(_)) | Err(error @ Tp3ErrorKind::SetConfigSize(_)) => {
+            write_reply(sock, Err(&error))?;
+            Err(error)
+        },
+        Err(error) => Err(error),
+    }
+}
+
+fn default_bytedepth() -> usize { 4 }
+fn default_spim_size() -> usize { 1 }
+fn default_time_width() -> usize { 1000 }
+
+///The JSON payload of a `KIND_SETTINGS` message. Absent fields take their default value. Times are
+///in ns, and the TDCs are named in snake case (`"tdc_one_rising_edge"`).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsMessage {
+    #[serde(default)]
+    pub bin: bool,
+    #[serde(default = "default_bytedepth")]
+    pub bytedepth: usize,
+    #[serde(default)]
+    pub cumul: bool,
+    #[serde(default)]
+    pub mode: u8,
+    #[serde(default = "default_spim_size")]
+    pub xspim: usize,
+    #[serde(default = "default_spim_size")]
+    pub yspim: usize,
+    #[serde(default)]
+    pub xscan: usize,
+    #[serde(default)]
+    pub yscan: usize,
+    #[serde(default)]
+    pub time_delay: usize,
+    #[serde(default = "default_time_width")]
+    pub time_width: usize,
+    #[serde(default)]
+    pub frame_tdc: Option<TdcType>,
+    #[serde(default)]
+    pub spim_tdc: Option<TdcType>,
+    #[serde(default)]
+    pub ref_tdc: Option<TdcType>,
+    #[serde(default)]
+    pub laser_tdc: Option<TdcType>,
+    #[serde(default)]
+    pub roi: Option<Roi>,
+    ///Not supported by the live modes yet, so `true` is refused.
+    #[serde(default)]
+    pub remove_clusters: bool,
+    #[serde(default)]
+    pub output: OutputFormat,
+}
+
+impl SettingsMessage {
+    pub fn from_json(payload: &[u8]) -> Result<Self, Tp3ErrorKind> {
+        serde_json::from_slice(payload).map_err(|e| Tp3ErrorKind::SetMessage(e.to_string()))
+    }
+
+    ///Validates the message and creates the `Settings` of the acquisition.
+    pub fn create_settings(&self, layout: DetectorLayout, tdc: TdcConfig) -> Result<Settings, Tp3ErrorKind> {
+        let frame_tdc = self.frame_tdc.unwrap_or(tdc.frame);
+        let spim_tdc = self.spim_tdc.unwrap_or(tdc.spim);
+        let ref_tdc = self.ref_tdc.unwrap_or(tdc.reference);
+        let laser_tdc = self.laser_tdc.unwrap_or(tdc.laser);
+        if ![1, 2, 4].contains(&self.bytedepth) {return Err(Tp3ErrorKind::SetByteDepth(self.bytedepth));}
+        if self.xspim == 0 {return Err(Tp3ErrorKind::SetXSize);}
+        if self.yspim == 0 {return Err(Tp3ErrorKind::SetYSize);}
+        if self.time_width == 0 {return Err(Tp3ErrorKind::SetMessage(String::from("the time width must be greater than zero")));}
+        if self.remove_clusters {return Err(Tp3ErrorKind::SetMessage(String::from("the live modes can not remove clusters")));}
+        for tdc in [ref_tdc, laser_tdc].iter() {
+            if frame_tdc.associate_value() == tdc.associate_value() || spim_tdc.associate_value() == tdc.associate_value() {
+                return Err(Tp3ErrorKind::SetMessage(format!("the reference and laser TDCs ({}) must differ from the frame and spim TDCs", tdc.associate_str())));
+            }
+        }
+        if let Some(roi) = self.roi {
+            if roi.x.0 > roi.x.1 || roi.y.0 > roi.y.1 || roi.x.1 >= layout.size().0 || roi.y.1 >= layout.size().1 {
+                return Err(Tp3ErrorKind::SetMessage(format!("ROI {:?} is not within the detector ({:?})", roi, layout.size())));
+            }
+        }
+        let settings = Settings {
+            bin: self.bin,
+            bytedepth: self.bytedepth,
+            cumul: self.cumul,
+            mode: self.mode,
+            xspim_size: self.xspim,
+            yspim_size: self.yspim,
+            xscan_size: self.xscan,
+            yscan_size: self.yscan,
+            time_delay: self.time_delay,
+            time_width: self.time_width,
+            spimoverscanx: (self.xscan / self.xspim).max(1),
+            spimoverscany: (self.yscan / self.yspim).max(1),
+            layout,
+            frame_tdc,
+            spim_tdc,
+            ref_tdc,
+            laser_tdc,
+            roi: self.roi,
+            output: self.output,
+        };
+        modelib::find(&settings)?;
+        println!("Received settings is {:?}.", settings);
+        Ok(settings)
+    }
+}
+
+///Commands accepted during an acquisition. The JSON payload names the command and, if needed, its
+///value, e.g. `{"command": "set_time_delay", "value": 150}`. Times are in ns.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Stop,
+    Pause,
+    Resume,
+    Clear,
+    SetCumul {value: bool},
+    SetTimeDelay {value: usize},
+    SetTimeWidth {value: usize},
+}
+
+///`AcquisitionControl` receives the commands of the client during an acquisition. The measurement
+///loops call `apply` before each buffer.
+pub struct AcquisitionControl {
+    commands: Option<mpsc::Receiver<Command>>,
+    sock: Option<TcpStream>,
+    paused: bool,
+    stopped: bool,
+}
+
+impl AcquisitionControl {
+    ///No command is ever received. Used when reading from files.
+    pub fn none() -> Self {
+        Self {
+            commands: None,
+            sock: None,
+            paused: false,
+            stopped: false,
+        }
+    }
+
+    ///Receives the commands sent through `commands` by another thread.
+    pub fn from_channel(commands: mpsc::Receiver<Command>) -> Self {
+        Self {
+            commands: Some(commands),
+            sock: None,
+            paused: false,
+            stopped: false,
+        }
+    }
+
+    ///Listens for commands on the control socket, in a separate thread.
+    pub fn listen(sock: &TcpStream) -> Result<Self, Tp3ErrorKind> {
+        let mut reader = sock.try_clone().map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                match read_message(&mut reader) {
+                    Ok(ControlMessage::Framed {kind: KIND_COMMAND, payload}) => {
+                        match serde_json::from_slice::<Command>(&payload) {
+                            Ok(command) => if tx.send(command).is_err() {break;},
+                            Err(e) => println!("***Control Lib***: Bad command: {}.", e),
+                        }
+                    },
+                    Ok(_) => println!("***Control Lib***: Ignoring a message that is not a command."),
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(Self {
+            commands: Some(rx),
+            sock: Some(sock.try_clone().map_err(|e| Tp3ErrorKind::SocketRead("Nionswift", e))?),
+            paused: false,
+            stopped: false,
+        })
+    }
+
+    ///Applies the commands received since the last call. Returns true if the accumulated data
+    ///must be cleared.
+    pub fn apply(&mut self, settings: &mut Settings) -> bool {
+        let mut clear = false;
+        if let Some(commands) = &self.commands {
+            for command in commands.try_iter() {
+                println!("***Control Lib***: Received command {:?}.", command);
+                match command {
+                    Command::Stop => self.stopped = true,
+                    Command::Pause => self.paused = true,
+                    Command::Resume => self.paused = false,
+                    Command::Clear => clear = true,
+                    Command::SetCumul {value} => settings.cumul = value,
+                    Command::SetTimeDelay {value} => settings.time_delay = value,
+                    Command::SetTimeWidth {value: 0} => println!("***Control Lib***: Ignoring a time width of zero."),
+                    Command::SetTimeWidth {value} => settings.time_width = value,
+                }
+            }
+        }
+        clear
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+impl Drop for AcquisitionControl {
+    ///Unblocks the command thread, so it ends with the acquisition.
+    fn drop(&mut self) {
+        if let Some(sock) = &self.sock {
+            let _ = sock.shutdown(Shutdown::Read);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//!`detectorlib` describes how the chips of a TPX3 camera are put together. A `DetectorLayout`
+//!maps the pixel address given by each chip to the position in the assembled camera, and can be
+//!loaded from a TOML file so the same acquisition runs on different detectors.
+
+use crate::packetlib::Packet;
+use crate::errorlib::Tp3ErrorKind;
+use serde::Deserialize;
+use std::fs;
+
+///Number of pixels in each direction of a single chip.
+pub const CHIP_SIZE: usize = 256;
+///Maximum number of chips in a camera.
+pub const MAX_CHIPS: usize = 8;
+///Layout file read by the acquisition when present in the working directory.
+pub const LAYOUT_FILE: &str = "layout.toml";
+
+///Rotation of a chip, clockwise and in image coordinates (y pointing downwards).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Orientation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Orientation {
+    fn from_degrees(degrees: u16) -> Result<Self, Tp3ErrorKind> {
+        match degrees {
+            0 => Ok(Orientation::Rotate0),
+            90 => Ok(Orientation::Rotate90),
+            180 => Ok(Orientation::Rotate180),
+            270 => Ok(Orientation::Rotate270),
+            _ => Err(Tp3ErrorKind::LayoutBadChip(format!("orientation must be 0, 90, 180 or 270 degrees. Received {}", degrees))),
+        }
+    }
+}
+
+///Placement of a single chip. The chip address is first rotated, then flipped and finally
+///shifted by `offset`, in pixels.
+#[derive(Copy, Clone, Debug)]
+pub struct ChipLayout {
+    pub orientation: Orientation,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub offset: (usize, usize),
+}
+
+impl ChipLayout {
+    const UNUSED: ChipLayout = ChipLayout::new((0, 0), false, false);
+
+    ///Non-rotated chip at `position`, given in chips, with no gap in between.
+    pub const fn new(position: (usize, usize), flip_x: bool, flip_y: bool) -> Self {
+        ChipLayout {
+            orientation: Orientation::Rotate0,
+            flip_x,
+            flip_y,
+            offset: (position.0 * CHIP_SIZE, position.1 * CHIP_SIZE),
+        }
+    }
+
+    ///Position in the camera of the chip address (`x`, `y`).
+    #[inline]
+    pub fn map(&self, x: usize, y: usize) -> (usize, usize) {
+        let (x, y) = match self.orientation {
+            Orientation::Rotate0 => (x, y),
+            Orientation::Rotate90 => (CHIP_SIZE - 1 - y, x),
+            Orientation::Rotate180 => (CHIP_SIZE - 1 - x, CHIP_SIZE - 1 - y),
+            Orientation::Rotate270 => (y, CHIP_SIZE - 1 - x),
+        };
+        let x = if self.flip_x { CHIP_SIZE - 1 - x } else { x };
+        let y = if self.flip_y { CHIP_SIZE - 1 - y } else { y };
+        (x + self.offset.0, y + self.offset.1)
+    }
+
+    ///Chip address of the camera position (`x`, `y`), if it falls in this chip. Inverse of `map`.
+    pub fn unmap(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let x = x.checked_sub(self.offset.0).filter(|x| *x < CHIP_SIZE)?;
+        let y = y.checked_sub(self.offset.1).filter(|y| *y < CHIP_SIZE)?;
+        let x = if self.flip_x { CHIP_SIZE - 1 - x } else { x };
+        let y = if self.flip_y { CHIP_SIZE - 1 - y } else { y };
+        Some(match self.orientation {
+            Orientation::Rotate0 => (x, y),
+            Orientation::Rotate90 => (y, CHIP_SIZE - 1 - x),
+            Orientation::Rotate180 => (CHIP_SIZE - 1 - x, CHIP_SIZE - 1 - y),
+            Orientation::Rotate270 => (CHIP_SIZE - 1 - y, x),
+        })
+    }
+}
+
+///`DetectorLayout` is the geometry of the camera. Chips are indexed by the chip index of the
+///TPX3 stream. `extra_columns` are appended to each row of the assembled image (the live
+///spectrum uses the last one to count TDCs) and `extra_channels` are appended to each spectrum of
+///a hyperspectral image (used by the IsiBox channels).
+#[derive(Copy, Clone, Debug)]
+pub struct DetectorLayout {
+    chips: [ChipLayout; MAX_CHIPS],
+    nchips: usize,
+    width: usize,
+    height: usize,
+    extra_columns: usize,
+    extra_channels: usize,
+}
+
+#[derive(Deserialize)]
+struct LayoutFile {
+    #[serde(default)]
+    gap: usize,
+    #[serde(default = "default_extra_columns")]
+    extra_columns: usize,
+    #[serde(default = "default_extra_channels")]
+    extra_channels: usize,
+    chip: Vec<ChipFile>,
+}
+
+#[derive(Deserialize)]
+struct ChipFile {
+    position: (usize, usize),
+    #[serde(default)]
+    orientation: u16,
+    #[serde(default)]
+    flip_x: bool,
+    #[serde(default)]
+    flip_y: bool,
+    #[serde(default)]
+    offset: (usize, usize),
+}
+
+fn default_extra_columns() -> usize {
+    1
+}
+
+fn default_extra_channels() -> usize {
+    16
+}
+
+impl DetectorLayout {
+    ///The 1x4 EELS camera. Chips are all flipped horizontally and placed in the order 0, 3, 2, 1.
+    pub const fn eels() -> Self {
+        let mut chips = [ChipLayout::UNUSED; MAX_CHIPS];
+        chips[0] = ChipLayout::new((0, 0), true, false);
+        chips[1] = ChipLayout::new((3, 0), true, false);
+        chips[2] = ChipLayout::new((2, 0), true, false);
+        chips[3] = ChipLayout::new((1, 0), true, false);
+        DetectorLayout {
+            chips,
+            nchips: 4,
+            width: 4 * CHIP_SIZE,
+            height: CHIP_SIZE,
+            extra_columns: 1,
+            extra_channels: 16,
+        }
+    }
+
+    ///The 2x2 diffraction camera.
+    pub const fn diffraction() -> Self {
+        let mut chips = [ChipLayout::UNUSED; MAX_CHIPS];
+        chips[0] = ChipLayout::new((0, 0), true, false);
+        chips[1] = ChipLayout::new((0, 1), false, true);
+        chips[2] = ChipLayout::new((1, 1), false, true);
+        chips[3] = ChipLayout::new((1, 0), true, false);
+        DetectorLayout {
+            chips,
+            nchips: 4,
+            width: 2 * CHIP_SIZE,
+            height: 2 * CHIP_SIZE,
+            extra_columns: 0,
+            extra_channels: 16,
+        }
+    }
+
+    pub fn new(chips: &[ChipLayout], extra_columns: usize, extra_channels: usize) -> Result<Self, Tp3ErrorKind> {
+        if chips.is_empty() || chips.len() > MAX_CHIPS {return Err(Tp3ErrorKind::LayoutBadChip(format!("number of chips must be between 1 and {}. Received {}", MAX_CHIPS, chips.len())));}
+        for (first, a) in chips.iter().enumerate() {
+            for (second, b) in chips.iter().enumerate().skip(first + 1) {
+                if a.offset.0.abs_diff(b.offset.0) < CHIP_SIZE && a.offset.1.abs_diff(b.offset.1) < CHIP_SIZE {
+                    return Err(Tp3ErrorKind::LayoutBadChip(format!("chips {} and {} overlap", first, second)));
+                }
+            }
+        }
+        let mut layout = DetectorLayout {
+            chips: [ChipLayout::UNUSED; MAX_CHIPS],
+            nchips: chips.len(),
+            width: 0,
+            height: 0,
+            extra_columns,
+            extra_channels,
+        };
+        layout.chips[..chips.len()].copy_from_slice(chips);
+        layout.width = chips.iter().map(|chip| chip.offset.0 + CHIP_SIZE).max().unwrap();
+        layout.height = chips.iter().map(|chip| chip.offset.1 + CHIP_SIZE).max().unwrap();
+        Ok(layout)
+    }
+
+    ///Reads a layout from a TOML file. Chips are listed in chip index order. Example of the EELS
+    ///camera:
+    ///
+    ///```toml
+    ///gap = 0
+    ///extra_columns = 1
+    ///extra_channels = 16
+    ///
+    ///[[chip]]
+    ///position = [0, 0]
+    ///orientation = 0
+    ///flip_x = true
+    ///
+    ///[[chip]]
+    ///position = [3, 0]
+    ///flip_x = true
+    ///```
+    ///
+    ///`position` is given in chips and `gap` is the number of pixels in between neighbouring
+    ///chips. `orientation` is the clockwise rotation in degrees and `offset` an additional shift in
+    ///pixels.
+    pub fn from_file(path: &str) -> Result<Self, Tp3ErrorKind> {
+        let content = fs::read_to_string(path).map_err(|e| Tp3ErrorKind::LayoutNoReadFile(path.to_string(), e))?;
+        let file: LayoutFile = toml::from_str(&content).map_err(|e| Tp3ErrorKind::LayoutBadFormat(path.to_string(), e.to_string()))?;
+        let chips = file.chip.iter()
+            .map(|chip| {
+                Ok(ChipLayout {
+                    orientation: Orientation::from_degrees(chip.orientation)?,
+                    flip_x: chip.flip_x,
+                    flip_y: chip.flip_y,
+                    offset: (chip.position.0 * (CHIP_SIZE + file.gap) + chip.offset.0, chip.position.1 * (CHIP_SIZE + file.gap) + chip.offset.1),
+                })
+            })
+            .collect::<Result<Vec<ChipLayout>, Tp3ErrorKind>>()?;
+        DetectorLayout::new(&chips, file.extra_columns, file.extra_channels)
+    }
+
+    ///Reads the layout from `path` if it exists. The EELS camera is used otherwise.
+    pub fn from_file_or_default(path: &str) -> Result<Self, Tp3ErrorKind> {
+        match fs::metadata(path) {
+            Ok(_) => {
+                let layout = DetectorLayout::from_file(path)?;
+                println!("***Detector Lib***: Layout read from {}. Image size is {:?}.", path, layout.chip_array());
+                Ok(layout)
+            },
+            Err(_) => Ok(DetectorLayout::default()),
+        }
+    }
+
+    ///Position in the camera of the pixel of `packet`. None if its chip is not in the layout.
+    #[inline]
+    pub fn x_y<T: Packet + ?Sized>(&self, packet: &T) -> Option<(usize, usize)> {
+        self.chip(packet.ci()).map(|chip| chip.map(packet.x_raw(), packet.y_raw()))
+    }
+
+    #[inline]
+    pub fn x<T: Packet + ?Sized>(&self, packet: &T) -> Option<usize> {
+        self.x_y(packet).map(|(x, _)| x)
+    }
+
+    #[inline]
+    pub fn y<T: Packet + ?Sized>(&self, packet: &T) -> Option<usize> {
+        self.x_y(packet).map(|(_, y)| y)
+    }
+
+    ///Chip index and chip address of the camera position (`x`, `y`). Inverse of `x_y`.
+    pub fn chip_address(&self, x: usize, y: usize) -> Option<(usize, usize, usize)> {
+        self.chips[..self.nchips].iter()
+            .enumerate()
+            .find_map(|(ci, chip)| chip.unmap(x, y).map(|(x_raw, y_raw)| (ci, x_raw, y_raw)))
+    }
+
+    pub fn nchips(&self) -> usize {
+        self.nchips
+    }
+
+    pub fn chip(&self, ci: usize) -> Option<&ChipLayout> {
+        self.chips[..self.nchips].get(ci)
+    }
+
+    ///Size of the assembled image covered by the chips.
+    pub const fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    ///Size of the assembled image, extra columns included.
+    pub const fn chip_array(&self) -> (usize, usize) {
+        (self.width + self.extra_columns, self.height)
+    }
+
+    ///Number of channels of each spectrum of a hyperspectral image.
+    pub const fn spim_pixels(&self) -> usize {
+        self.width + self.extra_columns + self.extra_channels
+    }
+}
+
+impl Default for DetectorLayout {
+    fn default() -> Self {
+        DetectorLayout::eels()
+    }
+}
@@ -1,23 +1,103 @@
-//!`errorlib` is a simply enumeration to control error handling and logging.
+//!`errorlib` is a simply enumeration to control error handling and logging. Errors carry the
+//!context needed to log them (which TDC, which socket or file, how many bytes) and, when they
+//!come from the operating system, the underlying `io::Error`.
+use crate::tdclib::TdcType;
+use std::fmt;
+use std::io;
+
 #[derive(Debug)]
 pub enum Tp3ErrorKind {
-    SetBin,
-    SetByteDepth,
-    SetCumul,
-    SetMode,
+    SetBin(u8),
+    SetByteDepth(usize),
+    SetCumul(u8),
+    SetMode(u8),
     SetXSize,
     SetYSize,
-    SetNoReadFile,
-    SetNoWriteFile,
+    SetConfigSize(usize),
+    SetArgument(String),
+    SetProtocolVersion(u8),
+    SetMessageKind(u8),
+    SetMessage(String),
+    SetNoReadFile(String, io::Error),
+    SetNoWriteFile(String, io::Error),
+
+    SocketBind(String, io::Error),
+    SocketAccept(&'static str, io::Error),
+    SocketRead(&'static str, io::Error),
+    SocketWrite(&'static str, io::Error),
 
-    TdcNoReceived,
-    TdcBadPeriod,
-    TdcBadHighTime,
-    TdcNotAscendingOrder,
+    TdcNoReceived(TdcType),
+    TdcBadPeriod(TdcType),
+    TdcBadHighTime(TdcType),
+    TdcNotAscendingOrder(TdcType),
     TdcZeroBytes,
 
+    LayoutNoReadFile(String, io::Error),
+    LayoutBadFormat(String, String),
+    LayoutBadChip(String),
+
+    CalibrationNoReadFile(String, io::Error),
+    CalibrationBadFormat(String),
+    CalibrationNoCoincidence,
+    CalibrationNegativeDelay(f64),
+
+    DatasetEmpty(String),
+
     MiscModeNotImplemented(u8),
 
-    TimepixReadLoop,
-    TimepixReadOver,
+    TimepixReadLoop(io::Error),
+    TimepixReadOver(usize),
+}
+
+impl fmt::Display for Tp3ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tp3ErrorKind::SetBin(val) => write!(f, "Invalid binning byte {}. Expected 0 or 1", val),
+            Tp3ErrorKind::SetByteDepth(val) => write!(f, "Invalid bytedepth {}", val),
+            Tp3ErrorKind::SetCumul(val) => write!(f, "Invalid cumulation byte {}. Expected 0 or 1", val),
+            Tp3ErrorKind::SetMode(val) => write!(f, "Invalid acquisition mode {}", val),
+            Tp3ErrorKind::SetXSize => write!(f, "X spim size must be greater than zero"),
+            Tp3ErrorKind::SetYSize => write!(f, "Y spim size must be greater than zero"),
+            Tp3ErrorKind::SetConfigSize(size) => write!(f, "Received {} bytes of configuration", size),
+            Tp3ErrorKind::SetArgument(msg) => write!(f, "Bad argument: {}", msg),
+            Tp3ErrorKind::SetProtocolVersion(version) => write!(f, "Unsupported control protocol version {}", version),
+            Tp3ErrorKind::SetMessageKind(kind) => write!(f, "Unexpected control message kind {}", kind),
+            Tp3ErrorKind::SetMessage(msg) => write!(f, "Bad control message: {}", msg),
+            Tp3ErrorKind::SetNoReadFile(path, e) => write!(f, "Could not read file {}: {}", path, e),
+            Tp3ErrorKind::SetNoWriteFile(path, e) => write!(f, "Could not write file {}: {}", path, e),
+            Tp3ErrorKind::SocketBind(addr, e) => write!(f, "Could not bind to {}: {}", addr, e),
+            Tp3ErrorKind::SocketAccept(name, e) => write!(f, "Could not connect to {}: {}", name, e),
+            Tp3ErrorKind::SocketRead(name, e) => write!(f, "Could not read from {}: {}", name, e),
+            Tp3ErrorKind::SocketWrite(name, e) => write!(f, "Could not write to {}: {}", name, e),
+            Tp3ErrorKind::TdcNoReceived(tdc) => write!(f, "{} was not received", tdc.associate_str()),
+            Tp3ErrorKind::TdcBadPeriod(tdc) => write!(f, "Could not find the period of {}", tdc.associate_str()),
+            Tp3ErrorKind::TdcBadHighTime(tdc) => write!(f, "Could not find the high time of {}", tdc.associate_str()),
+            Tp3ErrorKind::TdcNotAscendingOrder(tdc) => write!(f, "{} arrived in non-ascending order", tdc.associate_str()),
+            Tp3ErrorKind::TdcZeroBytes => write!(f, "Zero bytes received while searching for a Tdc"),
+            Tp3ErrorKind::LayoutNoReadFile(path, e) => write!(f, "Could not read layout file {}: {}", path, e),
+            Tp3ErrorKind::LayoutBadFormat(path, msg) => write!(f, "Bad layout file {}: {}", path, msg),
+            Tp3ErrorKind::LayoutBadChip(msg) => write!(f, "Bad chip in layout: {}", msg),
+            Tp3ErrorKind::CalibrationNoReadFile(path, e) => write!(f, "Could not read calibration file {}: {}", path, e),
+            Tp3ErrorKind::CalibrationBadFormat(msg) => write!(f, "Bad calibration: {}", msg),
+            Tp3ErrorKind::CalibrationNoCoincidence => write!(f, "No electron-photon pair was found to calibrate the delay"),
+            Tp3ErrorKind::CalibrationNegativeDelay(delay) => write!(f, "The calibrated delay is {} ns, but the photons must arrive after their electron", delay),
+            Tp3ErrorKind::DatasetEmpty(path) => write!(f, "No TPX3 file was found in {}", path),
+            Tp3ErrorKind::MiscModeNotImplemented(mode) => write!(f, "Mode {} is not implemented", mode),
+            Tp3ErrorKind::TimepixReadLoop(e) => write!(f, "Could not read from the Timepix: {}", e),
+            Tp3ErrorKind::TimepixReadOver(size) => write!(f, "Read {} bytes from the Timepix, which is not a whole number of packets", size),
+        }
+    }
+}
+
+impl std::error::Error for Tp3ErrorKind {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Tp3ErrorKind::SetNoReadFile(_, e) | Tp3ErrorKind::SetNoWriteFile(_, e) |
+            Tp3ErrorKind::SocketBind(_, e) | Tp3ErrorKind::SocketAccept(_, e) |
+            Tp3ErrorKind::SocketRead(_, e) | Tp3ErrorKind::SocketWrite(_, e) |
+            Tp3ErrorKind::LayoutNoReadFile(_, e) | Tp3ErrorKind::CalibrationNoReadFile(_, e) |
+            Tp3ErrorKind::TimepixReadLoop(e) => Some(e),
+            _ => None,
+        }
+    }
 }
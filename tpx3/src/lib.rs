@@ -6,6 +6,9 @@
 //!Ethernet.
 
 pub mod auxiliar;
+pub mod controllib;
+pub mod configlib;
+pub mod modelib;
 pub mod tdclib;
 pub mod packetlib;
 pub mod postlib;
@@ -13,3 +16,7 @@ pub mod speclib;
 pub mod spimlib;
 pub mod errorlib;
 pub mod clusterlib;
+pub mod detectorlib;
+pub mod calibrationlib;
+pub mod simulationlib;
+pub mod npylib;
@@ -1,71 +1,30 @@
 use timepix3::errorlib::Tp3ErrorKind;
 use timepix3::auxiliar::*;
-use timepix3::tdclib::*;
-use timepix3::{speclib, spimlib, spimlib::SpimKind};
+use timepix3::configlib::Tp3Config;
+use timepix3::modelib;
 
 
-fn connect_and_loop() -> Result<u8, Tp3ErrorKind> {
+fn connect_and_loop(config: &Tp3Config) -> Result<u8, Tp3ErrorKind> {
     
-    let (my_settings, mut pack, ns) = Settings::create_settings([192, 168, 199, 11], 8088)?;
-
-    match my_settings.mode {
-        0 if my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Live1D)?;
-            Ok(my_settings.mode)
-        },
-        0 if !my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Live2D)?;
-            Ok(my_settings.mode)
-        },
-        1 if my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let laser_tdc = SingleTriggerPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, laser_tdc, speclib::LiveTR1D)?;
-            Ok(my_settings.mode)
-        },
-        1 if !my_settings.bin => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let laser_tdc = SingleTriggerPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, laser_tdc, speclib::LiveTR2D)?;
-            Ok(my_settings.mode)
-        },
-        2 => {
-            let spim_tdc = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut pack, Some(my_settings.yspim_size))?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            let measurement = spimlib::Live::new();
-            spimlib::build_spim(pack, ns, my_settings, spim_tdc, np_tdc, measurement)?;
-            Ok(my_settings.mode)
-        },
-        6 => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::FastChrono)?;
-            Ok(my_settings.mode)
-        },
-        7 => {
-            let frame_tdc = PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut pack, None)?;
-            let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut pack, None)?;
-            speclib::run_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, speclib::Chrono)?;
-            Ok(my_settings.mode)
-        },
-        _ => Err(Tp3ErrorKind::MiscModeNotImplemented(my_settings.mode)),
-    }
+    let (my_settings, pack, ns, control) = Settings::create_settings(config)?;
+    modelib::run(my_settings, pack, ns, control, config)
 }
 
-fn main() {
-    let mut log_file = simple_log::start().unwrap();
+fn main() -> Result<(), Tp3ErrorKind> {
+    let (config, _) = Tp3Config::load(&std::env::args().collect::<Vec<String>>())?;
+    let mut log_file = simple_log::start(&config.log_dir).map_err(|e| Tp3ErrorKind::SetNoWriteFile(config.log_dir.clone(), e))?;
     loop {
-        match connect_and_loop() {
+        match connect_and_loop(&config) {
             Ok(val) => {
-                simple_log::ok(&mut log_file, val).unwrap();
+                if let Err(e) = simple_log::ok(&mut log_file, val) {
+                    println!("Could not write to the log file. Error message: {}.", e);
+                }
             },
             Err(e) => {
-                println!("Error in measurement. Error message: {:?}.", e);
-                simple_log::error(&mut log_file, e).unwrap();
+                println!("Error in measurement. Error message: {}.", e);
+                if let Err(e) = simple_log::error(&mut log_file, e) {
+                    println!("Could not write to the log file. Error message: {}.", e);
+                }
             },
         }
     }
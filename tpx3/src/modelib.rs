@@ -0,0 +1,169 @@
+//!`modelib` is the registry of the acquisition modes. Each mode received from the client is
+//!mapped to its measurement and to the roles of its two TDCs. Roles are resolved to TDC inputs
+//!using the `Settings`, so the wiring of a setup is a matter of configuration (see `configlib`).
+use crate::auxiliar::{Settings, misc::{TimepixRead, TeeRead}};
+use crate::configlib::Tp3Config;
+use crate::controllib::AcquisitionControl;
+use crate::errorlib::Tp3ErrorKind;
+use crate::speclib::{self, GenerateDepth, SpecKind, SpecMeasurement};
+use crate::spimlib::{self, SpimKind};
+use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef, NonPeriodicTdcRef, SingleTriggerPeriodicTdcRef};
+use chrono::prelude::*;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+type Pack = Box<dyn TimepixRead + Send>;
+type Output = Box<dyn Write + Send>;
+type Launcher = fn(Settings, Pack, Output, &Mode, AcquisitionControl, &Tp3Config) -> Result<(), Tp3ErrorKind>;
+
+///Function of a TDC within a mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TdcRole {
+    ///Beginning of the frames of the spectral modes.
+    Frame,
+    ///Beginning of the lines of the hyperspectral modes.
+    Line,
+    ///Laser trigger of the time-resolved modes.
+    Laser,
+    ///Photon detection.
+    Photon,
+}
+
+impl TdcRole {
+    ///The TDC input playing this role.
+    pub fn tdc(&self, settings: &Settings) -> TdcType {
+        match self {
+            TdcRole::Frame => settings.frame_tdc,
+            TdcRole::Line => settings.spim_tdc,
+            TdcRole::Laser => settings.laser_tdc,
+            TdcRole::Photon => settings.ref_tdc,
+        }
+    }
+}
+
+///An acquisition mode. The periodic TDC defines the frames (or lines) and the reference TDC is
+///used by the measurement.
+pub struct Mode {
+    pub id: u8,
+    ///Binning the mode is restricted to, if any.
+    pub bin: Option<bool>,
+    pub name: &'static str,
+    pub periodic: TdcRole,
+    pub reference: TdcRole,
+    launch: Launcher,
+}
+
+impl Mode {
+    fn accepts(&self, settings: &Settings) -> bool {
+        self.id == settings.mode && self.bin.unwrap_or(settings.bin) == settings.bin
+    }
+}
+
+fn spectrum<T, Y>(settings: Settings, mut pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, kind: Y) -> Result<(), Tp3ErrorKind>
+    where T: TdcControl,
+          Y: GenerateDepth,
+          SpecMeasurement<Y, u8>: SpecKind,
+          SpecMeasurement<Y, u16>: SpecKind,
+          SpecMeasurement<Y, u32>: SpecKind
+{
+    let frame_tdc = PeriodicTdcRef::new(mode.periodic.tdc(&settings), &mut pack, None)?;
+    let ref_tdc = T::new(mode.reference.tdc(&settings), &mut pack, None)?;
+    speclib::run_spectrum(pack, ns, settings, frame_tdc, ref_tdc, kind, control)?;
+    Ok(())
+}
+
+fn live_1d(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<NonPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::Live1D)
+}
+
+fn live_2d(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<NonPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::Live2D)
+}
+
+fn live_tr_1d(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<SingleTriggerPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::LiveTR1D)
+}
+
+fn live_tr_2d(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<SingleTriggerPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::LiveTR2D)
+}
+
+fn fast_chrono(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<NonPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::FastChrono)
+}
+
+fn chrono(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<NonPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::Chrono)
+}
+
+fn spim<T, W>(settings: Settings, mut pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl) -> Result<(), Tp3ErrorKind>
+    where T: 'static + Send + TdcControl,
+          W: 'static + Send + SpimKind
+{
+    let spim_tdc = PeriodicTdcRef::new(mode.periodic.tdc(&settings), &mut pack, Some(settings.yspim_size))?;
+    let ref_tdc = T::new(mode.reference.tdc(&settings), &mut pack, None)?;
+    spimlib::build_spim(pack, ns, settings, spim_tdc, ref_tdc, W::new(), control)
+}
+
+fn time_delay(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<SingleTriggerPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::TimeDelay)
+}
+
+fn coincidence(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spectrum::<NonPeriodicTdcRef, _>(settings, pack, ns, mode, control, speclib::LiveCoincidence::default())
+}
+
+fn live_spim(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spim::<NonPeriodicTdcRef, spimlib::Live>(settings, pack, ns, mode, control)
+}
+
+fn time_resolved_spim(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spim::<SingleTriggerPeriodicTdcRef, spimlib::LiveTimeResolved>(settings, pack, ns, mode, control)
+}
+
+fn tdc_spim(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, _config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    spim::<NonPeriodicTdcRef, spimlib::LiveTdc>(settings, pack, ns, mode, control)
+}
+
+///The raw data is saved in `save_dir` while the spectral image is shown.
+fn saved_spim(settings: Settings, pack: Pack, ns: Output, mode: &Mode, control: AcquisitionControl, config: &Tp3Config) -> Result<(), Tp3ErrorKind> {
+    let dir = Path::new(&config.save_dir);
+    fs::create_dir_all(dir).map_err(|e| Tp3ErrorKind::SetNoWriteFile(config.save_dir.clone(), e))?;
+    let path = dir.join(Local::now().format("spim_%Y-%m-%d_%H-%M-%S.tpx3").to_string());
+    println!("***Mode Lib***: Raw data is saved in {}.", path.display());
+    let pack: Pack = Box::new(TeeRead::new(pack, &path)?);
+    spim::<NonPeriodicTdcRef, spimlib::Live>(settings, pack, ns, mode, control)
+}
+
+///The modes known by the acquisition.
+pub static MODES: [Mode; 12] = [
+    Mode {id: 0, bin: Some(true), name: "Live1D", periodic: TdcRole::Frame, reference: TdcRole::Photon, launch: live_1d},
+    Mode {id: 0, bin: Some(false), name: "Live2D", periodic: TdcRole::Frame, reference: TdcRole::Photon, launch: live_2d},
+    Mode {id: 1, bin: Some(true), name: "LiveTR1D", periodic: TdcRole::Frame, reference: TdcRole::Laser, launch: live_tr_1d},
+    Mode {id: 1, bin: Some(false), name: "LiveTR2D", periodic: TdcRole::Frame, reference: TdcRole::Laser, launch: live_tr_2d},
+    Mode {id: 2, bin: None, name: "LiveSpim", periodic: TdcRole::Line, reference: TdcRole::Photon, launch: live_spim},
+    Mode {id: 3, bin: None, name: "LiveTimeResolvedSpim", periodic: TdcRole::Line, reference: TdcRole::Laser, launch: time_resolved_spim},
+    Mode {id: 4, bin: None, name: "LiveTdcSpim", periodic: TdcRole::Line, reference: TdcRole::Photon, launch: tdc_spim},
+    Mode {id: 5, bin: None, name: "SavedSpim", periodic: TdcRole::Line, reference: TdcRole::Photon, launch: saved_spim},
+    Mode {id: 6, bin: None, name: "FastChrono", periodic: TdcRole::Frame, reference: TdcRole::Photon, launch: fast_chrono},
+    Mode {id: 7, bin: None, name: "Chrono", periodic: TdcRole::Frame, reference: TdcRole::Photon, launch: chrono},
+    Mode {id: 8, bin: None, name: "TimeDelay", periodic: TdcRole::Frame, reference: TdcRole::Laser, launch: time_delay},
+    Mode {id: 9, bin: None, name: "LiveCoincidence", periodic: TdcRole::Frame, reference: TdcRole::Photon, launch: coincidence},
+];
+
+///The mode matching the settings.
+pub fn find(settings: &Settings) -> Result<&'static Mode, Tp3ErrorKind> {
+    MODES.iter()
+        .find(|mode| mode.accepts(settings))
+        .ok_or(Tp3ErrorKind::MiscModeNotImplemented(settings.mode))
+}
+
+///Runs the acquisition of the mode matching the settings. Returns the mode.
+pub fn run(settings: Settings, pack: Pack, ns: Output, control: AcquisitionControl, config: &Tp3Config) -> Result<u8, Tp3ErrorKind> {
+    let mode = find(&settings)?;
+    println!("***Mode Lib***: Mode {} is {}. Periodic TDC is {:?} ({}) and reference TDC is {:?} ({}).", mode.id, mode.name,
+        mode.periodic, mode.periodic.tdc(&settings).associate_str(), mode.reference, mode.reference.tdc(&settings).associate_str());
+    (mode.launch)(settings, pack, ns, mode, control, config)?;
+    Ok(settings.mode)
+}
@@ -0,0 +1,110 @@
+//!`npylib` writes NumPy `.npy` arrays, so results can be loaded in Python with `numpy.load`
+//!instead of parsing text files. Arrays are little-endian and in C order.
+use crate::errorlib::Tp3ErrorKind;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+const HEADER_LEN: usize = 128; //Fixed, so the shape of a streamed array can be rewritten in place.
+
+///Types that can be stored in an array. `DESCR` is the NumPy type string.
+pub trait NpyType: Copy {
+    const DESCR: &'static str;
+    fn write_le(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! npy_type {
+    ($($t:ty => $descr:expr, $as:ty);* $(;)?) => {
+        $(impl NpyType for $t {
+            const DESCR: &'static str = $descr;
+            fn write_le(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&(*self as $as).to_le_bytes());
+            }
+        })*
+    };
+}
+
+npy_type!(
+    u8 => "|u1", u8;
+    u16 => "<u2", u16;
+    u32 => "<u4", u32;
+    u64 => "<u8", u64;
+    usize => "<u8", u64;
+    i64 => "<i8", i64;
+    isize => "<i8", i64;
+    f32 => "<f4", f32;
+    f64 => "<f8", f64;
+);
+
+///Magic string and header, padded with spaces to `HEADER_LEN` bytes.
+fn header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape.len() {
+        1 => format!("({},)", shape[0]),
+        _ => format!("({})", shape.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    let mut out = MAGIC.to_vec();
+    let len = HEADER_LEN - MAGIC.len() - 2;
+    out.extend_from_slice(&(len as u16).to_le_bytes());
+    out.extend_from_slice(dict.as_bytes());
+    out.resize(HEADER_LEN - 1, b' ');
+    out.push(b'\n');
+    out
+}
+
+fn as_bytes<T: NpyType>(data: &[T]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(std::mem::size_of_val(data));
+    data.iter().for_each(|x| x.write_le(&mut out));
+    out
+}
+
+///Writes `data`, of the given `shape`, in the file `path`.
+pub fn write_npy<T: NpyType>(path: &Path, data: &[T], shape: &[usize]) -> Result<(), Tp3ErrorKind> {
+    assert_eq!(shape.iter().product::<usize>(), data.len());
+    let mut out = header(T::DESCR, shape);
+    out.extend(as_bytes(data));
+    fs::write(path, out).map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))
+}
+
+///A one-dimensional array written as it grows. The header is updated after each append, so the
+///file can be loaded at any time.
+pub struct NpyWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    descr: &'static str,
+    len: usize,
+}
+
+impl NpyWriter {
+    pub fn create<T: NpyType>(path: &Path) -> Result<Self, Tp3ErrorKind> {
+        let file = File::create(path).map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))?;
+        let mut writer = NpyWriter {path: path.to_path_buf(), file: BufWriter::new(file), descr: T::DESCR, len: 0};
+        writer.append::<T>(&[])?;
+        Ok(writer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn descr(&self) -> &'static str {
+        self.descr
+    }
+
+    pub fn append<T: NpyType>(&mut self, data: &[T]) -> Result<(), Tp3ErrorKind> {
+        assert_eq!(T::DESCR, self.descr);
+        let path = &self.path;
+        let error = |e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e);
+        self.file.seek(SeekFrom::End(0)).map_err(error)?;
+        self.file.write_all(&as_bytes(data)).map_err(error)?;
+        self.len += data.len();
+        self.file.seek(SeekFrom::Start(0)).map_err(error)?;
+        self.file.write_all(&header(self.descr, &[self.len])).map_err(error)?;
+        self.file.flush().map_err(error)
+    }
+}
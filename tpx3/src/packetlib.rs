@@ -1,47 +1,41 @@
 //!`packetlib` is a collection of tools to facilitate manipulation of individual TP3 packets. Module is built
 //!in around `Packet` struct.
 
+use crate::detectorlib::DetectorLayout;
+use crate::calibrationlib::{TimeCalibration, EnergyCalibration};
+use std::marker::PhantomData;
+use std::ops::{Add, AddAssign, Sub, SubAssign, Rem, Div, Mul};
+
+const EELS_LAYOUT: DetectorLayout = DetectorLayout::eels();
+const DIFFRACTION_LAYOUT: DetectorLayout = DetectorLayout::diffraction();
+
 pub trait Packet {
     fn ci(&self) -> usize;
     fn data(&self) -> &[u8; 8];
-    fn x(&self) -> usize {
-        let temp = ((self.data()[6] & 224)>>4 | (self.data()[7] << 4) | ((self.data()[5] & 112) >> 6)) as usize;
-        //(!temp & 255) | (temp & 768)
-
-        match self.ci() {
-            0 => 255 - temp,
-            1 => 256 * 4 - 1 - temp,
-            2 => 256 * 3 - 1 - temp,
-            3 => 256 * 2 - 1 - temp,
-            _ => panic!("More than four CIs."),
-        }
+
+    ///X in the EELS camera. See `detectorlib::DetectorLayout` for other geometries.
+    fn x(&self) -> Option<usize> {
+        EELS_LAYOUT.x(self)
     }
     
+    ///X address within the chip.
     fn x_raw(&self) -> usize {
         let x = (((self.data()[6] & 224)>>4 | (self.data()[7] & 15)<<4) | ((self.data()[5] & 64)>>6)) as usize;
         x
     }
     
-    fn y(&self) -> usize {
+    ///Y address within the chip.
+    fn y_raw(&self) -> usize {
         let y = (   ( (self.data()[5] & 128)>>5 | (self.data()[6] & 31)<<3 ) | ( ((self.data()[5] & 112)>>4) & 3 )   ) as usize;
         y
     }
 
-    fn x_y(&self) -> (usize, usize) {
-        let dcol = (self.data()[6] & 224)>>4 | (self.data()[7] << 4);
-        let spix = (self.data()[5] & 128) >> 5 | (self.data()[6] & 31) << 3;
-        let pix = (self.data()[5] & 112) >> 4;
+    fn y(&self) -> Option<usize> {
+        EELS_LAYOUT.y(self)
+    }
 
-        let temp = (dcol | (pix >> 2)) as usize;
-        let y = (spix | (pix & 3)) as usize;
-        
-        match self.ci() {
-            0 => (255 - temp, y),
-            1 => (256 * 4 - 1 - temp, y),
-            2 => (256 * 3 - 1 - temp, y),
-            3 => (256 * 2 - 1 - temp, y),
-            _ => panic!("More than four CIs."),
-        }
+    fn x_y(&self) -> Option<(usize, usize)> {
+        EELS_LAYOUT.x_y(self)
     }
 
     #[inline]
@@ -64,6 +58,12 @@ pub trait Packet {
         ((self.data()[2] & 240) as u16)>>4 | ((self.data()[3] & 63) as u16)<<4
     }
 
+    ///Deposited energy of the hit. See `calibrationlib::EnergyCalibration`.
+    #[inline]
+    fn energy(&self, calibration: &EnergyCalibration) -> f32 {
+        calibration.energy(self)
+    }
+
     #[inline]
     fn toa(&self) -> usize {
         ((self.data()[3] >> 6) as usize) | (self.data()[4] as usize)<<2 | ((self.data()[5] & 15) as usize)<<10
@@ -136,10 +136,95 @@ pub trait Packet {
         coarse * 12 + fine
     }
 
+    ///`electron_time` as a `Timestamp`.
+    #[inline]
+    fn electron_timestamp(&self) -> Timestamp {
+        Timestamp::new(self.electron_time() as u64)
+    }
+
+    ///`tdc_time_norm` as a `Timestamp`, in the electron time frame.
+    #[inline]
+    fn tdc_timestamp(&self) -> Timestamp {
+        Timestamp::new(self.tdc_time_norm() as u64)
+    }
+
+    ///`tdc_time_abs` as a `Timestamp`, with the full TDC resolution.
+    #[inline]
+    fn tdc_timestamp_abs(&self) -> Timestamp<Clock3840MHz> {
+        Timestamp::new(self.tdc_time_abs() as u64)
+    }
+
     #[inline]
     fn electron_reset_time() -> usize {
         26_843_545_600 * 16 / 25
     }
+
+    ///The packet as a little-endian 64-bit word.
+    #[inline]
+    fn raw(&self) -> u64 {
+        u64::from_le_bytes(*self.data())
+    }
+
+    ///Decodes the packet family and its fields.
+    fn kind(&self) -> PacketKind {
+        match self.id() {
+            11 => PacketKind::Pixel,
+            10 => PacketKind::PixelCounting,
+            6 => PacketKind::Tdc {
+                tdc_type: self.tdc_type(),
+                counter: self.tdc_counter(),
+                coarse: self.tdc_coarse(),
+                fine: self.tdc_fine(),
+            },
+            4 => match self.data()[7] & 15 {
+                4 => PacketKind::GlobalTimeLow { time: ((self.raw() >> 16) & 0xFFFF_FFFF) as u32 },
+                5 => PacketKind::GlobalTimeHigh { time: ((self.raw() >> 16) & 0xFFFF) as u16 },
+                sub => PacketKind::Unknown { id: 4, sub },
+            },
+            5 => PacketKind::ExtraTimestamp {
+                sub: self.data()[7] & 15,
+                time: self.raw() & 0x00FF_FFFF_FFFF_FFFF,
+            },
+            7 => match (self.data()[7] & 15, self.data()[6]) {
+                (1, 0xA0) => PacketKind::Control(ControlKind::EndOfSequentialCommand),
+                (1, 0xB0) => PacketKind::Control(ControlKind::EndOfReadout),
+                (sub, command) => PacketKind::Control(ControlKind::Acknowledge { command: (sub as u16) << 8 | command as u16 }),
+            },
+            id => PacketKind::Unknown { id, sub: self.data()[7] & 15 },
+        }
+    }
+}
+
+///Control packets (`0x7`) sent by the chips or by SPIDR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlKind {
+    ///`0x71B0`. A chip finished its readout.
+    EndOfReadout,
+    ///`0x71A0`. End of a sequential (frame-based) readout command.
+    EndOfSequentialCommand,
+    ///Any other control or acknowledge packet. `command` holds the sub-header and command bytes.
+    Acknowledge { command: u16 },
+}
+
+///Typed view of every packet family carried by the SPIDR stream, with their fields decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    ///`0xB`. Pixel hit in ToA and ToT mode. Use the `Packet` accessors for its fields.
+    Pixel,
+    ///`0xA`. Pixel hit in event counting mode.
+    PixelCounting,
+    ///`0x6`. TDC timestamp. `coarse` is in 320 MHz ticks and `fine` in 1/12 of these.
+    Tdc { tdc_type: u8, counter: u16, coarse: usize, fine: usize },
+    ///`0x44`. Lower 32 bits of the 48-bit global time (40 MHz).
+    GlobalTimeLow { time: u32 },
+    ///`0x45`. Upper 16 bits of the 48-bit global time (40 MHz).
+    GlobalTimeHigh { time: u16 },
+    ///`0x5`. Extra timestamp inserted by the readout. `time` holds the 56 bits below the header.
+    ExtraTimestamp { sub: u8, time: u64 },
+    ///`0x7`. Control and acknowledge packets.
+    Control(ControlKind),
+    ///Anything else.
+    Unknown { id: u8, sub: u8 },
 }
 
 pub struct PacketEELS<'a> {
@@ -158,13 +243,15 @@ impl<'a> Packet for PacketEELS<'a> {
 
 impl<'a> PacketEELS<'a> {
     pub const fn chip_array() -> (usize, usize) {
-        (1025, 256)
+        EELS_LAYOUT.chip_array()
     }
 }
 
+///EELS packet whose electron time is corrected by a `TimeCalibration`.
 pub struct TimeCorrectedPacketEELS<'a> {
     pub chip_index: usize,
     pub data: &'a [u8; 8],
+    pub calibration: &'a TimeCalibration,
 }
 
 impl<'a> Packet for TimeCorrectedPacketEELS<'a> {
@@ -184,18 +271,22 @@ impl<'a> Packet for TimeCorrectedPacketEELS<'a> {
     fn electron_time(&self) -> usize {
         let spidr = self.spidr();
         let ctoa = self.ctoa();
-        let x = self.x();
         let t = spidr * 262_144 + ctoa;
-        match x {
-            52..=61 | 308..=317 | 560..=575 | 580..=581 | 584..=585 | 592..=593 | 820..=829 => t-16,
-            _ => t,
-        }
+        self.calibration.correct(t, self)
     }
 }
 
 impl<'a> TimeCorrectedPacketEELS<'a> {
+    pub fn new(packet: PacketEELS<'a>, calibration: &'a TimeCalibration) -> Self {
+        TimeCorrectedPacketEELS {
+            chip_index: packet.chip_index,
+            data: packet.data,
+            calibration,
+        }
+    }
+
     pub const fn chip_array() -> (usize, usize) {
-        (1025, 256)
+        EELS_LAYOUT.chip_array()
     }
 }
 
@@ -211,32 +302,518 @@ impl<'a> Packet for PacketDiffraction<'a> {
     fn data(&self) -> &[u8; 8] {
         self.data
     }
-    fn x(&self) -> usize {
-        let temp = (((self.data[6] & 224)>>4 | (self.data[7] & 15)<<4) | (((self.data[5] & 112)>>4)>>2)) as usize;
-        match self.chip_index {
-            0 => 255 - temp,
-            1 => temp,
-            2 => temp + 256,
-            3 => 256 * 2 - 1 - temp,
-            _ => panic!("More than four CI."),
-        }
+    fn x(&self) -> Option<usize> {
+        DIFFRACTION_LAYOUT.x(self)
     }
 
-    fn y(&self) -> usize {
-        let temp = (   ( (self.data[5] & 128)>>5 | (self.data[6] & 31)<<3 ) | ( ((self.data[5] & 112)>>4) & 3 )   ) as usize;
-        match self.chip_index {
-            0 => temp,
-            1 => 256 * 2 - 1 - temp,
-            2 => 256 * 2 - 1 - temp,
-            3 => temp,
-            _ => panic!("More than four CI."),
-        }
+    fn y(&self) -> Option<usize> {
+        DIFFRACTION_LAYOUT.y(self)
+    }
+
+    fn x_y(&self) -> Option<(usize, usize)> {
+        DIFFRACTION_LAYOUT.x_y(self)
     }
 }
 
 impl<'a> PacketDiffraction<'a> {
     pub const fn chip_array() -> (usize, usize) {
-        (512, 512)
+        DIFFRACTION_LAYOUT.chip_array()
+    }
+}
+
+impl<'a> From<PacketEELS<'a>> for PacketDiffraction<'a> {
+    fn from(packet: PacketEELS<'a>) -> Self {
+        PacketDiffraction {
+            chip_index: packet.chip_index,
+            data: packet.data,
+        }
+    }
+}
+
+///`Event` is a single TPX3 packet classified by its `id`. The chip index of the packet is
+///already resolved from the last `TPX3` chunk header.
+pub enum Event<'a> {
+    Electron(PacketEELS<'a>),
+    Tdc(PacketEELS<'a>),
+    GlobalTime(PacketEELS<'a>),
+    Control(PacketEELS<'a>),
+    ExtraTimestamp(PacketEELS<'a>),
+    Other(PacketEELS<'a>),
+}
+
+impl<'a> Event<'a> {
+    fn from_packet(packet: PacketEELS<'a>) -> Self {
+        match packet.id() {
+            11 => Event::Electron(packet),
+            6 => Event::Tdc(packet),
+            4 => Event::GlobalTime(packet),
+            7 => Event::Control(packet),
+            5 => Event::ExtraTimestamp(packet),
+            _ => Event::Other(packet),
+        }
+    }
+
+    ///The underlying packet, whatever its kind.
+    pub fn packet(&self) -> &PacketEELS<'a> {
+        match self {
+            Event::Electron(packet) | Event::Tdc(packet) | Event::GlobalTime(packet) | Event::Control(packet) | Event::ExtraTimestamp(packet) | Event::Other(packet) => packet,
+        }
+    }
+
+    ///Decoded packet family. See `Packet::kind`.
+    pub fn kind(&self) -> PacketKind {
+        self.packet().kind()
+    }
+}
+
+///`PacketStatistics` counts every packet seen by a `PacketStream`, per family, so one can tell
+///what an acquisition is discarding.
+#[derive(Default, Clone, Debug)]
+pub struct PacketStatistics {
+    pub chunks: usize,
+    pub per_id: [usize; 16],
+    pub end_of_readout: Vec<usize>,
+}
+
+impl PacketStatistics {
+    fn add_packet(&mut self, packet: &PacketEELS) {
+        let id = packet.id();
+        self.per_id[id as usize] += 1;
+        if id == 7 && packet.kind() == PacketKind::Control(ControlKind::EndOfReadout) {
+            if self.end_of_readout.len() <= packet.chip_index {
+                self.end_of_readout.resize(packet.chip_index + 1, 0);
+            }
+            self.end_of_readout[packet.chip_index] += 1;
+        }
+    }
+
+    ///Total number of packets, chunk headers excluded.
+    pub fn total(&self) -> usize {
+        self.per_id.iter().sum()
+    }
+
+    pub fn pixel(&self) -> usize {
+        self.per_id[11]
+    }
+
+    pub fn tdc(&self) -> usize {
+        self.per_id[6]
+    }
+
+    pub fn global_time(&self) -> usize {
+        self.per_id[4]
+    }
+
+    pub fn extra_timestamp(&self) -> usize {
+        self.per_id[5]
+    }
+
+    pub fn control(&self) -> usize {
+        self.per_id[7]
+    }
+
+    ///Packets that do not belong to any of the known families.
+    pub fn unknown(&self) -> usize {
+        self.total() - self.pixel() - self.per_id[10] - self.tdc() - self.global_time() - self.extra_timestamp() - self.control()
+    }
+}
+
+impl std::fmt::Display for PacketStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Chunks: {}. Pixels: {}. Counting pixels: {}. Tdcs: {}. Global times: {}. Extra timestamps: {}. Controls: {}. Unknown: {}. End of readouts per chip: {:?}.",
+            self.chunks, self.pixel(), self.per_id[10], self.tdc(), self.global_time(), self.extra_timestamp(), self.control(), self.unknown(), self.end_of_readout)
+    }
+}
+
+///`PacketStream` decodes raw TPX3 buffers into `Event`s without copying them. The last chip index
+///seen and the bytes of an incomplete trailing packet are kept between calls, so consecutive
+///buffers of the same stream can be fed one after the other, whatever their length.
+#[derive(Default, Debug)]
+pub struct PacketStream {
+    chip_index: usize,
+    statistics: PacketStatistics,
+    ///Bytes of a packet split between two buffers.
+    tail: [u8; 8],
+    tail_len: usize,
+    ///The split packet, once completed by the next buffer.
+    head: [u8; 8],
+}
+
+impl PacketStream {
+    pub fn new() -> Self {
+        PacketStream::default()
+    }
+
+    ///Chip index of the last `TPX3` header seen.
+    pub fn chip_index(&self) -> usize {
+        self.chip_index
+    }
+
+    ///Packets counted so far, per family.
+    pub fn statistics(&self) -> &PacketStatistics {
+        &self.statistics
+    }
+
+    ///Bytes kept from the previous buffers, waiting for the rest of their packet.
+    pub fn pending(&self) -> usize {
+        self.tail_len
+    }
+
+    ///Iterates over the events of `data`, preceded by the packet split with the previous buffer,
+    ///if any. Trailing bytes that do not form a full packet are kept for the next call.
+    pub fn events<'a>(&'a mut self, data: &'a [u8]) -> Events<'a> {
+        let mut data = data;
+        let mut complete = false;
+        if self.tail_len > 0 {
+            let len = (8 - self.tail_len).min(data.len());
+            self.tail[self.tail_len..self.tail_len + len].copy_from_slice(&data[..len]);
+            self.tail_len += len;
+            data = &data[len..];
+            if self.tail_len == 8 {
+                self.head = self.tail;
+                self.tail_len = 0;
+                complete = true;
+            }
+        }
+        let chunks = data.chunks_exact(8);
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            self.tail[..remainder.len()].copy_from_slice(remainder);
+            self.tail_len = remainder.len();
+        }
+        let PacketStream { chip_index, statistics, head, .. } = self;
+        Events {
+            head: if complete { Some(head) } else { None },
+            chunks,
+            chip_index,
+            statistics,
+        }
+    }
+}
+
+pub struct Events<'a> {
+    head: Option<&'a [u8; 8]>,
+    chunks: std::slice::ChunksExact<'a, u8>,
+    chip_index: &'a mut usize,
+    statistics: &'a mut PacketStatistics,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chip_index = &mut *self.chip_index;
+        let statistics = &mut *self.statistics;
+        let mut decode = |chunk: &'a [u8; 8]| match *chunk {
+            [84, 80, 88, 51, nci, _, _, _] => {
+                *chip_index = nci as usize;
+                statistics.chunks += 1;
+                None
+            },
+            _ => {
+                let packet = PacketEELS { chip_index: *chip_index, data: chunk };
+                statistics.add_packet(&packet);
+                Some(Event::from_packet(packet))
+            },
+        };
+        if let Some(event) = self.head.take().and_then(&mut decode) {
+            return Some(event);
+        }
+        self.chunks.by_ref().find_map(|chunk| decode(chunk.try_into().unwrap()))
+    }
+}
+
+///A clock of the TPX3 stream. `PERIOD` is its tick period in twelfths of picosecond, the
+///smallest unit in which every TPX3 clock is an integer.
+pub trait Resolution: Copy + Default + Eq + Ord + std::hash::Hash {
+    const PERIOD: u64;
+    const NAME: &'static str;
+}
+
+macro_rules! genresolution {
+    ($($x: ident, $period: expr, $name: expr, $doc: expr);*) => {
+        $(
+        #[doc = $doc]
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $x;
+        impl Resolution for $x {
+            const PERIOD: u64 = $period;
+            const NAME: &'static str = $name;
+        }
+        )*
+    }
+}
+
+genresolution!(
+    Clock640MHz, 18_750, "640 MHz", "Electron ToA with FToA (1.5625 ns). Also used for TDCs by `tdc_time`.";
+    Clock320MHz, 37_500, "320 MHz", "TDC coarse time (3.125 ns).";
+    Clock3840MHz, 3_125, "3840 MHz", "TDC coarse plus fine time (~260 ps), as given by `tdc_time_abs`.";
+    Clock40MHz, 300_000, "40 MHz", "SPIDR global time and electron ToA without FToA (25 ns)."
+);
+
+///An exact amount of picoseconds. Stored in twelfths of picosecond so that every `Resolution`
+///converts without rounding.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Picoseconds {
+    twelfths: u128,
+}
+
+impl Picoseconds {
+    pub const fn from_twelfths(twelfths: u128) -> Self {
+        Picoseconds { twelfths }
+    }
+
+    pub const fn from_whole(ps: u128) -> Self {
+        Picoseconds { twelfths: ps * 12 }
+    }
+
+    pub fn twelfths(&self) -> u128 {
+        self.twelfths
+    }
+
+    ///Whole picoseconds, rounded down.
+    pub fn whole(&self) -> u128 {
+        self.twelfths / 12
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.twelfths as f64 / 12.0
+    }
+}
+
+impl Add for Picoseconds {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Picoseconds { twelfths: self.twelfths + other.twelfths }
+    }
+}
+
+impl Sub for Picoseconds {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Picoseconds { twelfths: self.twelfths - other.twelfths }
+    }
+}
+
+///`Timestamp` is a time, or a time interval, counted in ticks of the clock `R`. Arithmetic is
+///only defined between timestamps of the same resolution; `convert` must be used explicitly
+///otherwise. The default resolution is the electron one, `Clock640MHz`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp<R: Resolution = Clock640MHz> {
+    ticks: u64,
+    _resolution: PhantomData<R>,
+}
+
+impl<R: Resolution> Timestamp<R> {
+    pub const fn new(ticks: u64) -> Self {
+        Timestamp { ticks, _resolution: PhantomData }
+    }
+
+    pub const fn zero() -> Self {
+        Timestamp::new(0)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn to_picoseconds(&self) -> Picoseconds {
+        Picoseconds::from_twelfths(self.ticks as u128 * R::PERIOD as u128)
+    }
+
+    ///Closest tick, rounded down, to an amount of picoseconds.
+    pub fn from_picoseconds(ps: Picoseconds) -> Self {
+        Timestamp::new((ps.twelfths() / R::PERIOD as u128) as u64)
+    }
+
+    pub fn from_nanoseconds(ns: u64) -> Self {
+        Timestamp::from_picoseconds(Picoseconds::from_whole(ns as u128 * 1_000))
+    }
+
+    pub fn as_nanoseconds(&self) -> f64 {
+        self.to_picoseconds().as_f64() / 1_000.0
+    }
+
+    ///Converts to another resolution, rounding down if `S` is coarser than `R`.
+    pub fn convert<S: Resolution>(self) -> Timestamp<S> {
+        Timestamp::from_picoseconds(self.to_picoseconds())
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.ticks.checked_sub(other.ticks).map(Timestamp::new)
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Timestamp::new(self.ticks.saturating_sub(other.ticks))
+    }
+
+    pub fn wrapping_sub(self, other: Self) -> Self {
+        Timestamp::new(self.ticks.wrapping_sub(other.ticks))
+    }
+
+    pub fn abs_diff(self, other: Self) -> Self {
+        Timestamp::new(self.ticks.abs_diff(other.ticks))
+    }
+
+    ///Signed difference `self - other`, in ticks.
+    pub fn signed_diff(self, other: Self) -> i64 {
+        self.ticks as i64 - other.ticks as i64
+    }
+}
+
+impl<R: Resolution> std::fmt::Debug for Timestamp<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} @ {}", self.ticks, R::NAME)
+    }
+}
+
+impl<R: Resolution> std::fmt::Display for Timestamp<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ns", self.as_nanoseconds())
+    }
+}
+
+impl<R: Resolution> Add for Timestamp<R> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Timestamp::new(self.ticks + other.ticks)
+    }
+}
+
+impl<R: Resolution> AddAssign for Timestamp<R> {
+    fn add_assign(&mut self, other: Self) {
+        self.ticks += other.ticks;
+    }
+}
+
+impl<R: Resolution> Sub for Timestamp<R> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Timestamp::new(self.ticks - other.ticks)
+    }
+}
+
+impl<R: Resolution> SubAssign for Timestamp<R> {
+    fn sub_assign(&mut self, other: Self) {
+        self.ticks -= other.ticks;
+    }
+}
+
+impl<R: Resolution> Rem for Timestamp<R> {
+    type Output = Self;
+    fn rem(self, other: Self) -> Self {
+        Timestamp::new(self.ticks % other.ticks)
+    }
+}
+
+///How many times `other` fits in `self`.
+impl<R: Resolution> Div for Timestamp<R> {
+    type Output = u64;
+    fn div(self, other: Self) -> u64 {
+        self.ticks / other.ticks
+    }
+}
+
+impl<R: Resolution> Mul<u64> for Timestamp<R> {
+    type Output = Self;
+    fn mul(self, factor: u64) -> Self {
+        Timestamp::new(self.ticks * factor)
+    }
+}
+
+impl<R: Resolution> Div<u64> for Timestamp<R> {
+    type Output = Self;
+    fn div(self, divisor: u64) -> Self {
+        Timestamp::new(self.ticks / divisor)
+    }
+}
+
+impl<R: Resolution> std::iter::Sum for Timestamp<R> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Timestamp::zero(), |a, b| a + b)
+    }
+}
+
+///Electron timestamps (and `tdc_time_norm`) roll over every 2^34 ticks of 640 MHz, or ~26.8 s.
+pub const ELECTRON_ROLLOVER: u64 = 17_179_869_184;
+
+///`TimeExtension` unwraps the rolling electron and TDC timestamps into monotonic 64-bit times, in
+///units of 640 MHz (1.5625 ns). TDCs are brought to the electron time frame, as `tdc_time_norm`
+///does, so both can be compared directly. The current epoch is followed using every timestamp
+///extended and the SPIDR global time packets, so long periods without any hit are accounted for.
+#[derive(Default, Copy, Clone, Debug)]
+pub struct TimeExtension {
+    current: Option<u64>,
+    global_low: Option<u32>,
+    last_global: Option<(u64, u64)>,
+}
+
+impl TimeExtension {
+    pub fn new() -> Self {
+        TimeExtension {
+            current: None,
+            global_low: None,
+            last_global: None,
+        }
+    }
+
+    ///Latest extended time seen.
+    pub fn current(&self) -> Timestamp {
+        Timestamp::new(self.current.unwrap_or(0))
+    }
+
+    ///Unwraps `time`, given modulo `ELECTRON_ROLLOVER`, to the epoch closest to the current time.
+    pub fn extend(&mut self, time: u64) -> Timestamp {
+        let time = time % ELECTRON_ROLLOVER;
+        let extended = match self.current {
+            None => time,
+            Some(current) => {
+                let candidate = current - current % ELECTRON_ROLLOVER + time;
+                if candidate + ELECTRON_ROLLOVER / 2 < current {
+                    candidate + ELECTRON_ROLLOVER
+                } else if candidate > current + ELECTRON_ROLLOVER / 2 && candidate >= ELECTRON_ROLLOVER {
+                    candidate - ELECTRON_ROLLOVER
+                } else {
+                    candidate
+                }
+            },
+        };
+        self.advance(extended);
+        Timestamp::new(extended)
+    }
+
+    fn advance(&mut self, time: u64) {
+        self.current = Some(self.current.map_or(time, |current| current.max(time)));
+    }
+
+    ///Extended electron time.
+    pub fn electron_time<T: Packet>(&mut self, packet: &T) -> Timestamp {
+        self.extend(packet.electron_time() as u64)
+    }
+    
+    ///Extended TDC time, in the electron time frame.
+    pub fn tdc_time<T: Packet>(&mut self, packet: &T) -> Timestamp {
+        self.extend(packet.tdc_time_norm() as u64)
+    }
+
+    ///Feeds a global time packet. The global time elapsed between two complete (low and high)
+    ///readings moves the current time forward, even if no hit arrived meanwhile.
+    pub fn upt_global<T: Packet>(&mut self, packet: &T) {
+        match packet.kind() {
+            PacketKind::GlobalTimeLow { time } => self.global_low = Some(time),
+            PacketKind::GlobalTimeHigh { time } => {
+                if let Some(low) = self.global_low.take() {
+                    let global = ((time as u64) << 32 | low as u64) * 16;
+                    if let Some((last_global, last_current)) = self.last_global {
+                        if global > last_global {
+                            self.advance(last_current + global - last_global);
+                        }
+                    }
+                    self.last_global = Some((global, self.current().ticks()));
+                }
+            },
+            _ => {},
+        }
     }
 }
 
@@ -289,19 +866,47 @@ impl InversePacket {
             ci
         };
 
-        let data0: u8 = (spidr & 255) as u8;
-        let data1: u8 = ((spidr & 65_280) >> 8) as u8;
-        let data2: u8 = (!ftoa_ticks | (tot_ticks & 15) << 4) as u8;
-        let data3: u8 = ((tot_ticks & 1_008) >> 4 | (toa_ticks & 3) << 6) as u8;
-        let data4: u8 = ((toa_ticks & 1_020) >> 2) as u8;
-        let data5: u8 = ((x_raw & 1) << 6 | (self.y & 4) << 5 | (self.y & 3) << 4 | (toa_ticks & 15_360) >> 10) as u8;
-        let data6: u8 = ((self.y & 248) >> 3 | (x_raw & 14) << 4) as u8;
-        let data7: u8 = ((self.id & 15) << 4 | (x_raw & 240) >> 4) as u8;
+        let [data0, data1, data2, data3, data4, data5, data6, data7] = InversePacket::pack_electron(x_raw, self.y, tot_ticks, spidr, toa_ticks, ftoa_ticks);
         [84, 80, 88, 51, ci, 0, 8, 0, data0, data1, data2, data3, data4, data5, data6, data7]
     }
     
     pub fn create_tdc_array(&self, counter: usize, kind: TdcType) -> [u8; 16] {
         let (ct, ft) = self.tdc_time_to_ticks();
+        let [data0, data1, data2, data3, data4, data5, data6, data7] = InversePacket::pack_tdc(ct, ft, counter, &kind);
+        [84, 80, 88, 51, 0, 0, 8, 0, data0, data1, data2, data3, data4, data5, data6, data7]
+    }
+
+    ///Electron packet of the chip address (`x_raw`, `y_raw`). `time` is encoded exactly, modulo
+    ///`ELECTRON_ROLLOVER`.
+    pub fn electron_packet(x_raw: usize, y_raw: usize, tot: u16, time: Timestamp) -> [u8; 8] {
+        let ticks = (time.ticks() % ELECTRON_ROLLOVER) as usize;
+        let spidr = ticks / 262_144;
+        let toa_ticks = (ticks % 262_144) / 16;
+        let ftoa_ticks = ticks % 16;
+        InversePacket::pack_electron(x_raw, y_raw, tot as usize, spidr, toa_ticks, ftoa_ticks)
+    }
+
+    ///TDC packet of `kind`. `time` is encoded exactly, modulo the 35 bits of the coarse counter.
+    pub fn tdc_packet(kind: &TdcType, counter: u16, time: Timestamp<Clock3840MHz>) -> [u8; 8] {
+        let ticks = time.ticks() as usize;
+        let coarse_ticks = (ticks / 12) & 34_359_738_367;
+        let fine_ticks = ticks % 12;
+        InversePacket::pack_tdc(coarse_ticks, fine_ticks, counter as usize, kind)
+    }
+
+    fn pack_electron(x_raw: usize, y: usize, tot_ticks: usize, spidr: usize, toa_ticks: usize, ftoa_ticks: usize) -> [u8; 8] {
+        let data0: u8 = (spidr & 255) as u8;
+        let data1: u8 = ((spidr & 65_280) >> 8) as u8;
+        let data2: u8 = ((!ftoa_ticks & 15) | (tot_ticks & 15) << 4) as u8;
+        let data3: u8 = ((tot_ticks & 1_008) >> 4 | (toa_ticks & 3) << 6) as u8;
+        let data4: u8 = ((toa_ticks & 1_020) >> 2) as u8;
+        let data5: u8 = ((x_raw & 1) << 6 | (y & 4) << 5 | (y & 3) << 4 | (toa_ticks & 15_360) >> 10) as u8;
+        let data6: u8 = ((y & 248) >> 3 | (x_raw & 14) << 4) as u8;
+        let data7: u8 = (11 << 4 | (x_raw & 240) >> 4) as u8;
+        [data0, data1, data2, data3, data4, data5, data6, data7]
+    }
+
+    fn pack_tdc(ct: usize, ft: usize, counter: usize, kind: &TdcType) -> [u8; 8] {
         let res = 0;
         let tdc_type: u8 = kind.associate_value();
 
@@ -312,8 +917,8 @@ impl InversePacket {
         let data4: u8 = ((ct & 2_139_095_040) >> 23) as u8;
         let data5: u8 = ((counter & 15) << 4 | (ct & 32_212_254_720) >> 31) as u8;
         let data6: u8 = ((counter & 4080) >> 4) as u8;
-        let data7: u8 = ((self.id & 15) << 4) as u8 | (tdc_type & 15);
-        [84, 80, 88, 51, 0, 0, 8, 0, data0, data1, data2, data3, data4, data5, data6, data7]
+        let data7: u8 = (6 << 4) as u8 | (tdc_type & 15);
+        [data0, data1, data2, data3, data4, data5, data6, data7]
     }
 
 
@@ -326,7 +931,7 @@ impl InversePacket {
             data: &my_data[8..16].try_into().unwrap()
         };
 
-        println!("{} and {} and {} and {} and {} and {}", my_packet.x(), my_packet.y(), my_packet.x_raw(), my_packet.electron_time(), !my_packet.ftoa() & 15, my_packet.tot());
+        println!("{:?} and {:?} and {} and {} and {} and {}", my_packet.x(), my_packet.y(), my_packet.x_raw(), my_packet.electron_time(), !my_packet.ftoa() & 15, my_packet.tot());
     }
 
     pub fn tdc_test_func(&self) {
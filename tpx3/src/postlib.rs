@@ -1,44 +1,364 @@
+pub mod dataset {
+    use crate::auxiliar::misc::TimepixRead;
+    use crate::errorlib::Tp3ErrorKind;
+    use std::cmp::Ordering;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{self, Read};
+    use std::path::{Path, PathBuf};
+
+    const EXTENSION: &str = "tpx3";
+
+    ///Compares file names as a person would, so `raw10.tpx3` comes after `raw9.tpx3`.
+    pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+        let (mut a, mut b) = (a, b);
+        loop {
+            let (first_a, first_b) = match (a.chars().next(), b.chars().next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(first_a), Some(first_b)) => (first_a, first_b),
+            };
+            if first_a.is_ascii_digit() && first_b.is_ascii_digit() {
+                let len_a = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+                let len_b = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+                let (digits_a, digits_b) = (a[..len_a].trim_start_matches('0'), b[..len_b].trim_start_matches('0'));
+                let order = digits_a.len().cmp(&digits_b.len()).then_with(|| digits_a.cmp(digits_b));
+                if order != Ordering::Equal {return order;}
+                a = &a[len_a..];
+                b = &b[len_b..];
+            } else {
+                if first_a != first_b {return first_a.cmp(&first_b);}
+                a = &a[first_a.len_utf8()..];
+                b = &b[first_b.len_utf8()..];
+            }
+        }
+    }
+
+    ///Number at the end of the file stem, as the 12 in `raw000012.tpx3`.
+    fn file_number(path: &Path) -> Option<u64> {
+        let stem = path.file_stem()?.to_str()?;
+        let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        stem[stem.len()-digits..].parse().ok()
+    }
+
+    ///`Dataset` is an ordered sequence of TPX3 files, such as `raw000000.tpx3`, `raw000001.tpx3`
+    ///and so on, read as a single stream. The TDC references are thus created once and keep their
+    ///counters and frames from one file to the next.
+    #[derive(Debug, Clone)]
+    pub struct Dataset {
+        files: Vec<PathBuf>,
+        ///Numbers missing in the sequence of files, as (first, last) missing.
+        gaps: Vec<(u64, u64)>,
+    }
+
+    impl Dataset {
+        ///A single file, or all the TPX3 files of a folder in natural order.
+        pub fn new(path: &str) -> Result<Self, Tp3ErrorKind> {
+            let metadata = fs::metadata(path).map_err(|e| Tp3ErrorKind::SetNoReadFile(path.to_string(), e))?;
+            if !metadata.is_dir() {
+                return Dataset::from_files(vec![PathBuf::from(path)], path);
+            }
+            let files = fs::read_dir(path).map_err(|e| Tp3ErrorKind::SetNoReadFile(path.to_string(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| file.is_file() && file.extension() == Some(OsStr::new(EXTENSION)))
+                .collect();
+            Dataset::from_files(files, path)
+        }
+
+        ///The files are naturally sorted by name. `name` identifies the dataset in errors.
+        pub fn from_files(mut files: Vec<PathBuf>, name: &str) -> Result<Self, Tp3ErrorKind> {
+            if files.is_empty() {return Err(Tp3ErrorKind::DatasetEmpty(name.to_string()));}
+            files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+            let numbers: Vec<Option<u64>> = files.iter().map(|file| file_number(file)).collect();
+            let gaps: Vec<(u64, u64)> = numbers.windows(2)
+                .filter_map(|pair| match (pair[0], pair[1]) {
+                    (Some(first), Some(second)) if second > first + 1 => Some((first + 1, second - 1)),
+                    _ => None,
+                })
+                .collect();
+            for (first, last) in &gaps {
+                match first == last {
+                    true => println!("***Dataset***: File {} is missing in {}. Its events are lost.", first, name),
+                    false => println!("***Dataset***: Files {} to {} are missing in {}. Their events are lost.", first, last, name),
+                }
+            }
+            println!("***Dataset***: {} files in {}.", files.len(), name);
+            Ok(Dataset {files, gaps})
+        }
+
+        pub fn files(&self) -> &[PathBuf] {
+            &self.files
+        }
+
+        pub fn gaps(&self) -> &[(u64, u64)] {
+            &self.gaps
+        }
+
+        ///A reader from the beginning of the first file.
+        pub fn open(&self) -> Result<DatasetReader, Tp3ErrorKind> {
+            Ok(DatasetReader {
+                files: self.files.clone(),
+                index: 0,
+                file: open_file(&self.files[0])?,
+            })
+        }
+    }
+
+    ///Fills `buf` up to a whole number of packets, as a short read can stop in the middle of one.
+    ///Fewer bytes are only returned at the end of `reader`, and zero once it is over.
+    pub fn read_packets<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        let mut size = 0;
+        while size == 0 || size % 8 != 0 {
+            match reader.read(&mut buf[size..])? {
+                0 => break,
+                n => size += n,
+            }
+        }
+        Ok(size)
+    }
+
+    fn open_file(path: &Path) -> Result<fs::File, Tp3ErrorKind> {
+        fs::File::open(path).map_err(|e| Tp3ErrorKind::SetNoReadFile(path.display().to_string(), e))
+    }
+
+    ///Reads the files of a `Dataset` one after the other.
+    pub struct DatasetReader {
+        files: Vec<PathBuf>,
+        index: usize,
+        file: fs::File,
+    }
+
+    impl DatasetReader {
+        ///The file being read.
+        pub fn current(&self) -> &Path {
+            &self.files[self.index]
+        }
+    }
+
+    impl Read for DatasetReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let size = self.file.read(buf)?;
+                if size > 0 || buf.is_empty() || self.index + 1 == self.files.len() {
+                    return Ok(size);
+                }
+                self.index += 1;
+                self.file = fs::File::open(&self.files[self.index])?;
+            }
+        }
+    }
+
+    impl TimepixRead for DatasetReader {}
+}
+
 pub mod coincidence {
 
-    use crate::spimlib::SPIM_PIXELS;
-    use crate::packetlib::{Packet, TimeCorrectedPacketEELS as Pack};
+    use crate::packetlib::{Packet, TimeCorrectedPacketEELS as Pack, PacketStream, Event, TimeExtension, Timestamp};
+    use crate::detectorlib::DetectorLayout;
+    use crate::calibrationlib::{TimeCalibration, EnergyCalibration, TIME_CALIBRATION_FILE, ENERGY_CALIBRATION_FILES};
     use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef, NonPeriodicTdcRef};
-    use std::io;
+    use crate::configlib::{TdcConfig, CoincidenceConfig, OutputFormat};
+    use crate::npylib::{self, NpyType, NpyWriter};
+    use serde_json::{json, Map, Value};
+    use std::path::{Path, PathBuf};
+    use crate::errorlib::Tp3ErrorKind;
+    use std::sync::Arc;
     use std::io::prelude::*;
     use std::fs;
     use std::time::Instant;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron};
     use crate::auxiliar::ConfigAcquisition;
-    use std::convert::TryInto;
+    use super::dataset::{Dataset, read_packets};
     use std::cmp;
+    use rayon::prelude::*;
+
+    const CALIBRATION_RANGE: usize = 6_400; //Maximum delay, of either sign, searched by `calibrate_delay` (in units of 640 MHz, or 10 us).
+    const G2_RANGE: i64 = 640; //Half range of the g2 histogram (in units of 640 MHz, or 1 us).
+    const TICK: Timestamp = Timestamp::new(1); //Width of the g2 bins.
+    pub(super) const BUFFER_SIZE: usize = 256_000_000; //Bytes read at once by `search_coincidence`.
+    const CHUNKS_PER_THREAD: usize = 4; //Electron chunks correlated by each thread, for a better balance.
+    pub(super) const CHUNK_OVERLAP: Timestamp = Timestamp::new(64_000); //Margin for the events written out of time order (in units of 640 MHz, or 100 us).
+    const NO_SPIM_INDEX: usize = usize::MAX; //Spim index of the electrons outside of a spim, so the per-event arrays stay aligned.
+
+    const TH_DESCRIPTION: &str = "Time of each coincident electron minus the one of its delayed photon, in units of time_unit_ns.";
+    const XH_DESCRIPTION: &str = "Dispersive pixel of each coincident electron.";
+    const YH_DESCRIPTION: &str = "Non-dispersive pixel of each coincident electron.";
+    const EH_DESCRIPTION: &str = "Energy of each coincident electron.";
+    const SI_DESCRIPTION: &str = "Spim index of each coincident electron, as scan position times spim_pixels plus pixel. It is 18446744073709551615 for the electrons without one.";
+    const TOT_DESCRIPTION: &str = "Time over threshold of each coincident electron, summed over its cluster.";
+    const CS_DESCRIPTION: &str = "Number of hits in the cluster of each coincident electron.";
+    const CH_DESCRIPTION: &str = "Photon channel of each coincident electron.";
+    const XTH_DESCRIPTION: &str = "Photon time of the first channel minus the one of the second channel, in units of time_unit_ns.";
+
+    fn write_text(path: &Path, out: String) -> Result<(), Tp3ErrorKind> {
+        fs::write(path, out).map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))
+    }
+
+    ///Appends `values` to the comma-separated values of `path`.
+    fn append_text<T: ToString>(path: &Path, values: &[T], after_values: bool) -> Result<(), Tp3ErrorKind> {
+        let mut file = fs::OpenOptions::new().append(true).open(path)
+            .map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))?;
+        let mut out: String = values.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+        if after_values && !values.is_empty() {
+            out.insert_str(0, ", ");
+        }
+        file.write_all(out.as_bytes()).map_err(|e| Tp3ErrorKind::SetNoWriteFile(path.display().to_string(), e))
+    }
+
+    ///An array written by `ResultWriter`, as described in the metadata.
+    struct ArrayInfo {
+        name: String,
+        dtype: &'static str,
+        shape: Vec<usize>,
+        description: String,
+        ///Writer of the per-event NumPy arrays.
+        events: Option<NpyWriter>,
+    }
+
+    ///`ResultWriter` writes the results in a folder, as text or NumPy arrays, and describes them
+    ///in `metadata.json`. Per-event arrays are appended as the search goes.
+    pub struct ResultWriter {
+        dir: PathBuf,
+        format: OutputFormat,
+        arrays: Vec<ArrayInfo>,
+    }
+
+    impl ResultWriter {
+        pub fn new(dir: &str, format: OutputFormat) -> Result<Self, Tp3ErrorKind> {
+            fs::create_dir_all(dir).map_err(|e| Tp3ErrorKind::SetNoWriteFile(dir.to_string(), e))?;
+            Ok(ResultWriter {dir: PathBuf::from(dir), format, arrays: Vec::new()})
+        }
+
+        fn file_name(&self, name: &str) -> String {
+            match self.format {
+                OutputFormat::Text => format!("{}.txt", name),
+                OutputFormat::Npy => format!("{}.npy", name),
+            }
+        }
+
+        fn describe(&mut self, name: &str, dtype: &'static str, shape: Vec<usize>, description: &str) {
+            self.arrays.retain(|array| array.name != name);
+            self.arrays.push(ArrayInfo {name: name.to_string(), dtype, shape, description: description.to_string(), events: None});
+        }
+
+        ///Writes `data` of the given `shape`. Text files hold a single line.
+        pub fn array<T: NpyType + ToString>(&mut self, name: &str, data: &[T], shape: &[usize], description: &str) -> Result<(), Tp3ErrorKind> {
+            let path = self.dir.join(self.file_name(name));
+            match self.format {
+                OutputFormat::Text => write_text(&path, data.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", "))?,
+                OutputFormat::Npy => npylib::write_npy(&path, data, shape)?,
+            }
+            self.describe(name, T::DESCR, shape.to_vec(), description);
+            Ok(())
+        }
+
+        ///Writes `rows`, all of the same length. Text files hold one line per row.
+        pub fn table(&mut self, name: &str, rows: &[Vec<f64>], description: &str) -> Result<(), Tp3ErrorKind> {
+            let path = self.dir.join(self.file_name(name));
+            let shape = vec![rows.len(), rows.first().map_or(0, Vec::len)];
+            match self.format {
+                OutputFormat::Text => write_text(&path, rows.iter()
+                    .map(|row| row.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", "))
+                    .collect::<Vec<String>>().join("\n"))?,
+                OutputFormat::Npy => npylib::write_npy(&path, &rows.concat(), &shape)?,
+            }
+            self.describe(name, f64::DESCR, shape, description);
+            Ok(())
+        }
+
+        ///Appends `data` to the per-event array `name`. The first call creates it.
+        pub fn events<T: NpyType + ToString>(&mut self, name: &str, data: &[T], description: &str) -> Result<(), Tp3ErrorKind> {
+            let path = self.dir.join(self.file_name(name));
+            let index = match self.arrays.iter().position(|array| array.name == name) {
+                Some(index) => index,
+                None => {
+                    let events = match self.format {
+                        OutputFormat::Text => {
+                            write_text(&path, String::new())?;
+                            None
+                        },
+                        OutputFormat::Npy => Some(NpyWriter::create::<T>(&path)?),
+                    };
+                    self.arrays.push(ArrayInfo {name: name.to_string(), dtype: T::DESCR, shape: vec![0], description: description.to_string(), events});
+                    self.arrays.len() - 1
+                },
+            };
+            let array = &mut self.arrays[index];
+            match &mut array.events {
+                Some(writer) => writer.append(data)?,
+                None => append_text(&path, data, array.shape[0] > 0)?,
+            }
+            array.shape[0] += data.len();
+            Ok(())
+        }
+
+        ///Writes `metadata.json`: `info` and the description of the arrays written so far.
+        pub fn metadata(&self, mut info: Value) -> Result<(), Tp3ErrorKind> {
+            let arrays: Map<String, Value> = self.arrays.iter()
+                .map(|array| (array.name.clone(), json!({
+                    "file": self.file_name(&array.name),
+                    "dtype": array.dtype,
+                    "shape": array.shape,
+                    "description": array.description,
+                })))
+                .collect();
+            info["format"] = json!(format!("{:?}", self.format).to_lowercase());
+            info["arrays"] = Value::Object(arrays);
+            write_text(&self.dir.join("metadata.json"), format!("{:#}", info))
+        }
+    }
 
-    const TIME_WIDTH: usize = 16; //Time width to correlate (in units of 640 Mhz, or 1.5625 ns).
-    //const TIME_DELAY: usize = 100_000 - 1867; //Time delay to correlate (ps).
-    const TIME_DELAY: usize = 103; // + 50_000; //Time delay to correlate (in units of 640 Mhz, or 1.5625 ns).
-    const MIN_LEN: usize = 100; // Sliding time window size.
+    ///Electrons in coincidence with photons of two channels. For each of them, the time of the
+    ///photon of the first channel minus the one of the second channel is kept.
+    pub struct CrossChannel {
+        pub channels: (usize, usize),
+        pub spectrum: Vec<usize>,
+        pub rel_time: Vec<isize>,
+    }
 
     pub struct ElectronData {
-        pub time: Vec<usize>,
+        pub time: Vec<Timestamp>,
         pub rel_time: Vec<isize>,
         pub x: Vec<usize>,
         pub y: Vec<usize>,
         pub tot: Vec<u16>,
+        pub energy: Vec<f32>,
         pub cluster_size: Vec<usize>,
         pub spectrum: Vec<usize>,
         pub corr_spectrum: Vec<usize>,
+        pub acc_spectrum: Vec<usize>,
+        ///Coincident and accidental electrons of each scan position.
+        pub spim_counts: Vec<usize>,
+        pub acc_spim_counts: Vec<usize>,
+        ///Coincident electrons, including the ones already written by `flush_events`.
+        pub ncoincidences: usize,
+        pub writer: ResultWriter,
+        pub g2: G2Data,
+        ///Photon TDCs. The channel of a photon is its index.
+        pub channels: Vec<TdcType>,
+        ///Channel of each coincident electron. The first channel is kept when there are several.
+        pub channel: Vec<usize>,
+        pub channel_spectrum: Vec<Vec<usize>>,
+        pub cross: Vec<CrossChannel>,
         pub is_spim: bool,
         pub spim_size: (usize, usize),
         //pub begin_frame: Option<usize>,
         pub spim_index: Vec<usize>,
         pub spim_tdc: Option<PeriodicTdcRef>,
+        pub layout: DetectorLayout,
+        pub tdc: TdcConfig,
+        pub coincidence: CoincidenceConfig,
+        pub time_calibration: Arc<TimeCalibration>,
+        pub energy_calibration: Arc<EnergyCalibration>,
     }
 
     impl ElectronData {
         fn add_electron(&mut self, val: SingleElectron) {
-            self.spectrum[val.image_index()] += 1;
+            self.spectrum[val.image_index(&self.layout)] += 1;
         }
 
-        fn add_spim_line(&mut self, pack: &Pack) {
+        fn add_spim_line(&mut self, pack: &Pack, time: Timestamp) {
             //match &mut self.spim_tdc {
             //    Some(spim_tdc) => {
             //        spim_tdc.ticks_to_frame = None;
@@ -47,39 +367,134 @@ pub mod coincidence {
             //};
             
             if let Some(spim_tdc) = &mut self.spim_tdc {
-                spim_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+                spim_tdc.upt(time, pack.tdc_counter());
             }
         }
 
-        fn add_coincident_electron(&mut self, val: SingleElectron, photon_time: usize) {
-            self.corr_spectrum[val.image_index()] += 1; //Adding the electron
-            self.corr_spectrum[SPIM_PIXELS-1] += 1; //Adding the photon
+        fn add_coincident_electron(&mut self, val: SingleElectron, photon_time: Timestamp, channel: usize) {
+            self.corr_spectrum[val.image_index(&self.layout)] += 1; //Adding the electron
+            self.corr_spectrum[self.layout.spim_pixels()-1] += 1; //Adding the photon
+            self.channel.push(channel);
             self.time.push(val.time());
             self.rel_time.push(val.relative_time(photon_time));
             self.x.push(val.x());
             self.y.push(val.y());
-            if let Some(index) = val.get_or_not_spim_index(self.spim_tdc, self.spim_size.0, self.spim_size.1) {
-                self.spim_index.push(index);
+            self.energy.push(val.energy());
+            self.tot.push(val.tot());
+            self.cluster_size.push(val.cluster_size());
+            match val.get_or_not_spim_index(self.spim_tdc, self.spim_size.0, self.spim_size.1, &self.layout) {
+                Some(index) => {
+                    self.spim_index.push(index);
+                    if let Some(counts) = self.spim_counts.get_mut(index / self.layout.spim_pixels()) {
+                        *counts += 1;
+                    }
+                },
+                None => self.spim_index.push(NO_SPIM_INDEX),
             }
+            self.ncoincidences += 1;
         }
         
-        fn add_events(&mut self, mut temp_edata: TempElectronData, mut temp_tdc: TempTdcData) {
-            temp_tdc.sort();
-            let nphotons = temp_tdc.tdc.len();
-            println!("Supplementary events: {}.", nphotons);
+        fn add_channel_electron(&mut self, val: SingleElectron, channel: usize) {
+            self.channel_spectrum[channel][val.image_index(&self.layout)] += 1;
+            self.channel_spectrum[channel][self.layout.spim_pixels()-1] += 1;
+        }
+
+        ///Photon-photon-electron coincidence. `first` and `second` are (channel, photon time).
+        fn add_cross_electron(&mut self, val: SingleElectron, first: (usize, Timestamp), second: (usize, Timestamp)) {
+            let (index, spim_pixels) = (val.image_index(&self.layout), self.layout.spim_pixels());
+            if let Some(cross) = self.cross.iter_mut().find(|cross| cross.channels == (first.0, second.0)) {
+                cross.spectrum[index] += 1;
+                cross.spectrum[spim_pixels-1] += 1;
+                cross.rel_time.push(first.1.signed_diff(second.1) as isize);
+            }
+        }
+
+        ///Electrons in the off-peak window, shifted by `accidental_shift`, estimate the
+        ///accidental coincidences of the on-peak window.
+        fn add_accidental_electron(&mut self, val: SingleElectron) {
+            self.acc_spectrum[val.image_index(&self.layout)] += 1;
+            if let Some(index) = val.get_or_not_spim_index(self.spim_tdc, self.spim_size.0, self.spim_size.1, &self.layout) {
+                if let Some(counts) = self.acc_spim_counts.get_mut(index / self.layout.spim_pixels()) {
+                    *counts += 1;
+                }
+            }
+        }
+        
+        fn add_photons(&mut self, nphotons: usize) {
+            self.spectrum[self.layout.spim_pixels()-1] += nphotons; //Adding photons to the last pixel
+            self.g2.nphotons += nphotons;
+        }
+
+        ///Time after an electron in which its photons, delayed or in the off-peak window, can
+        ///still be read.
+        fn lookahead(&self) -> Timestamp {
+            self.coincidence.delay_time() + self.coincidence.accidental_shift_time() + self.coincidence.width_time() + Timestamp::new(G2_RANGE as u64) + CHUNK_OVERLAP
+        }
+
+        ///Time before an electron in which its photons can be found.
+        fn lookbehind(&self) -> Timestamp {
+            self.coincidence.width_time() + Timestamp::new(G2_RANGE as u64)
+        }
+
+        ///`temp_tdc` holds the photons of each channel. All the photons that can correlate with
+        ///the electrons of `temp_edata` must be present.
+        fn add_events(&mut self, mut temp_edata: TempElectronData, temp_tdc: &mut [TempTdcData]) {
+            temp_tdc.iter_mut().for_each(|channel| channel.sort());
+            println!("Supplementary events: {:?} per channel.", temp_tdc.iter().map(|channel| channel.tdc.len()).collect::<Vec<usize>>());
             
+            if temp_edata.electron.is_empty() {return;}
             temp_edata.electron.clean();
 
-            self.spectrum[SPIM_PIXELS-1]=nphotons; //Adding photons to the last pixel
+            let mut photons: Vec<Timestamp> = temp_tdc.iter().flat_map(|channel| channel.tdc.iter().copied()).collect();
+            photons.sort_unstable();
+            self.g2.add_events(&temp_edata.electron, &photons);
 
-            for val in temp_edata.electron.values() {
+            let correlations = correlate(temp_edata.electron.as_slice(), temp_tdc, self.coincidence.accidental_shift_time());
+            for (val, correlation) in temp_edata.electron.values().zip(correlations.iter()) {
                 self.add_electron(*val);
-                if let Some(pht) = temp_tdc.check(*val) {
-                    self.add_coincident_electron(*val, pht);
+                let hits = &correlation.hits;
+                if let Some(&(channel, pht)) = hits.first() {
+                    self.add_coincident_electron(*val, pht, channel);
+                }
+                for (index, &first) in hits.iter().enumerate() {
+                    self.add_channel_electron(*val, first.0);
+                    for &second in &hits[index+1..] {
+                        self.add_cross_electron(*val, first, second);
+                    }
+                }
+                if correlation.accidental {
+                    self.add_accidental_electron(*val);
                 }
             };
 
-            println!("Number of coincident electrons: {:?}. Last photon real time is {:?}. Last relative time is {:?}.", self.x.len(), self.time.iter().last(), self.rel_time.iter().last());
+            println!("Number of coincident electrons: {:?}. Last photon real time is {:?}. Last relative time is {:?}.", self.ncoincidences, self.time.iter().last(), self.rel_time.iter().last());
+        }
+
+        ///Appends the per-event outputs to their files and frees them, so the memory does not grow
+        ///with the acquisition.
+        pub fn flush_events(&mut self) -> Result<(), Tp3ErrorKind> {
+            self.writer.events("tH", &self.rel_time, TH_DESCRIPTION)?;
+            self.writer.events("xH", &self.x, XH_DESCRIPTION)?;
+            self.writer.events("yH", &self.y, YH_DESCRIPTION)?;
+            self.writer.events("eH", &self.energy, EH_DESCRIPTION)?;
+            self.writer.events("si", &self.spim_index, SI_DESCRIPTION)?;
+            self.writer.events("ch", &self.channel, CH_DESCRIPTION)?;
+            self.writer.events("tot", &self.tot, TOT_DESCRIPTION)?;
+            self.writer.events("cs", &self.cluster_size, CS_DESCRIPTION)?;
+            for cross in &mut self.cross {
+                self.writer.events(&format!("xtH_{}_{}", cross.channels.0, cross.channels.1), &cross.rel_time, XTH_DESCRIPTION)?;
+                cross.rel_time.clear();
+            }
+            self.time.clear();
+            self.rel_time.clear();
+            self.x.clear();
+            self.y.clear();
+            self.energy.clear();
+            self.spim_index.clear();
+            self.channel.clear();
+            self.tot.clear();
+            self.cluster_size.clear();
+            Ok(())
         }
 
         fn prepare_spim(&mut self, spim_tdc: PeriodicTdcRef) {
@@ -87,142 +502,420 @@ pub mod coincidence {
             self.spim_tdc = Some(spim_tdc);
         }
 
-        pub fn new(my_config: &ConfigAcquisition) -> Self {
-            Self {
+        pub fn new(my_config: &ConfigAcquisition) -> Result<Self, Tp3ErrorKind> {
+            let spectrum_len = my_config.layout.spim_pixels()*my_config.layout.chip_array().1;
+            let channels = my_config.coincidence.photon_tdcs(&my_config.tdc);
+            let cross = (0..channels.len())
+                .flat_map(|first| (first+1..channels.len()).map(move |second| (first, second)))
+                .map(|pair| CrossChannel {channels: pair, spectrum: vec![0; spectrum_len], rel_time: Vec::new()})
+                .collect();
+            Ok(Self {
                 time: Vec::new(),
                 rel_time: Vec::new(),
                 x: Vec::new(),
                 y: Vec::new(),
                 tot: Vec::new(),
+                energy: Vec::new(),
                 cluster_size: Vec::new(),
-                spectrum: vec![0; SPIM_PIXELS*256],
-                corr_spectrum: vec![0; SPIM_PIXELS*256],
+                spectrum: vec![0; my_config.layout.spim_pixels()*my_config.layout.chip_array().1],
+                corr_spectrum: vec![0; my_config.layout.spim_pixels()*my_config.layout.chip_array().1],
+                acc_spectrum: vec![0; my_config.layout.spim_pixels()*my_config.layout.chip_array().1],
+                spim_counts: vec![0; my_config.xspim * my_config.yspim],
+                acc_spim_counts: vec![0; my_config.xspim * my_config.yspim],
+                ncoincidences: 0,
+                writer: ResultWriter::new(&my_config.coincidence.output_dir, my_config.coincidence.output_format)?,
+                g2: G2Data::new(),
+                channel: Vec::new(),
+                channel_spectrum: vec![vec![0; spectrum_len]; channels.len()],
+                cross,
+                channels,
                 is_spim: my_config.is_spim,
                 spim_size: (my_config.xspim, my_config.yspim),
                 spim_index: Vec::new(),
                 spim_tdc: None,
-            }
+                layout: my_config.layout,
+                tdc: my_config.tdc,
+                coincidence: my_config.coincidence.clone(),
+                time_calibration: Arc::new(TimeCalibration::from_file_or_default(TIME_CALIBRATION_FILE)?),
+                energy_calibration: Arc::new(EnergyCalibration::from_files_or_default(ENERGY_CALIBRATION_FILES)?),
+            })
         }
         
-        pub fn output_corr_spectrum(&self, bin: bool) {
-            let out: String = match bin {
+        ///The spectrum summed over the rows if `bin`, and its shape.
+        fn binned(&self, spectrum: &[usize], bin: bool) -> (Vec<usize>, Vec<usize>) {
+            let spim_pixels = self.layout.spim_pixels();
+            match bin {
                 true => {
-                    let mut spec: Vec<usize> = vec![0; SPIM_PIXELS];
-                    for val in self.corr_spectrum.chunks_exact(SPIM_PIXELS) {
-                        spec.iter_mut().zip(val.iter()).map(|(a, b)| *a += b).count();
+                    let mut spec: Vec<usize> = vec![0; spim_pixels];
+                    for val in spectrum.chunks_exact(spim_pixels) {
+                        spec.iter_mut().zip(val.iter()).for_each(|(a, b)| *a += b);
                     }
-                    spec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
-                },
-                false => {
-                    self.corr_spectrum.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
+                    (spec, vec![spim_pixels])
                 },
-            };
-            fs::write("cspec.txt", out).unwrap();
+                false => (spectrum.to_vec(), vec![spectrum.len() / spim_pixels, spim_pixels]),
+            }
+        }
+
+        pub fn output_corr_spectrum(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            let (data, shape) = self.binned(&self.corr_spectrum, bin);
+            self.writer.array("cspec", &data, &shape, "Coincident electrons per pixel. The last pixel holds the number of coincidences.")
         }
         
-        pub fn output_spectrum(&self, bin: bool) {
-            let out: String = match bin {
-                true => {
-                    let mut spec: Vec<usize> = vec![0; SPIM_PIXELS];
-                    for val in self.spectrum.chunks_exact(SPIM_PIXELS) {
-                        spec.iter_mut().zip(val.iter()).map(|(a, b)| *a += b).count();
-                    }
-                    spec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
-                },
-                false => {
-                    self.spectrum.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
-                },
-            };
-            fs::write("spec.txt", out).unwrap();
+        pub fn output_acc_spectrum(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            let (data, shape) = self.binned(&self.acc_spectrum, bin);
+            self.writer.array("aspec", &data, &shape, "Electrons per pixel with a photon in the off-peak window.")
         }
 
-        pub fn output_relative_time(&self) {
-            println!("Outputting relative time under tH name. Vector len is {}", self.rel_time.len());
-            let out: String = self.rel_time.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
-            fs::write("tH.txt", out).unwrap();
+        ///One coincident spectrum per channel, under cspec_<channel> names.
+        pub fn output_channel_spectra(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            for channel in 0..self.channels.len() {
+                let tdc = self.channels[channel];
+                println!("Outputting the coincident spectrum of channel {} ({:?}) under cspec_{} name.", channel, tdc, channel);
+                let (data, shape) = self.binned(&self.channel_spectrum[channel], bin);
+                self.writer.array(&format!("cspec_{}", channel), &data, &shape, &format!("Electrons per pixel in coincidence with {:?}.", tdc))?;
+            }
+            Ok(())
+        }
+
+        ///For each pair of channels, the spectrum of the electrons in coincidence with both, under
+        ///xspec_<first>_<second> names, and the time between their photons, under
+        ///xtH_<first>_<second> names.
+        pub fn output_cross_channels(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            for index in 0..self.cross.len() {
+                let (first, second) = self.cross[index].channels;
+                println!("Outputting the coincidences of channels {} and {} under xspec_{}_{} and xtH_{}_{} names. Number of electrons is {}", first, second, first, second, first, second, self.cross[index].spectrum[self.layout.spim_pixels()-1]);
+                let (data, shape) = self.binned(&self.cross[index].spectrum, bin);
+                self.writer.array(&format!("xspec_{}_{}", first, second), &data, &shape, "Electrons per pixel in coincidence with photons of both channels.")?;
+                self.writer.events(&format!("xtH_{}_{}", first, second), &self.cross[index].rel_time, XTH_DESCRIPTION)?;
+                self.cross[index].rel_time.clear();
+            }
+            Ok(())
+        }
+
+        pub fn output_channel(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each photon channel under ch name. Vector len is {}", self.ncoincidences);
+            self.writer.events("ch", &self.channel, CH_DESCRIPTION)?;
+            self.channel.clear();
+            Ok(())
+        }
+
+        ///The coincident spectrum minus the accidental one. The first row holds the values and
+        ///the second their standard deviation, assuming Poisson statistics. The photon channel is
+        ///not subtracted.
+        pub fn output_subtracted_spectrum(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            let (corr, _) = self.binned(&self.corr_spectrum, bin);
+            let (mut acc, _) = self.binned(&self.acc_spectrum, bin);
+            acc[self.layout.spim_pixels()-1] = 0;
+            self.writer.table("sspec", &subtracted_rows(&corr, &acc), "Coincident minus accidental electrons per pixel (first row) and its standard deviation (second row).")
+        }
+
+        ///The background-subtracted number of coincident electrons of each scan position, as in
+        ///`output_subtracted_spectrum`.
+        pub fn output_subtracted_spim(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting the subtracted spim under sspim name. Number of positions is {}", self.spim_counts.len());
+            let rows = subtracted_rows(&self.spim_counts, &self.acc_spim_counts);
+            self.writer.table("sspim", &rows, "Coincident minus accidental electrons per scan position (first row) and its standard deviation (second row).")
+        }
+
+        pub fn output_g2(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting the second-order correlation under g2 name. Number of pairs is {}", self.g2.histogram.iter().sum::<usize>());
+            let rows = self.g2.output();
+            self.writer.table("g2", &rows, "One row per delay: the photon minus electron delay (ns), g2 and its standard deviation.")
         }
         
-        pub fn output_dispersive(&self) {
-            println!("Outputting each dispersive value under xH name. Vector len is {}", self.rel_time.len());
-            let out: String = self.x.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
-            fs::write("xH.txt", out).unwrap();
+        pub fn output_spectrum(&mut self, bin: bool) -> Result<(), Tp3ErrorKind> {
+            let (data, shape) = self.binned(&self.spectrum, bin);
+            self.writer.array("spec", &data, &shape, "Electrons per pixel. The last pixel holds the number of photons.")
+        }
+
+        pub fn output_relative_time(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting relative time under tH name. Vector len is {}", self.ncoincidences);
+            self.writer.events("tH", &self.rel_time, TH_DESCRIPTION)?;
+            self.rel_time.clear();
+            Ok(())
         }
         
-        pub fn output_non_dispersive(&self) {
-            println!("Outputting each non-dispersive value under yH name. Vector len is {}", self.rel_time.len());
-            let out: String = self.y.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
-            fs::write("yH.txt", out).unwrap();
+        pub fn output_dispersive(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each dispersive value under xH name. Vector len is {}", self.ncoincidences);
+            self.writer.events("xH", &self.x, XH_DESCRIPTION)?;
+            self.x.clear();
+            Ok(())
         }
         
-        pub fn output_spim_index(&self) {
-            println!("Outputting each spim index value under si name. Vector len is {}", self.spim_index.len());
-            let out: String = self.spim_index.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
-            fs::write("si.txt", out).unwrap();
+        pub fn output_non_dispersive(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each non-dispersive value under yH name. Vector len is {}", self.ncoincidences);
+            self.writer.events("yH", &self.y, YH_DESCRIPTION)?;
+            self.y.clear();
+            Ok(())
+        }
+        
+        pub fn output_spim_index(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each spim index value under si name. Vector len is {}", self.ncoincidences);
+            self.writer.events("si", &self.spim_index, SI_DESCRIPTION)?;
+            self.spim_index.clear();
+            Ok(())
         }
 
-        pub fn output_cluster_size(&self) {
-            let out: String = self.cluster_size.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
-            fs::write("cs.txt", out).unwrap();
+        pub fn output_cluster_size(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each cluster size under cs name. Vector len is {}", self.ncoincidences);
+            self.writer.events("cs", &self.cluster_size, CS_DESCRIPTION)?;
+            self.cluster_size.clear();
+            Ok(())
         }
 
-        pub fn output_tot(&self, sum_cluster: bool) {
-            let out: String = match sum_cluster {
-                false => {
-                    self.tot.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
+        pub fn output_tot(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each time over threshold under tot name. Vector len is {}", self.ncoincidences);
+            self.writer.events("tot", &self.tot, TOT_DESCRIPTION)?;
+            self.tot.clear();
+            Ok(())
+        }
+
+        pub fn output_energy(&mut self) -> Result<(), Tp3ErrorKind> {
+            println!("Outputting each energy value under eH name. Vector len is {}", self.ncoincidences);
+            self.writer.events("eH", &self.energy, EH_DESCRIPTION)?;
+            self.energy.clear();
+            Ok(())
+        }
+
+        ///Writes `metadata.json`, with the settings of the search and the arrays written so far.
+        ///It must be the last output.
+        pub fn output_metadata(&self) -> Result<(), Tp3ErrorKind> {
+            let spim_tdc = self.spim_tdc.map(|tdc| json!({
+                "tdc": format!("{:?}", self.tdc.spim),
+                "period_ns": tdc.period.as_nanoseconds(),
+                "high_time_ns": tdc.high_time.as_nanoseconds(),
+                "low_time_ns": tdc.low_time.as_nanoseconds(),
+                "counter": tdc.counter(),
+            }));
+            let info = json!({
+                "is_spim": self.is_spim,
+                "spim_size": [self.spim_size.0, self.spim_size.1],
+                "detector": {
+                    "width": self.layout.chip_array().0,
+                    "height": self.layout.chip_array().1,
+                    "spim_pixels": self.layout.spim_pixels(),
                 },
-                true => {
-                    self.tot.iter().zip(self.cluster_size.iter()).map(|(tot, cs)| (*tot as usize * cs).to_string()).collect::<Vec<String>>().join(", ")
+                "photon_channels": self.channels.iter().map(|tdc| format!("{:?}", tdc)).collect::<Vec<String>>(),
+                "coincidence": {
+                    "delay_ns": self.coincidence.delay,
+                    "width_ns": self.coincidence.width,
+                    "search_len": self.coincidence.search_len,
+                    "accidental_shift_ns": self.coincidence.accidental_shift,
                 },
+                "spim_tdc": spim_tdc,
+                "counts": {
+                    "electrons": self.g2.nelectrons,
+                    "photons": self.g2.nphotons,
+                    "coincidences": self.ncoincidences,
+                },
+                "time_unit_ns": TICK.as_nanoseconds(),
+            });
+            self.writer.metadata(info)
+        }
+    }
+
+    ///The difference of `corr` and `acc` and its standard deviation.
+    fn subtracted_rows(corr: &[usize], acc: &[usize]) -> Vec<Vec<f64>> {
+        let values = corr.iter().zip(acc.iter()).map(|(&c, &a)| c as f64 - a as f64).collect();
+        let errors = corr.iter().zip(acc.iter()).map(|(&c, &a)| ((c + a) as f64).sqrt()).collect();
+        vec![values, errors]
+    }
+
+    ///Photons found for an electron: (channel, photon time) in the coincidence window and whether
+    ///any photon is in the off-peak window.
+    #[derive(Default)]
+    struct Correlation {
+        hits: Vec<(usize, Timestamp)>,
+        accidental: bool,
+    }
+
+    ///Searches the photons of each electron. The electrons are split in time-ordered chunks
+    ///searched in parallel. Each chunk starts its windows where the previous chunks left them, so
+    ///the result is the one of a sequential search.
+    fn correlate(electrons: &[SingleElectron], temp_tdc: &[TempTdcData], shift: Timestamp) -> Vec<Correlation> {
+        let chunk_len = cmp::max(electrons.len() / (rayon::current_num_threads() * CHUNKS_PER_THREAD), 1);
+        let starts: Vec<Option<Timestamp>> = electrons.chunks(chunk_len)
+            .scan(None, |latest: &mut Option<Timestamp>, chunk| {
+                let start = *latest;
+                *latest = chunk.iter().map(|val| val.time()).chain(start).max();
+                Some(start)
+            })
+            .collect();
+        let search = |chunk: &[SingleElectron], start: Option<Timestamp>| {
+            let mut cursors: Vec<(usize, usize)> = temp_tdc.iter()
+                .map(|tdc| match start {
+                    Some(start) => (tdc.seek(start, tdc.delay), tdc.seek(start, tdc.delay + shift)),
+                    None => (0, 0),
+                })
+                .collect();
+            chunk.iter().map(|val| {
+                let mut correlation = Correlation::default();
+                for (channel, (tdc, cursor)) in temp_tdc.iter().zip(cursors.iter_mut()).enumerate() {
+                    if let Some(pht) = tdc.find(&mut cursor.0, val.time(), tdc.delay) {
+                        correlation.hits.push((channel, pht));
+                    }
+                    correlation.accidental |= tdc.find(&mut cursor.1, val.time(), tdc.delay + shift).is_some();
+                }
+                correlation
+            }).collect::<Vec<Correlation>>()
+        };
+        electrons.par_chunks(chunk_len)
+            .zip(starts.into_par_iter())
+            .flat_map_iter(|(chunk, start)| search(chunk, start))
+            .collect()
+    }
+
+    ///`G2Data` is the histogram of the photon time minus the electron time, for every pair within
+    ///`G2_RANGE`. It is normalized by the pairs expected from uncorrelated electrons and photons,
+    ///so g2 tends to 1 away from the coincidence peak.
+    pub struct G2Data {
+        pub histogram: Vec<usize>,
+        pub nelectrons: usize,
+        pub nphotons: usize,
+        ///First and last electron times.
+        pub first: Option<Timestamp>,
+        pub last: Timestamp,
+    }
+
+    impl G2Data {
+        fn new() -> Self {
+            Self {
+                histogram: vec![0; 2 * G2_RANGE as usize + 1],
+                nelectrons: 0,
+                nphotons: 0,
+                first: None,
+                last: Timestamp::zero(),
+            }
+        }
+
+        ///Electrons must be sorted, as `CollectionElectron::clean` does.
+        fn add_events(&mut self, electrons: &CollectionElectron, photons: &[Timestamp]) {
+            let (first, last) = match (electrons.values().next(), electrons.values().last()) {
+                (Some(first), Some(last)) => (first.time(), last.time()),
+                _ => return,
             };
-            fs::write("tot.txt", out).unwrap();
+            let histogram = electrons.as_slice().par_iter()
+                .fold(|| vec![0; self.histogram.len()], |mut histogram, val| {
+                    let start = photons.partition_point(|&pht| pht.signed_diff(val.time()) < -G2_RANGE);
+                    photons[start..].iter()
+                        .map(|&pht| pht.signed_diff(val.time()))
+                        .take_while(|&dt| dt <= G2_RANGE)
+                        .for_each(|dt| histogram[(dt + G2_RANGE) as usize] += 1);
+                    histogram
+                })
+                .reduce(|| vec![0; self.histogram.len()], |mut total, histogram| {
+                    total.iter_mut().zip(histogram.iter()).for_each(|(a, b)| *a += b);
+                    total
+                });
+            self.histogram.iter_mut().zip(histogram.iter()).for_each(|(a, b)| *a += b);
+            self.nelectrons += electrons.len();
+            self.first = Some(self.first.map_or(first, |time| time.min(first)));
+            self.last = self.last.max(last);
         }
 
-            
+        ///One row per delay: the delay (ns), g2 and its standard deviation.
+        fn output(&self) -> Vec<Vec<f64>> {
+            let duration = self.last.saturating_sub(self.first.unwrap_or(self.last));
+            let expected = self.nelectrons as f64 * self.nphotons as f64 / duration.ticks().max(1) as f64;
+            self.histogram.iter().enumerate().map(|(index, &counts)| {
+                let tau = (index as i64 - G2_RANGE) as f64 * TICK.as_nanoseconds();
+                let (g2, error) = if expected > 0.0 {
+                    (counts as f64 / expected, (counts as f64).sqrt() / expected)
+                } else {
+                    (0.0, 0.0)
+                };
+                vec![tau, g2, error]
+            }).collect()
+        }
     }
 
+    ///`TempTdcData` holds the photons. They are shifted by the delay when checked, so the window
+    ///can change during a live acquisition. Electrons must be checked in increasing time, as the
+    ///search window slides forward.
+    #[derive(Clone)]
     pub struct TempTdcData {
-        pub tdc: Vec<usize>,
+        pub tdc: Vec<Timestamp>,
         pub min_index: usize,
+        pub delay: Timestamp,
+        pub width: Timestamp,
+        pub search_len: usize,
+    }
+
+    impl Default for TempTdcData {
+        fn default() -> Self {
+            TempTdcData::new(&CoincidenceConfig::default())
+        }
     }
 
     impl TempTdcData {
-        fn new() -> Self {
+        pub fn new(window: &CoincidenceConfig) -> Self {
             Self {
                 tdc: Vec::new(),
                 min_index: 0,
+                delay: window.delay_time(),
+                width: window.width_time(),
+                search_len: window.search_len,
             }
         }
 
-        fn add_tdc(&mut self, my_pack: &Pack) {
-            self.tdc.push(my_pack.tdc_time_norm() - TIME_DELAY);
+        pub fn set_window(&mut self, delay: Timestamp, width: Timestamp) {
+            self.delay = delay;
+            self.width = width;
+        }
+
+        pub fn add_tdc(&mut self, time: Timestamp) {
+            self.tdc.push(time);
+        }
+
+        ///Keeps only the photons arriving from `time`. The search window starts again from the
+        ///first photon.
+        pub fn retain_from(&mut self, time: Timestamp) {
+            self.tdc.retain(|&pht| pht >= time);
+            self.min_index = 0;
         }
 
-        fn sort(&mut self) {
+        pub fn sort(&mut self) {
             self.tdc.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
         }
 
-        fn check(&mut self, value: SingleElectron) -> Option<usize> {
+        ///Removes the photons that can not correlate with electrons after `time`. The search
+        ///window starts again from the first photon.
+        pub fn discard_before(&mut self, time: Timestamp) {
+            let (delay, width) = (self.delay, self.width);
+            self.tdc.retain(|&pht| pht.saturating_sub(delay) + width >= time);
+            self.min_index = 0;
+        }
+
+        ///The photon correlated with an electron arriving at `time`, if any. The photon time is
+        ///shifted by the delay.
+        pub fn check(&mut self, time: Timestamp) -> Option<Timestamp> {
+            let mut min_index = self.min_index;
+            let result = self.find(&mut min_index, time, self.delay);
+            self.min_index = min_index;
+            result
+        }
+
+        ///As `check`, with the search window starting at `min_index` and the photons shifted by
+        ///`delay`. Several windows can so be searched at once over the same photons.
+        pub fn find(&self, min_index: &mut usize, time: Timestamp, delay: Timestamp) -> Option<Timestamp> {
 
+            let (width, search_len) = (self.width, self.search_len);
             let array_length = self.tdc.len();
-            let max_index = cmp::min(self.min_index + MIN_LEN, array_length);
-            
-            let result = self.tdc[self.min_index..max_index].iter()
-                .enumerate()
-                .find(|(_, x)| ((**x as isize - value.time() as isize).abs() as usize) < TIME_WIDTH);
-            
-            //Index must be greater than 10% of MIN_LEN, so first photons do not count.
-            //Effective size must be greater or equal than MIN_LEN otherwise a smaller array is
-            //iterated.
-            match result {
-                Some((index, pht_value)) => {
-                    //if index > MIN_LEN/10 && array_length>self.min_index + MIN_LEN + index {
-                    if index > MIN_LEN/10 && (max_index - self.min_index) >= MIN_LEN {
-                       self.min_index += index/2;
-                    }
-                    Some(*pht_value)
-                },
-                None => None,
+
+            //Photons too early for this electron are too early for the next ones as well.
+            while *min_index < array_length && self.tdc[*min_index].saturating_sub(delay) + width <= time {
+                *min_index += 1;
             }
+            let max_index = cmp::min(*min_index + search_len, array_length);
+            
+            self.tdc[*min_index..max_index].iter()
+                .map(|pht| pht.saturating_sub(delay))
+                .find(|x| x.abs_diff(time) < width)
+        }
+
+        ///The start of the search window once electrons up to `time` were checked.
+        pub fn seek(&self, time: Timestamp, delay: Timestamp) -> usize {
+            self.tdc.partition_point(|pht| pht.saturating_sub(delay) + self.width <= time)
         }
     }
 
@@ -242,72 +935,194 @@ pub mod coincidence {
 
             
 
-    pub fn search_coincidence(file: &str, coinc_data: &mut ElectronData) -> io::Result<()> {
+    ///Searches the coincidences of all the files of `dataset`, read as a single stream.
+    pub fn search_coincidence(dataset: &Dataset, coinc_data: &mut ElectronData) -> Result<(), Tp3ErrorKind> {
         
-        let mut file0 = fs::File::open(file)?;
+        let mut file0 = dataset.open()?;
         
         let spim_tdc: Box<dyn TdcControl> = if coinc_data.is_spim {
-            if coinc_data.spim_size.0 == 0 || coinc_data.spim_size.1 == 0 {
-                panic!("Spim mode is on. X and Y pixels must be greater than 0.");
-            }
-            let temp = PeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut file0, Some(coinc_data.spim_size.1)).expect("Could not create period TDC reference.");
+            if coinc_data.spim_size.0 == 0 {return Err(Tp3ErrorKind::SetXSize);}
+            if coinc_data.spim_size.1 == 0 {return Err(Tp3ErrorKind::SetYSize);}
+            let temp = PeriodicTdcRef::new(coinc_data.tdc.spim, &mut file0, Some(coinc_data.spim_size.1))?;
             coinc_data.prepare_spim(temp);
             Box::new(temp)
         } else {
-            Box::new(NonPeriodicTdcRef::new(TdcType::TdcOneFallingEdge, &mut file0, None).expect("Could not create non periodic TDC reference."))
+            Box::new(NonPeriodicTdcRef::new(coinc_data.tdc.spim, &mut file0, None)?)
         };
-        let np_tdc = NonPeriodicTdcRef::new(TdcType::TdcTwoRisingEdge, &mut file0, None).expect("Could not create non periodic (photon) TDC reference.");
+        let photon_tdcs = coinc_data.channels.iter()
+            .map(|&tdc| NonPeriodicTdcRef::new(tdc, &mut file0, None))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let mut ci = 0;
-        let mut file = fs::File::open(file)?;
-        let mut buffer: Vec<u8> = vec![0; 256_000_000];
+        let time_calibration = Arc::clone(&coinc_data.time_calibration);
+        let energy_calibration = Arc::clone(&coinc_data.energy_calibration);
+        let mut stream = PacketStream::new();
+        let mut time_ext = TimeExtension::new();
+        let mut file = dataset.open()?;
+        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
         let mut total_size = 0;
         let start = Instant::now();
+
+        //Electrons close to the end of a buffer, and the photons that can correlate with them, are
+        //carried to the next one. Coincidences are thus not lost at the buffer boundaries.
+        let mut pending = TempElectronData::new();
+        let mut temp_tdc: Vec<TempTdcData> = photon_tdcs.iter().map(|_| TempTdcData::new(&coinc_data.coincidence)).collect();
+        let mut latest = Timestamp::zero();
         
-        while let Ok(size) = file.read(&mut buffer) {
-            if size == 0 {println!("Finished Reading."); break;}
+        loop {
+            let size = read_packets(&mut file, &mut buffer).map_err(|e| Tp3ErrorKind::SetNoReadFile(file.current().display().to_string(), e))?;
+            if size == 0 {println!("Finished Reading.");}
             total_size += size;
-            println!("MB Read: {}", total_size / 1_000_000 );
-            let mut temp_edata = TempElectronData::new();
-            let mut temp_tdc = TempTdcData::new();
-            //let mut packet_chunks = buffer[0..size].chunks_exact(8);
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => {ci=nci as usize;},
-                    _ => {
-                        let packet = Pack { chip_index: ci, data: pack_oct.try_into().unwrap() };
-                        match packet.id() {
-                            6 if packet.tdc_type() == np_tdc.id() => {
-                                temp_tdc.add_tdc(&packet);
+            println!("MB Read: {}. File: {}.", total_size / 1_000_000, file.current().display());
+            let mut nphotons = 0;
+            stream.events(&buffer[0..size]).for_each(|event| {
+                match event {
+                    Event::Tdc(packet) => {
+                        match photon_tdcs.iter().position(|tdc| tdc.id() == packet.tdc_type()) {
+                            Some(channel) => {
+                                let time = time_ext.tdc_time(&packet);
+                                temp_tdc[channel].add_tdc(time);
+                                latest = latest.max(time);
+                                nphotons += 1;
                             },
-                            6 if packet.tdc_type() == spim_tdc.id() => {
-                                coinc_data.add_spim_line(&packet);
+                            None if packet.tdc_type() == spim_tdc.id() => {
+                                let time = time_ext.tdc_time(&packet);
+                                coinc_data.add_spim_line(&Pack::new(packet, &time_calibration), time);
                             },
-                            11 => {
-                                let se = SingleElectron::new(&packet, coinc_data.spim_tdc, 0);
-                                temp_edata.electron.add_electron(se);
-                            },
-                            _ => {}, //println!("{}", packet.tdc_type());},
-                        };
+                            None => {},
+                        }
+                    },
+                    Event::Electron(packet) => {
+                        let packet = Pack::new(packet, &time_calibration);
+                        let time = time_ext.electron_time(&packet);
+                        if let Some(se) = SingleElectron::new(&packet, time, coinc_data.spim_tdc, 0, &coinc_data.layout, &energy_calibration) {
+                            latest = latest.max(se.time());
+                            pending.electron.add_electron(se);
+                        }
+                    },
+                    Event::GlobalTime(packet) => {
+                        time_ext.upt_global(&packet);
                     },
+                    _ => {}, //println!("{}", packet.tdc_type());},
                 };
             });
-        coinc_data.add_events(temp_edata, temp_tdc);
-        println!("Time elapsed: {:?}", start.elapsed());
+            coinc_data.add_photons(nphotons);
 
+            //The last buffer is processed entirely.
+            let horizon = match size {
+                0 => None,
+                _ => Some(latest.saturating_sub(coinc_data.lookahead())),
+            };
+            let carried = match horizon {
+                Some(horizon) => pending.electron.split_off_after(horizon),
+                None => CollectionElectron::new(),
+            };
+            let temp_edata = std::mem::replace(&mut pending, TempElectronData {electron: carried, min_index: 0});
+            coinc_data.add_events(temp_edata, &mut temp_tdc);
+            if let Some(horizon) = horizon {
+                let first_photon = horizon.saturating_sub(coinc_data.lookbehind());
+                temp_tdc.iter_mut().for_each(|channel| channel.retain_from(first_photon));
+            }
+            coinc_data.flush_events()?;
+            println!("Time elapsed: {:?}. Electrons carried to the next buffer: {}.", start.elapsed(), pending.electron.len());
+            if size == 0 {break;}
         }
         println!("Total number of bytes read {}", total_size);
+        if stream.pending() > 0 {println!("The dataset ends with {} bytes of an incomplete packet.", stream.pending());}
+        println!("Packet statistics: {}", stream.statistics());
         Ok(())
     }
+
+    ///Finds the photon delay from the peak of the histogram of the photon time minus the electron
+    ///time, over all the files of `dataset`. The photons of all the channels are used, and delays
+    ///of either sign are searched. The delay of `coinc_data` is then replaced.
+    pub fn calibrate_delay(dataset: &Dataset, coinc_data: &mut ElectronData) -> Result<Timestamp, Tp3ErrorKind> {
+        
+        let mut file0 = dataset.open()?;
+        let photon_tdcs = coinc_data.channels.iter()
+            .map(|&tdc| NonPeriodicTdcRef::new(tdc, &mut file0, None))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stream = PacketStream::new();
+        let mut time_ext = TimeExtension::new();
+        let mut reader = dataset.open()?;
+        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+        let range = Timestamp::new(CALIBRATION_RANGE as u64);
+        
+        //Electrons whose photons can still arrive in the next buffer wait for it.
+        let mut electrons: Vec<Timestamp> = Vec::new();
+        let mut photons: Vec<Timestamp> = Vec::new();
+        let mut histogram = vec![0usize; 2 * CALIBRATION_RANGE];
+        let mut latest = Timestamp::zero();
+        loop {
+            let size = read_packets(&mut reader, &mut buffer).map_err(|e| Tp3ErrorKind::SetNoReadFile(reader.current().display().to_string(), e))?;
+            stream.events(&buffer[0..size]).for_each(|event| {
+                match event {
+                    Event::Tdc(packet) if photon_tdcs.iter().any(|tdc| tdc.id() == packet.tdc_type()) => {
+                        let time = time_ext.tdc_time(&packet);
+                        latest = latest.max(time);
+                        photons.push(time);
+                    },
+                    Event::Electron(packet) => {
+                        let packet = Pack::new(packet, &coinc_data.time_calibration);
+                        let time = time_ext.electron_time(&packet);
+                        latest = latest.max(time);
+                        electrons.push(time);
+                    },
+                    Event::GlobalTime(packet) => {
+                        time_ext.upt_global(&packet);
+                    },
+                    _ => {},
+                };
+            });
+            electrons.sort_unstable();
+            photons.sort_unstable();
+
+            //The last buffer is processed entirely.
+            let horizon = match size {
+                0 => None,
+                _ => Some(latest.saturating_sub(range + CHUNK_OVERLAP)),
+            };
+            let ready = horizon.map_or(electrons.len(), |horizon| electrons.partition_point(|&time| time <= horizon));
+            for &time in &electrons[..ready] {
+                let first = photons.partition_point(|&pht| pht + range <= time);
+                photons[first..].iter()
+                    .map(|pht| pht.signed_diff(time) + CALIBRATION_RANGE as i64)
+                    .take_while(|&bin| bin < 2 * CALIBRATION_RANGE as i64)
+                    .for_each(|bin| histogram[bin as usize] += 1);
+            }
+            electrons.drain(..ready);
+            if let Some(horizon) = horizon {
+                let first = photons.partition_point(|&pht| pht + range <= horizon);
+                photons.drain(..first);
+            }
+            if size == 0 {break;}
+        }
+
+        let (peak, counts) = histogram.iter().enumerate()
+            .max_by_key(|&(_, counts)| *counts)
+            .ok_or(Tp3ErrorKind::CalibrationNoCoincidence)?;
+        if *counts == 0 {return Err(Tp3ErrorKind::CalibrationNoCoincidence);}
+        if peak < CALIBRATION_RANGE {
+            let delay: Timestamp = Timestamp::new((CALIBRATION_RANGE - peak) as u64);
+            println!("Calibrated delay is -{}. Number of electron-photon pairs at the peak: {}.", delay, counts);
+            return Err(Tp3ErrorKind::CalibrationNegativeDelay(-delay.as_nanoseconds()));
+        }
+
+        let delay = Timestamp::new((peak - CALIBRATION_RANGE) as u64);
+        coinc_data.coincidence.delay = delay.as_nanoseconds();
+        println!("Calibrated delay is {}. Number of electron-photon pairs at the peak: {}.", delay, counts);
+        Ok(delay)
+    }
 }
 
 pub mod ntime_resolved {
-    use crate::spimlib::SPIM_PIXELS;
-    use crate::packetlib::{Packet, PacketEELS as Pack};
+    use crate::packetlib::{Packet, PacketEELS as Pack, PacketStream, Event, TimeExtension, Timestamp};
+    use crate::detectorlib::DetectorLayout;
+    use crate::calibrationlib::{EnergyCalibration, ENERGY_CALIBRATION_FILES};
     use crate::tdclib::{TdcControl, TdcType, PeriodicTdcRef};
-    use std::io::prelude::*;
+    use crate::errorlib::Tp3ErrorKind;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron};
-    use std::convert::TryInto;
+    use super::dataset::{Dataset, DatasetReader, read_packets};
     use std::fs;
 
     #[derive(Debug)]
@@ -317,12 +1132,19 @@ pub mod ntime_resolved {
         FolderNotCreated,
         ScanOutofBounds,
         MinGreaterThanMax,
+        Tp3(Tp3ErrorKind),
+    }
+
+    impl From<Tp3ErrorKind> for ErrorType {
+        fn from(error: Tp3ErrorKind) -> Self {
+            ErrorType::Tp3(error)
+        }
     }
 
     pub trait TimeTypes {
-        fn prepare(&mut self, file: &mut fs::File);
-        fn add_electron(&mut self, packet: &Pack);
-        fn add_tdc(&mut self, packet: &Pack);
+        fn prepare(&mut self, file: &mut DatasetReader) -> Result<(), ErrorType>;
+        fn add_electron(&mut self, packet: &Pack, time: Timestamp);
+        fn add_tdc(&mut self, packet: &Pack, time: Timestamp);
         fn process(&mut self) -> Result<(), ErrorType>;
         fn output(&mut self, how_many: usize) -> Result<(), ErrorType>;
         fn display_info(&self) -> Result<(), ErrorType>;
@@ -344,21 +1166,24 @@ pub mod ntime_resolved {
         pub remove_clusters: bool,
         pub frame_int: usize,
         pub slice: usize,
+        pub layout: DetectorLayout, //The detector geometry,
+        pub energy_calibration: EnergyCalibration, //The ToT to energy conversion,
     }
     
     impl TimeTypes for TimeSpectralSpatial {
-        fn prepare(&mut self, file: &mut fs::File) {
+        fn prepare(&mut self, file: &mut DatasetReader) -> Result<(), ErrorType> {
             self.tdc_periodic = match self.tdc_periodic {
                 None if self.spimx>1 && self.spimy>1 => {
-                    let val = Some(PeriodicTdcRef::new(self.tdc_type.clone(), file, Some(self.spimy)).expect("Problem in creating periodic tdc ref."));
+                    let val = Some(PeriodicTdcRef::new(self.tdc_type, file, Some(self.spimy))?);
                     val
                 },
                 Some(val) => Some(val),
                 _ => None,
             };
+            Ok(())
         }
 
-        fn add_electron(&mut self, packet: &Pack) {
+        fn add_electron(&mut self, packet: &Pack, time: Timestamp) {
             //Getting Initial Time
             let mut vec_index;
             if let Some(spim_tdc) = self.tdc_periodic {
@@ -373,29 +1198,30 @@ pub mod ntime_resolved {
                 self.expand_data();
             }
             
-            let se = SingleElectron::new(packet, self.tdc_periodic, vec_index);
-            self.ensemble.add_electron(se);
+            if let Some(se) = SingleElectron::new(packet, time, self.tdc_periodic, vec_index, &self.layout, &self.energy_calibration) {
+                self.ensemble.add_electron(se);
+            }
         }
 
-        fn add_tdc(&mut self, packet: &Pack) {
+        fn add_tdc(&mut self, packet: &Pack, time: Timestamp) {
             //Synchronizing clocks using two different approaches. It is always better to use a multiple of 2 and use the FPGA counter.
             match &mut self.tdc_periodic {
                 Some(my_tdc_periodic) if packet.tdc_type() == self.tdc_type.associate_value() => {
-                    my_tdc_periodic.upt(packet.tdc_time_norm(), packet.tdc_counter());
+                    my_tdc_periodic.upt(time, packet.tdc_counter());
                 },
                 _ => {},
             };
         }
 
         fn process(&mut self) -> Result<(), ErrorType> {
-            //self.ensemble.output_data(String::from("entire_data"), 2);
+            //self.ensemble.output_data(String::from("entire_data"), 2, (0.0, f32::MAX));
             if self.ensemble.try_clean(0, self.remove_clusters) {
-                //self.ensemble.output_data(String::from("entire_data_cluster"), 2);
+                //self.ensemble.output_data(String::from("entire_data_cluster"), 2, (0.0, f32::MAX));
                 let mut max_slice: Option<usize> = None;
                 let mut min_slice: Option<usize> = None;
                 
                 for val in self.ensemble.values() {
-                    if let Some(index) = val.get_or_not_spim_index(self.tdc_periodic, self.spimx, self.spimy) {
+                    if let Some(index) = val.get_or_not_spim_index(self.tdc_periodic, self.spimx, self.spimy, &self.layout) {
                         self.spectra[val.spim_slice()-self.slice][index] += 1;
                         
                         max_slice = match max_slice {
@@ -552,7 +1378,7 @@ pub mod ntime_resolved {
     }
     
     impl TimeSpectralSpatial {
-        pub fn new(frame_int: usize, spimx: usize, spimy: usize, remove_clusters: bool, tdc_type: TdcType, folder: String) -> Result<Self, ErrorType> {
+        pub fn new(frame_int: usize, spimx: usize, spimy: usize, remove_clusters: bool, tdc_type: TdcType, folder: String, layout: DetectorLayout) -> Result<Self, ErrorType> {
 
             Ok(Self {
                 spectra: Vec::new(),
@@ -565,55 +1391,213 @@ pub mod ntime_resolved {
                 remove_clusters: remove_clusters,
                 frame_int: frame_int,
                 slice: 0,
+                layout,
+                energy_calibration: EnergyCalibration::from_files_or_default(ENERGY_CALIBRATION_FILES)?,
             })
         }
         
         fn expand_data(&mut self) {
-            self.spectra.push(vec![0; self.spimx*self.spimy*SPIM_PIXELS]);
+            self.spectra.push(vec![0; self.spimx*self.spimy*self.layout.spim_pixels()]);
         }
     }
 
-    pub fn analyze_data(file: &str, data: &mut TimeSet) {
+    ///Analyzes all the files of `dataset`, read as a single stream.
+    pub fn analyze_data(dataset: &Dataset, data: &mut TimeSet) -> Result<(), ErrorType> {
         for each in data.set.iter_mut() {
-            let mut file = fs::File::open(file).expect("Could not open desired file.");
-            each.prepare(&mut file);
+            let mut file = dataset.open()?;
+            each.prepare(&mut file)?;
         }
 
 
-        let mut my_file = fs::File::open(file).expect("Could not open desired file.");
+        let mut my_file = dataset.open()?;
         let mut buffer: Vec<u8> = vec![0; 128_000_000];
 
         let mut total_size = 0;
-        let mut ci = 0usize;
+        let mut stream = PacketStream::new();
+        let mut time_ext = TimeExtension::new();
 
-        while let Ok(size) = my_file.read(&mut buffer) {
+        loop {
+            let size = read_packets(&mut my_file, &mut buffer).map_err(|e| Tp3ErrorKind::SetNoReadFile(my_file.current().display().to_string(), e))?;
             if size==0 {break;}
             total_size += size;
-            buffer[0..size].chunks_exact(8).for_each(|pack_oct| {
-                match pack_oct {
-                    &[84, 80, 88, 51, nci, _, _, _] => {ci = nci as usize},
-                    _ => {
-                        let packet = Pack{chip_index: ci, data: pack_oct.try_into().unwrap()};
-                        match packet.id() {
-                            6 => {
-                                for each in data.set.iter_mut() {
-                                    each.add_tdc(&packet);
-                                }
-                            },
-                            11 => {
-                                for each in data.set.iter_mut() {
-                                    each.add_electron(&packet);
-                                }
-                            },
-                            _ => {},
-                        };
+            stream.events(&buffer[0..size]).for_each(|event| {
+                match event {
+                    Event::Tdc(packet) => {
+                        let time = time_ext.tdc_time(&packet);
+                        for each in data.set.iter_mut() {
+                            each.add_tdc(&packet, time);
+                        }
                     },
+                    Event::Electron(packet) => {
+                        let time = time_ext.electron_time(&packet);
+                        for each in data.set.iter_mut() {
+                            each.add_electron(&packet, time);
+                        }
+                    },
+                    Event::GlobalTime(packet) => {
+                        time_ext.upt_global(&packet);
+                    },
+                    _ => {},
                 };
             });
             for each in data.set.iter_mut() {
-                each.process().expect("Error in processing");
+                each.process()?;
             }
-            println!("File: {:?}. Total number of bytes read (MB): ~ {}", file, total_size/1_000_000);
+            println!("File: {:?}. Total number of bytes read (MB): ~ {}", my_file.current(), total_size/1_000_000);
         };
+        println!("Packet statistics: {}", stream.statistics());
+        Ok(())
+    }
+}
+
+pub mod calibration {
+    use crate::packetlib::{Packet, PacketStream, Event, TimeExtension, Timestamp};
+    use crate::calibrationlib::{TimeCalibration, pixel_index, PIXELS_PER_CHIP, TOT_VALUES};
+    use super::dataset::{Dataset, read_packets};
+    use super::coincidence::{BUFFER_SIZE, CHUNK_OVERLAP};
+    use crate::tdclib::{TdcControl, TdcType, NonPeriodicTdcRef};
+    use crate::errorlib::Tp3ErrorKind;
+
+    const TIME_WINDOW: i64 = 256; //Half width of the window in which electrons and photons are paired (in units of 640 MHz).
+    const MIN_HITS: usize = 20; //Minimum number of coincidences to calibrate a pixel or a ToT value.
+
+    fn median(values: &mut [i64]) -> f64 {
+        values.sort_unstable();
+        let len = values.len();
+        if len.is_multiple_of(2) {
+            (values[len / 2 - 1] + values[len / 2]) as f64 / 2.0
+        } else {
+            values[len / 2] as f64
+        }
+    }
+
+    ///`TimeCalibrationData` gathers the electron-photon coincidences of a dataset and derives the
+    ///`TimeCalibration` from them. Electrons use their uncorrected time, so the dataset must be a
+    ///measurement in which the photon emission is prompt. Photons are compared to the electrons
+    ///once shifted by `delay` (see `CoincidenceConfig::delay` and `coincidence::calibrate_delay`).
+    pub struct TimeCalibrationData {
+        hits: Vec<(usize, u16, i64)>, //Pixel index, ToT and time difference to the photon,
+        photon_tdc: TdcType,
+        delay: Timestamp,
+    }
+
+    impl TimeCalibrationData {
+        pub fn new(photon_tdc: TdcType, delay: Timestamp) -> Self {
+            Self {
+                hits: Vec::new(),
+                photon_tdc,
+                delay,
+            }
+        }
+
+        ///Pairs every electron of `dataset` with its closest photon. As in `search_coincidence`,
+        ///electrons close to the end of a buffer, and the photons they can pair with, are carried
+        ///to the next one.
+        pub fn add_dataset(&mut self, dataset: &Dataset) -> Result<(), Tp3ErrorKind> {
+            let mut file0 = dataset.open()?;
+            let np_tdc = NonPeriodicTdcRef::new(self.photon_tdc, &mut file0, None)?;
+
+            let mut stream = PacketStream::new();
+            let mut time_ext = TimeExtension::new();
+            let mut reader = dataset.open()?;
+            let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+            let window = Timestamp::new(TIME_WINDOW as u64);
+            let delay = self.delay;
+
+            let mut electrons: Vec<(usize, u16, Timestamp)> = Vec::new();
+            let mut photons: Vec<Timestamp> = Vec::new();
+            let mut latest = Timestamp::zero();
+            loop {
+                let size = read_packets(&mut reader, &mut buffer).map_err(|e| Tp3ErrorKind::SetNoReadFile(reader.current().display().to_string(), e))?;
+                stream.events(&buffer[0..size]).for_each(|event| {
+                    match event {
+                        Event::Tdc(packet) if packet.tdc_type() == np_tdc.id() => {
+                            let time = time_ext.tdc_time(&packet) + delay;
+                            latest = latest.max(time);
+                            photons.push(time);
+                        },
+                        Event::Electron(packet) => {
+                            let time = time_ext.electron_time(&packet);
+                            latest = latest.max(time);
+                            electrons.push((pixel_index(&packet), packet.tot(), time));
+                        },
+                        Event::GlobalTime(packet) => {
+                            time_ext.upt_global(&packet);
+                        },
+                        _ => {},
+                    };
+                });
+                electrons.sort_unstable_by_key(|&(_, _, time)| time);
+                photons.sort_unstable();
+
+                //The last buffer is processed entirely.
+                let horizon = match size {
+                    0 => None,
+                    _ => Some(latest.saturating_sub(window + CHUNK_OVERLAP)),
+                };
+                let ready = horizon.map_or(electrons.len(), |horizon| electrons.partition_point(|&(_, _, time)| time <= horizon));
+                for &(index, tot, time) in &electrons[..ready] {
+                    let pos = photons.partition_point(|&pht| pht < time);
+                    let closest = photons[pos.saturating_sub(1)..photons.len().min(pos + 1)].iter()
+                        .map(|&pht| time.signed_diff(pht))
+                        .min_by_key(|dt| dt.abs());
+                    if let Some(dt) = closest {
+                        if dt.abs() < TIME_WINDOW {
+                            self.hits.push((index, tot, dt));
+                        }
+                    }
+                }
+                electrons.drain(..ready);
+                if let Some(horizon) = horizon {
+                    let first = photons.partition_point(|&pht| pht + window <= horizon);
+                    photons.drain(..first);
+                }
+                println!("File: {}. Number of coincidences: {}.", reader.current().display(), self.hits.len());
+                if size == 0 {break;}
+            }
+            println!("Packet statistics: {}", stream.statistics());
+            Ok(())
+        }
+
+        ///The time-walk is the median time difference of each ToT value. The ToA offset of a
+        ///pixel is then the median time difference of its hits, once corrected by the time-walk.
+        ///Both are relative to the median of the whole dataset. ToT values with too few hits take
+        ///the value of the closest smaller ToT, and such pixels are not corrected.
+        pub fn build(&self) -> Result<TimeCalibration, Tp3ErrorKind> {
+            let mut all = self.hits.iter().map(|hit| hit.2).collect::<Vec<i64>>();
+            if all.is_empty() {
+                println!("No coincidence found. Calibration will be empty.");
+                return Ok(TimeCalibration::empty());
+            }
+            let center = median(&mut all);
+
+            let mut per_tot: Vec<Vec<i64>> = vec![Vec::new(); TOT_VALUES];
+            for &(_, tot, dt) in &self.hits {
+                per_tot[tot as usize].push(dt);
+            }
+            let measured_walk = per_tot.iter_mut()
+                .map(|dts| if dts.len() >= MIN_HITS { Some((median(dts) - center).round() as i32) } else { None })
+                .collect::<Vec<Option<i32>>>();
+            let mut last = measured_walk.iter().flatten().next().copied().unwrap_or(0);
+            let time_walk = measured_walk.iter()
+                .map(|walk| {
+                    if let Some(val) = walk {last = *val;}
+                    last
+                })
+                .collect::<Vec<i32>>();
+
+            let nchips = self.hits.iter().map(|hit| hit.0 / PIXELS_PER_CHIP + 1).max().unwrap();
+            let mut per_pixel: Vec<Vec<i64>> = vec![Vec::new(); nchips * PIXELS_PER_CHIP];
+            for &(index, tot, dt) in &self.hits {
+                per_pixel[index].push(dt - time_walk[tot as usize] as i64);
+            }
+            let toa_offset = per_pixel.iter_mut()
+                .map(|dts| if dts.len() >= MIN_HITS { (median(dts) - center).round() as i32 } else { 0 })
+                .collect::<Vec<i32>>();
+
+            let calibrated = per_pixel.iter().filter(|dts| dts.len() >= MIN_HITS).count();
+            println!("Number of coincidences: {}. Time difference median (units of 640 MHz): {}. Calibrated pixels: {}. Calibrated ToT values: {}.", self.hits.len(), center, calibrated, measured_walk.iter().flatten().count());
+            TimeCalibration::new(toa_offset, time_walk)
+        }
     }
 }
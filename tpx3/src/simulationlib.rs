@@ -0,0 +1,353 @@
+//!`simulationlib` produces TPX3 data without the microscope. `Tpx3Writer` writes packets in the
+//!raw TPX3 format, grouped in chip chunks, to a file or to a socket. `SyntheticStream` generates
+//!the events of an acquisition: electrons with a given rate and spectrum, the scan TDCs and the
+//!photon TDCs.
+
+use crate::packetlib::{InversePacket, Timestamp, Clock3840MHz};
+use crate::detectorlib::DetectorLayout;
+use crate::tdclib::TdcType;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp, Normal};
+use std::collections::{BinaryHeap, VecDeque};
+use std::cmp::Reverse;
+use std::io::{self, Write};
+
+///Maximum number of packets in a chunk. The chunk size, in bytes, is a 16 bits value of the header.
+pub const MAX_CHUNK_PACKETS: usize = 8_191;
+///Ticks of 640 MHz in one second.
+const TICKS_PER_SECOND: f64 = 640_000_000.0;
+
+///`Tpx3Writer` writes packets in the raw TPX3 format. Consecutive packets of the same chip are
+///grouped in a chunk preceded by its `TPX3` header. TDCs go in the chunk being written. Call
+///`flush` (or `finish`) to write the last chunk.
+pub struct Tpx3Writer<W: Write> {
+    writer: W,
+    layout: DetectorLayout,
+    chip_index: Option<usize>,
+    chunk: Vec<u8>,
+    packets: usize,
+}
+
+impl<W: Write> Tpx3Writer<W> {
+    pub fn new(writer: W, layout: DetectorLayout) -> Self {
+        Tpx3Writer {
+            writer,
+            layout,
+            chip_index: None,
+            chunk: Vec::with_capacity(MAX_CHUNK_PACKETS * 8),
+            packets: 0,
+        }
+    }
+
+    ///Number of packets written so far.
+    pub fn packets(&self) -> usize {
+        self.packets
+    }
+
+    ///Writes a packet of chip `ci`. A new chunk starts when the chip changes or the chunk is full.
+    pub fn write_packet(&mut self, ci: usize, data: &[u8; 8]) -> io::Result<()> {
+        if self.chip_index != Some(ci) || self.chunk.len() == MAX_CHUNK_PACKETS * 8 {
+            self.write_chunk()?;
+            self.chip_index = Some(ci);
+        }
+        self.chunk.extend_from_slice(data);
+        self.packets += 1;
+        Ok(())
+    }
+
+    ///Writes an electron hit at the position (`x`, `y`) of the assembled image.
+    pub fn write_electron(&mut self, x: usize, y: usize, tot: u16, time: Timestamp) -> io::Result<()> {
+        let (ci, x_raw, y_raw) = self.layout.chip_address(x, y)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Electron position is not in the detector layout."))?;
+        self.write_packet(ci, &InversePacket::electron_packet(x_raw, y_raw, tot, time))
+    }
+
+    pub fn write_tdc(&mut self, kind: &TdcType, counter: u16, time: Timestamp<Clock3840MHz>) -> io::Result<()> {
+        let ci = self.chip_index.unwrap_or(0);
+        self.write_packet(ci, &InversePacket::tdc_packet(kind, counter, time))
+    }
+
+    pub fn write_event(&mut self, event: &SyntheticEvent) -> io::Result<()> {
+        match event {
+            SyntheticEvent::Electron { x, y, tot, time } => self.write_electron(*x, *y, *tot, *time),
+            SyntheticEvent::Tdc { kind, counter, time } => self.write_tdc(kind, *counter, time.convert()),
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if let Some(ci) = self.chip_index {
+            if !self.chunk.is_empty() {
+                let size = self.chunk.len();
+                self.writer.write_all(&[84, 80, 88, 51, ci as u8, 0, (size & 255) as u8, (size >> 8) as u8])?;
+                self.writer.write_all(&self.chunk)?;
+                self.chunk.clear();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.writer.flush()
+    }
+
+    ///Writes the last chunk and gives the writer back.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+///Shape of the spectrum along the dispersive (x) axis.
+#[derive(Clone, Debug)]
+pub enum SpectrumShape {
+    ///Electrons spread over the whole width of the detector.
+    Uniform,
+    ///Gaussian peaks given by their center and standard deviation, in pixels, and their relative
+    ///weight.
+    Peaks(Vec<(f64, f64, f64)>),
+}
+
+///Beam scan of a hyperspectral image. TDC one falls at the beginning of each line and rises at
+///its end, as expected by `tdclib::PeriodicTdcRef`. If `frame_tdc` is set, it is also sent at the
+///beginning of each frame.
+#[derive(Clone)]
+pub struct ScanConfig {
+    pub xspim: usize,
+    pub yspim: usize,
+    pub pixel_time: Timestamp,
+    pub flyback: Timestamp,
+    pub frame_tdc: Option<TdcType>,
+}
+
+///Photons detected by a TDC. A `coincidence_fraction` of the electrons emits a photon `delay` after
+///it, with a gaussian `jitter` (standard deviation). Uncorrelated photons arrive at `dark_rate`
+///per second.
+#[derive(Clone)]
+pub struct PhotonConfig {
+    pub tdc: TdcType,
+    pub coincidence_fraction: f64,
+    pub delay: Timestamp,
+    pub jitter: Timestamp,
+    pub dark_rate: f64,
+}
+
+///Parameters of a `SyntheticStream`. Electrons arrive at random (Poisson) with `electron_rate` per
+///second. Their x follows `spectrum` and their y a gaussian of center and standard deviation
+///`y_profile`. Electrons falling out of the detector are dropped. The same `seed` always gives the
+///same stream.
+#[derive(Clone)]
+pub struct SyntheticConfig {
+    pub layout: DetectorLayout,
+    pub duration: Timestamp,
+    pub electron_rate: f64,
+    pub spectrum: SpectrumShape,
+    pub y_profile: (f64, f64),
+    pub tot: u16,
+    pub scan: Option<ScanConfig>,
+    pub photons: Option<PhotonConfig>,
+    pub seed: u64,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        SyntheticConfig {
+            layout: DetectorLayout::default(),
+            duration: Timestamp::from_nanoseconds(1_000_000_000),
+            electron_rate: 1_000_000.0,
+            spectrum: SpectrumShape::Peaks(vec![(128.0, 3.0, 1.0)]),
+            y_profile: (128.0, 5.0),
+            tot: 100,
+            scan: None,
+            photons: None,
+            seed: 0,
+        }
+    }
+}
+
+///An event of the synthetic stream. Times are in units of 640 MHz (1.5625 ns).
+#[derive(Clone)]
+pub enum SyntheticEvent {
+    Electron { x: usize, y: usize, tot: u16, time: Timestamp },
+    Tdc { kind: TdcType, counter: u16, time: Timestamp },
+}
+
+impl SyntheticEvent {
+    pub fn time(&self) -> Timestamp {
+        match self {
+            SyntheticEvent::Electron { time, .. } | SyntheticEvent::Tdc { time, .. } => *time,
+        }
+    }
+}
+
+enum Source {
+    Electron,
+    Scan,
+    Dark,
+    Photon,
+}
+
+///`SyntheticStream` iterates over the events of a synthetic acquisition, in time order.
+pub struct SyntheticStream {
+    config: SyntheticConfig,
+    rng: StdRng,
+    electron_interval: Exp<f64>,
+    dark_interval: Option<Exp<f64>>,
+    peaks: Vec<(Normal<f64>, f64)>,
+    next_electron: f64,
+    next_dark: f64,
+    scan_line: usize,
+    scan_edges: VecDeque<(Timestamp, TdcType)>,
+    photons: BinaryHeap<Reverse<Timestamp>>,
+    counters: [u16; 2],
+}
+
+impl SyntheticStream {
+    pub fn new(config: SyntheticConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let electron_interval = Exp::new(config.electron_rate / TICKS_PER_SECOND).expect("Electron rate must be positive.");
+        let dark_interval = config.photons.as_ref()
+            .filter(|photons| photons.dark_rate > 0.0)
+            .map(|photons| Exp::new(photons.dark_rate / TICKS_PER_SECOND).expect("Dark rate must be positive."));
+        let peaks = match &config.spectrum {
+            SpectrumShape::Uniform => Vec::new(),
+            SpectrumShape::Peaks(peaks) => {
+                let total: f64 = peaks.iter().map(|peak| peak.2).sum();
+                let mut cumulative = 0.0;
+                peaks.iter().map(|(center, sigma, weight)| {
+                    cumulative += weight / total;
+                    (Normal::new(*center, *sigma).expect("Peak width must be positive."), cumulative)
+                }).collect()
+            },
+        };
+        let next_electron = electron_interval.sample(&mut rng);
+        let next_dark = dark_interval.map_or(f64::INFINITY, |dark| dark.sample(&mut rng));
+        SyntheticStream {
+            config,
+            rng,
+            electron_interval,
+            dark_interval,
+            peaks,
+            next_electron,
+            next_dark,
+            scan_line: 0,
+            scan_edges: VecDeque::new(),
+            photons: BinaryHeap::new(),
+            counters: [0; 2],
+        }
+    }
+
+    ///Writes the whole stream and returns the number of events written.
+    pub fn write_to<W: Write>(self, writer: &mut Tpx3Writer<W>) -> io::Result<usize> {
+        let mut nevents = 0;
+        for event in self {
+            writer.write_event(&event)?;
+            nevents += 1;
+        }
+        writer.flush()?;
+        Ok(nevents)
+    }
+
+    fn next_scan_edge(&mut self) -> Option<Timestamp> {
+        let scan = self.config.scan.as_ref()?;
+        if self.scan_edges.is_empty() {
+            let line_time = scan.pixel_time * scan.xspim as u64;
+            let line_start = (line_time + scan.flyback) * self.scan_line as u64;
+            if let Some(frame_tdc) = &scan.frame_tdc {
+                if self.scan_line.is_multiple_of(scan.yspim) {
+                    self.scan_edges.push_back((line_start, *frame_tdc));
+                }
+            }
+            self.scan_edges.push_back((line_start, TdcType::TdcOneFallingEdge));
+            self.scan_edges.push_back((line_start + line_time, TdcType::TdcOneRisingEdge));
+            self.scan_line += 1;
+        }
+        self.scan_edges.front().map(|edge| edge.0)
+    }
+
+    fn tdc_event(&mut self, kind: TdcType, time: Timestamp) -> SyntheticEvent {
+        let channel = match kind {
+            TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge => 0,
+            _ => 1,
+        };
+        self.counters[channel] = (self.counters[channel] + 1) & 4_095;
+        SyntheticEvent::Tdc { kind, counter: self.counters[channel], time }
+    }
+
+    fn sample_x(&mut self) -> f64 {
+        let width = self.config.layout.size().0 as f64;
+        if self.peaks.is_empty() {
+            return self.rng.gen_range(0.0..width);
+        }
+        let pick: f64 = self.rng.gen();
+        let peak = self.peaks.iter().find(|peak| pick < peak.1).unwrap_or(&self.peaks[self.peaks.len() - 1]).0;
+        peak.sample(&mut self.rng)
+    }
+
+    fn sample_y(&mut self) -> f64 {
+        let (center, sigma) = self.config.y_profile;
+        if sigma > 0.0 {
+            Normal::new(center, sigma).expect("Beam width must be positive.").sample(&mut self.rng)
+        } else {
+            center
+        }
+    }
+}
+
+impl Iterator for SyntheticStream {
+    type Item = SyntheticEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut source = (Timestamp::new(self.next_electron as u64), Source::Electron);
+            if let Some(time) = self.next_scan_edge() {
+                if time < source.0 {source = (time, Source::Scan);}
+            }
+            if self.next_dark < source.0.ticks() as f64 {
+                source = (Timestamp::new(self.next_dark as u64), Source::Dark);
+            }
+            if let Some(Reverse(time)) = self.photons.peek() {
+                if *time < source.0 {source = (*time, Source::Photon);}
+            }
+            let (time, source) = source;
+            if time > self.config.duration {return None;}
+
+            match source {
+                Source::Photon => {
+                    self.photons.pop();
+                    let kind = self.config.photons.as_ref().unwrap().tdc;
+                    return Some(self.tdc_event(kind, time));
+                },
+                Source::Scan => {
+                    let (time, kind) = self.scan_edges.pop_front().unwrap();
+                    return Some(self.tdc_event(kind, time));
+                },
+                Source::Dark => {
+                    self.next_dark += self.dark_interval.unwrap().sample(&mut self.rng);
+                    let kind = self.config.photons.as_ref().unwrap().tdc;
+                    return Some(self.tdc_event(kind, time));
+                },
+                Source::Electron => {
+                    self.next_electron += self.electron_interval.sample(&mut self.rng);
+                    if let Some(photons) = &self.config.photons {
+                        if self.rng.gen::<f64>() < photons.coincidence_fraction {
+                            let jitter = if photons.jitter > Timestamp::zero() {
+                                Normal::new(0.0, photons.jitter.ticks() as f64).unwrap().sample(&mut self.rng)
+                            } else {
+                                0.0
+                            };
+                            let photon_time = ((time + photons.delay).ticks() as f64 + jitter).max(time.ticks() as f64);
+                            self.photons.push(Reverse(Timestamp::new(photon_time as u64)));
+                        }
+                    }
+                    let x = self.sample_x();
+                    let y = self.sample_y();
+                    let (width, height) = self.config.layout.size();
+                    if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {continue;}
+                    return Some(SyntheticEvent::Electron { x: x as usize, y: y as usize, tot: self.config.tot, time });
+                },
+            }
+        }
+    }
+}
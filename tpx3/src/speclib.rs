@@ -1,18 +1,18 @@
 //!`speclib` is a collection of tools to set EELS/4D acquisition.
 
-use crate::packetlib::{Packet, PacketEELS as Pack};
-use crate::auxiliar::{Settings, misc::TimepixRead};
-use crate::tdclib::{TdcControl, PeriodicTdcRef};
+use crate::packetlib::{Packet, PacketEELS as Pack, PacketStream, Event, Events, TimeExtension, Timestamp};
+use crate::auxiliar::{Settings, OutputFormat, misc::TimepixRead};
+use crate::tdclib::{self, TdcControl, PeriodicTdcRef};
 use crate::errorlib::Tp3ErrorKind;
+use crate::controllib::AcquisitionControl;
+use crate::postlib::coincidence::TempTdcData;
 use std::time::Instant;
 use std::io::Write;
-use std::convert::TryInto;
 //use rayon::prelude::*;
 use core::ops::{Add, AddAssign};
 
-const CAM_DESIGN: (usize, usize) = Pack::chip_array();
 const BUFFER_SIZE: usize = 16384 * 2;
-const SR_TIME: usize = 10_000; //Time window (10_000 -> 10 us);
+const SR_TIME: Timestamp = Timestamp::new(6_400); //Time window (6_400 -> 10 us);
 const SR_INDEX: usize = 64; //Maximum x index value to account in the average calculation;
 const SR_MIN: usize = 0; //Minimum array size to perform the average in super resolution;
 const TILT_FRACTION: usize = 16; //Values with y = 256 will be tilted by 256 / 16;
@@ -70,7 +70,19 @@ pub trait GenerateDepth {
     gendepth!(gen8, u8);
 }
 genbitdepth!(u8, u16, u32);
-genall!(Live2D, Live1D, LiveTR2D, LiveTR1D, LiveTilted2D, FastChrono, Chrono, SuperResolution);
+genall!(Live2D, Live1D, LiveTR2D, LiveTR1D, LiveTilted2D, FastChrono, Chrono, SuperResolution, TimeDelay);
+
+///`LiveCoincidence` buffers the electrons and the photons of the reference TDC. At each frame, the
+///electrons old enough to have received their photon are paired using
+///`postlib::coincidence::TempTdcData`, and the others wait for the next frame. Photons are delayed
+///by `time_delay` and paired within `time_width` (ns). All times are extended, so the buffers are
+///kept ordered across rollovers.
+#[derive(Default)]
+pub struct LiveCoincidence {
+    electrons: Vec<(usize, Timestamp)>,
+    photons: TempTdcData,
+}
+impl GenerateDepth for LiveCoincidence{}
 
 pub trait BitDepth: Clone + Add<Output = Self> + Copy + AddAssign {
     fn zero() -> Self;
@@ -83,27 +95,32 @@ pub struct SpecMeasurement<T, K: BitDepth> {
     aux_data: Vec<usize>,
     is_ready: bool,
     global_stop: bool,
-    last_time: usize,
+    last_time: Timestamp,
     last_mean: Option<usize>,
-    _kind: T,
+    kind: T,
 }
 
 pub trait SpecKind {
     fn is_ready(&self) -> bool;
     fn build_output(&self) -> &[u8];
     fn new(settings: &Settings) -> Self;
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, ref_tdc: &T);
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, ref_tdc: &mut T);
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings);
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, frame_tdc: &PeriodicTdcRef, ref_tdc: &T);
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T);
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings);
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings);
+    ///Called instead of `reset_or_else` for the frames completed while the acquisition is paused.
+    fn skip_frame(&mut self, frame_tdc: &PeriodicTdcRef, settings: &Settings) {
+        self.reset_or_else(frame_tdc, settings);
+    }
 }
 
 macro_rules! tp3_vec {
-    ($x: expr) => {
+    ($layout: expr, $x: expr) => {
         {
+            let cam_design = $layout.chip_array();
             let len = match $x {
-                1 => CAM_DESIGN.0,
-                2 => CAM_DESIGN.1*CAM_DESIGN.0,
+                1 => cam_design.0,
+                2 => cam_design.1*cam_design.0,
                 _ => {panic!("One or two dimensions only!")},
             };
             let mut temp_vec: Vec<L> = vec![L::zero(); len+1];
@@ -121,20 +138,25 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Live2D, L> {
     fn build_output(&self) -> &[u8] {
         as_bytes(&self.data)
     }
-    fn new(_settings: &Settings) -> Self {
-        SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: Live2D }
+    fn new(settings: &Settings) -> Self {
+        SpecMeasurement{ data: tp3_vec!(settings.layout, 2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: Live2D }
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
-        let index = pack.x() + CAM_DESIGN.0 * pack.y();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        let (x, y) = match settings.layout.x_y(pack) {
+            Some(position) => position,
+            None => return,
+        };
+        let index = x + settings.layout.chip_array().0 * y;
         self.data[index] = self.data[index] + L::one();
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -153,20 +175,24 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Live1D, L> {
     fn build_output(&self) -> &[u8] {
         as_bytes(&self.data)
     }
-    fn new(_settings: &Settings) -> Self {
-        SpecMeasurement{ data: tp3_vec!(1), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: Live1D}
+    fn new(settings: &Settings) -> Self {
+        SpecMeasurement{ data: tp3_vec!(settings.layout, 1), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: Live1D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
-        let index = pack.x();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        let index = match settings.layout.x(pack) {
+            Some(x) => x,
+            None => return,
+        };
         self.data[index] = self.data[index] + L::one();
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -185,21 +211,23 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTR2D, L> {
     fn build_output(&self) -> &[u8] {
         as_bytes(&self.data)
     }
-    fn new(_settings: &Settings) -> Self {
-        SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: LiveTR2D}
+    fn new(settings: &Settings) -> Self {
+        SpecMeasurement{ data: tp3_vec!(settings.layout, 2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: LiveTR2D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
-        if LiveTR1D::tr_check_if_in(pack.electron_time(), ref_tdc, settings) {
-            let index = pack.x() + CAM_DESIGN.0 * pack.y();
-            self.data[index] = self.data[index] + L::one();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
+        if LiveTR1D::tr_check_if_in(time, ref_tdc, settings) {
+            if let Some((x, y)) = settings.layout.x_y(pack) {
+                let index = x + settings.layout.chip_array().0 * y;
+                self.data[index] = self.data[index] + L::one();
+            }
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -218,22 +246,23 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTR1D, L> {
     fn build_output(&self) -> &[u8] {
         as_bytes(&self.data)
     }
-    fn new(_settings: &Settings) -> Self {
-        SpecMeasurement{ data: tp3_vec!(1), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: LiveTR1D}
+    fn new(settings: &Settings) -> Self {
+        SpecMeasurement{ data: tp3_vec!(settings.layout, 1), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: LiveTR1D}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
-        if LiveTR1D::tr_check_if_in(pack.electron_time(), ref_tdc, settings) {
-            let index = pack.x();
-            //append_to_array(&mut self.data, index, settings.bytedepth);
-            self.data[index] = self.data[index] + L::one();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
+        if LiveTR1D::tr_check_if_in(time, ref_tdc, settings) {
+            if let Some(index) = settings.layout.x(pack) {
+                //append_to_array(&mut self.data, index, settings.bytedepth);
+                self.data[index] = self.data[index] + L::one();
+            }
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -252,22 +281,25 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<LiveTilted2D, L> {
     fn build_output(&self) -> &[u8] {
         as_bytes(&self.data)
     }
-    fn new(_settings: &Settings) -> Self {
-        SpecMeasurement{ data: tp3_vec!(2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: LiveTilted2D }
+    fn new(settings: &Settings) -> Self {
+        SpecMeasurement{ data: tp3_vec!(settings.layout, 2), aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: LiveTilted2D }
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
-        let x = pack.x();
-        let y = pack.y();
-        let index = x + CAM_DESIGN.0 * y;
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        let (x, y) = match settings.layout.x_y(pack) {
+            Some(position) => position,
+            None => return,
+        };
+        let index = x + settings.layout.chip_array().0 * y;
         self.data[index] = self.data[index] + L::one();
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
@@ -287,31 +319,38 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<FastChrono, L> {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len: usize = settings.xspim_size*CAM_DESIGN.0;
+        let len: usize = settings.xspim_size*settings.layout.chip_array().0;
         let mut temp_vec = vec![L::zero(); len + 1];
     //type MeasKind;
         temp_vec[len] = L::ten();
-        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: FastChrono}
+        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: FastChrono}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let line = frame_tdc.counter()/2;
-        let index = pack.x() + line * CAM_DESIGN.0;
+        let x = match settings.layout.x(pack) {
+            Some(x) => x,
+            None => return,
+        };
+        let index = x + line * settings.layout.chip_array().0;
         if line < settings.xspim_size {
             self.data[index] = self.data[index] + L::one();
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = (frame_tdc.counter()/2) > settings.xspim_size;
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, _settings: &Settings) {
         self.global_stop = true;
     }
+    ///The chrono is kept, and sent once the acquisition resumes.
+    fn skip_frame(&mut self, _frame_tdc: &PeriodicTdcRef, _settings: &Settings) {}
 }
 
 impl<L: BitDepth> SpecKind for SpecMeasurement<Chrono, L> {
@@ -322,28 +361,33 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Chrono, L> {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len: usize = settings.xspim_size*CAM_DESIGN.0;
+        let len: usize = settings.xspim_size*settings.layout.chip_array().0;
         let mut temp_vec = vec![L::zero(); len + 1];
         temp_vec[len] = L::ten();
-        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: Chrono}
+        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: Chrono}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
         let line = (frame_tdc.counter()/2) % settings.xspim_size;
-        let index = pack.x() + line * CAM_DESIGN.0;
+        let x = match settings.layout.x(pack) {
+            Some(x) => x,
+            None => return,
+        };
+        let index = x + line * settings.layout.chip_array().0;
         self.data[index] = self.data[index] + L::one();
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         let line = frame_tdc.counter() / 2;
         self.is_ready = line % 20 == 0; //Every 20 lines send chrono;
         if line % settings.xspim_size == 0 {
             self.aux_data.push(0); //This indicates the frame must be refreshed;
         }
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, _settings: &Settings) {
         self.is_ready = false;
@@ -355,6 +399,118 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<Chrono, L> {
     }
 }
 
+///Each line is a bin of the delay of the electrons to the preceding laser trigger. The
+///`xspim_size` bins cover `time_width` (ns), starting at `time_delay`.
+impl<L: BitDepth> SpecKind for SpecMeasurement<TimeDelay, L> {
+    fn is_ready(&self) -> bool {
+        self.is_ready
+    }
+    fn build_output(&self) -> &[u8] {
+        as_bytes(&self.data)
+    }
+    fn new(settings: &Settings) -> Self {
+        let len: usize = settings.xspim_size*settings.layout.chip_array().0;
+        let mut temp_vec = vec![L::zero(); len + 1];
+        temp_vec[len] = L::ten();
+        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: TimeDelay}
+    }
+    #[inline]
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, ref_tdc: &T) {
+        let x = match settings.layout.x(pack) {
+            Some(x) => x,
+            None => return,
+        };
+        let trigger = match tdclib::preceding_trigger(time, ref_tdc.time(), ref_tdc.period()) {
+            Some(trigger) => trigger,
+            None => return,
+        };
+        let time_delay = Timestamp::from_nanoseconds(settings.time_delay as u64);
+        let time_width = Timestamp::from_nanoseconds(settings.time_width as u64);
+        let delay = time - trigger;
+        if delay >= time_delay && delay < time_delay + time_width {
+            let line = (delay - time_delay) * settings.xspim_size as u64 / time_width;
+            let index = x + line as usize * settings.layout.chip_array().0;
+            self.data[index] += L::one();
+        }
+    }
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, _settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+    }
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
+        self.is_ready = true;
+    }
+    fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
+        self.is_ready = false;
+        if !settings.cumul {
+            self.data.iter_mut().for_each(|x| *x = L::zero());
+            *self.data.iter_mut().last().expect("SpecKind: Last value is none.") = L::ten();
+        }
+    }
+}
+
+///Three lines are sent: the spectrum, the coincident spectrum and the histogram of the electron
+///time to its photon, centered in the line (in units of 640 MHz).
+impl<L: BitDepth> SpecKind for SpecMeasurement<LiveCoincidence, L> {
+    fn is_ready(&self) -> bool {
+        self.is_ready
+    }
+    fn build_output(&self) -> &[u8] {
+        as_bytes(&self.data)
+    }
+    fn new(settings: &Settings) -> Self {
+        let len: usize = 3*settings.layout.chip_array().0;
+        let mut temp_vec = vec![L::zero(); len + 1];
+        temp_vec[len] = L::ten();
+        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: LiveCoincidence::default()}
+    }
+    #[inline]
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        if let Some(x) = settings.layout.x(pack) {
+            self.kind.electrons.push((x, time));
+        }
+    }
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
+        self.kind.photons.add_tdc(time);
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] += L::one();
+    }
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
+        self.is_ready = true;
+
+        let width = settings.layout.chip_array().0;
+        let time_delay = Timestamp::from_nanoseconds(settings.time_delay as u64);
+        let time_width = Timestamp::from_nanoseconds(settings.time_width as u64);
+        let horizon = frame_tdc.time().saturating_sub(time_delay + time_width);
+        let coinc = &mut self.kind;
+        coinc.photons.set_window(time_delay, time_width);
+        coinc.photons.sort();
+        coinc.electrons.sort_unstable_by_key(|&(_, time)| time);
+        let ready = coinc.electrons.partition_point(|&(_, time)| time <= horizon);
+        for &(x, time) in &coinc.electrons[..ready] {
+            self.data[x] += L::one();
+            if let Some(pht) = coinc.photons.check(time) {
+                self.data[width + x] += L::one();
+                let dt = time.ticks() as i64 - pht.ticks() as i64 + (width / 2) as i64;
+                if dt >= 0 && (dt as usize) < width {
+                    self.data[2 * width + dt as usize] += L::one();
+                }
+            }
+        }
+        coinc.electrons.drain(..ready);
+        coinc.photons.discard_before(horizon);
+    }
+    fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
+        self.is_ready = false;
+        if !settings.cumul {
+            self.data.iter_mut().for_each(|x| *x = L::zero());
+            *self.data.iter_mut().last().expect("SpecKind: Last value is none.") = L::ten();
+        }
+    }
+}
+
 impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
     fn is_ready(&self) -> bool {
         self.is_ready
@@ -363,17 +519,20 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
        as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len: usize = settings.bytedepth*CAM_DESIGN.0;
+        let len: usize = settings.bytedepth*settings.layout.chip_array().0;
         let mut temp_vec = vec![L::zero(); len + 1];
         temp_vec[len] = L::ten();
-        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: 0, last_mean: None, _kind: SuperResolution}
+        SpecMeasurement{ data: temp_vec, aux_data: Vec::new(), is_ready: false, global_stop: false, last_time: Timestamp::zero(), last_mean: None, kind: SuperResolution}
     }
     #[inline]
-    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
-        let index = pack.x();
+    fn add_electron_hit<T: TdcControl>(&mut self, pack: &Pack, _time: Timestamp, settings: &Settings, _frame_tdc: &PeriodicTdcRef, _ref_tdc: &T) {
+        let index = match settings.layout.x(pack) {
+            Some(x) => x,
+            None => return,
+        };
         self.aux_data.push(index);
         
-        let new_time = pack.fast_electron_time();
+        let new_time = Timestamp::new(pack.fast_electron_time() as u64);
         if new_time > self.last_time + SR_TIME {
             let len = self.aux_data.iter().filter(|&&val| val <= SR_INDEX).count();
             let sum: usize = self.aux_data.iter().filter(|&&val| val <= SR_INDEX).sum();
@@ -412,14 +571,15 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
             self.aux_data = Vec::new();
         }
     }
-    fn upt_frame(&mut self, pack: &Pack, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
-        frame_tdc.upt(pack.tdc_time(), pack.tdc_counter());
+    fn upt_frame(&mut self, pack: &Pack, time: Timestamp, frame_tdc: &mut PeriodicTdcRef, _settings: &Settings) {
+        frame_tdc.upt(time, pack.tdc_counter());
         self.is_ready = true;
     }
-    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, _settings: &Settings, ref_tdc: &mut T) {
-        ref_tdc.upt(pack.tdc_time_norm(), pack.tdc_counter());
+    fn add_tdc_hit<T: TdcControl>(&mut self, pack: &Pack, time: Timestamp, settings: &Settings, ref_tdc: &mut T) {
+        ref_tdc.upt(time, pack.tdc_counter());
         //append_to_array(&mut self.data, CAM_DESIGN.0-1, settings.bytedepth);
-        self.data[CAM_DESIGN.0-1] = self.data[CAM_DESIGN.0-1] + L::one();
+        let tdc_index = settings.layout.chip_array().0-1;
+        self.data[tdc_index] = self.data[tdc_index] + L::one();
     }
     fn reset_or_else(&mut self, _frame_tdc: &PeriodicTdcRef, settings: &Settings) {
         self.is_ready = false;
@@ -431,19 +591,11 @@ impl<L: BitDepth> SpecKind for SpecMeasurement<SuperResolution, L> {
 }
 
 impl LiveTR1D {
-    fn tr_check_if_in<T: TdcControl>(ele_time: usize, ref_tdc: &T, settings: &Settings) -> bool {
+    fn tr_check_if_in<T: TdcControl>(ele_time: Timestamp, ref_tdc: &T, settings: &Settings) -> bool {
         let period = ref_tdc.period().expect("Period must exist in LiveTR1D.");
-        let last_time = ref_tdc.time();
-
-        let eff_tdc = if last_time > ele_time {
-            let xper = (last_time - ele_time) / period + 1;
-            last_time - xper * period
-        } else {
-            let xper = (ele_time - last_time) / period;
-            last_time + xper * period
-        };
-
-        ele_time > eff_tdc + settings.time_delay && ele_time < eff_tdc + settings.time_delay + settings.time_width
+        let time_delay = Timestamp::from_nanoseconds(settings.time_delay as u64);
+        let time_width = Timestamp::from_nanoseconds(settings.time_width as u64);
+        tdclib::is_in_gate(ele_time, ref_tdc.time(), Some(period), time_delay, time_width)
 
     }
 }
@@ -452,7 +604,7 @@ impl LiveTR1D {
 ///Reads timepix3 socket and writes in the output socket a header and a full frame (binned or not). A periodic tdc is mandatory in order to define frame time.
 ///
 ///# Examples
-pub fn run_spectrum<T, V, U, Y>(pack: V, ns: U, my_settings: Settings, frame_tdc: PeriodicTdcRef, np_tdc: T, kind: Y) -> Result<u8, Tp3ErrorKind>
+pub fn run_spectrum<T, V, U, Y>(pack: V, ns: U, my_settings: Settings, frame_tdc: PeriodicTdcRef, np_tdc: T, kind: Y, control: AcquisitionControl) -> Result<u8, Tp3ErrorKind>
     where T: TdcControl,
           V: TimepixRead,
           U: Write,
@@ -465,101 +617,119 @@ pub fn run_spectrum<T, V, U, Y>(pack: V, ns: U, my_settings: Settings, frame_tdc
     match my_settings.bytedepth {
         1 => {
             let measurement = kind.gen8(&my_settings);
-            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement)?;
+            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement, control)?;
         },
         2 => {
             let measurement = kind.gen16(&my_settings);
-            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement)?;
+            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement, control)?;
         },
         4 => {
             let measurement = kind.gen32(&my_settings);
-            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement)?;
+            build_spectrum(pack, ns, my_settings, frame_tdc, np_tdc, measurement, control)?;
         },
-        _ => {return Err(Tp3ErrorKind::SetByteDepth)},
+        _ => {return Err(Tp3ErrorKind::SetByteDepth(my_settings.bytedepth))},
     }
     Ok(my_settings.mode)
 }
     
-fn build_spectrum<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: PeriodicTdcRef, mut ref_tdc: T, mut meas_type: W) -> Result<(), Tp3ErrorKind> 
+fn build_spectrum<T, V, U, W>(mut pack_sock: V, mut ns_sock: U, mut my_settings: Settings, mut frame_tdc: PeriodicTdcRef, mut ref_tdc: T, mut meas_type: W, mut control: AcquisitionControl) -> Result<(), Tp3ErrorKind> 
     where T: TdcControl,
           V: TimepixRead,
           U: Write,
           W: SpecKind
 {
     
-    let mut last_ci = 0usize;
+    let mut stream = PacketStream::new();
+    let mut time_ext = TimeExtension::new();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
     
     //let mut list = Live::new(&my_settings);
     let start = Instant::now();
 
     while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-        if build_data(&buffer_pack_data[0..size], &mut meas_type, &mut last_ci, &my_settings, &mut frame_tdc, &mut ref_tdc) {
-            let msg = create_header(&my_settings, &frame_tdc);
-            if ns_sock.write(&msg).is_err() {println!("Client disconnected on header."); break;}
+        if control.apply(&mut my_settings) {
+            clear_data(&mut meas_type, &frame_tdc, &my_settings);
+        }
+        if control.is_stopped() {println!("Acquisition stopped by the client."); break;}
+        if build_data(stream.events(&buffer_pack_data[0..size]), &mut meas_type, &mut time_ext, &my_settings, &mut frame_tdc, &mut ref_tdc, control.is_paused()) {
+            if control.is_paused() {
+                meas_type.skip_frame(&frame_tdc, &my_settings);
+                continue;
+            }
+            if my_settings.output == OutputFormat::HeaderAndFrame {
+                let msg = create_header(&my_settings, &frame_tdc);
+                if ns_sock.write(&msg).is_err() {println!("Client disconnected on header."); break;}
+            }
             if ns_sock.write(meas_type.build_output()).is_err() {println!("Client disconnected on data."); break;}
             meas_type.reset_or_else(&frame_tdc, &my_settings);
             if frame_tdc.counter() % 1000 == 0 { let elapsed = start.elapsed(); println!("Total elapsed time is: {:?}. Counter is {}.", elapsed, frame_tdc.counter());};
         }
     }
     println!("Total elapsed time is: {:?}.", start.elapsed());
+    println!("Packet statistics: {}", stream.statistics());
     Ok(())
 
 }
 
-fn build_data<T: TdcControl, W: SpecKind>(data: &[u8], final_data: &mut W, last_ci: &mut usize, settings: &Settings, frame_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T) -> bool {
-
-    data.chunks_exact(8).for_each( |x| {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci as usize,
-            _ => {
-                let packet = Pack { chip_index: *last_ci, data: x.try_into().unwrap()};
-                
-                match packet.id() {
-                    11 => {
-                        final_data.add_electron_hit(&packet, settings, frame_tdc, ref_tdc);
-                    },
-                    6 if packet.tdc_type() == frame_tdc.id() => {
-                        final_data.upt_frame(&packet, frame_tdc, settings);
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id() => {
-                        final_data.add_tdc_hit(&packet, settings, ref_tdc);
-                    },
-                    _ => {},
-                };
+///Clears the accumulated data, as a reset without cumulation does.
+fn clear_data<W: SpecKind>(meas_type: &mut W, frame_tdc: &PeriodicTdcRef, settings: &Settings) {
+    let mut settings = *settings;
+    settings.cumul = false;
+    meas_type.reset_or_else(frame_tdc, &settings);
+}
+
+///Electrons are discarded while the acquisition is paused, but TDCs are followed so frames keep
+///their timing. Times are extended by `time_ext`, so they can be compared across rollovers.
+fn build_data<T: TdcControl, W: SpecKind>(events: Events, final_data: &mut W, time_ext: &mut TimeExtension, settings: &Settings, frame_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T, paused: bool) -> bool {
+
+    events.for_each( |event| {
+        match event {
+            Event::Electron(packet) => {
+                let time = time_ext.electron_time(&packet);
+                if !paused && settings.in_roi(&packet) {
+                    final_data.add_electron_hit(&packet, time, settings, frame_tdc, ref_tdc);
+                }
+            },
+            Event::Tdc(packet) if packet.tdc_type() == frame_tdc.id() => {
+                let time = time_ext.tdc_time(&packet);
+                final_data.upt_frame(&packet, time, frame_tdc, settings);
+            },
+            Event::Tdc(packet) if packet.tdc_type() == ref_tdc.id() => {
+                let time = time_ext.tdc_time(&packet);
+                final_data.add_tdc_hit(&packet, time, settings, ref_tdc);
             },
+            Event::GlobalTime(packet) => {
+                time_ext.upt_global(&packet);
+            },
+            _ => {},
         };
     });
     final_data.is_ready()
 }
 
+///Number of lines of the frames sent to the client.
+fn frame_height(set: &Settings) -> usize {
+    match set.mode {
+        6..=8 => set.xspim_size, //ChronoMode and TimeDelay
+        9 => 3, //LiveCoincidence
+        _ if set.bin => 1,
+        _ => set.layout.chip_array().1,
+    }
+}
+
 fn create_header<T: TdcControl>(set: &Settings, tdc: &T) -> Vec<u8> {
     let mut msg: String = String::from("{\"timeAtFrame\":");
-    msg.push_str(&(tdc.time().to_string()));
+    msg.push_str(&(tdc.time().ticks().to_string()));
     msg.push_str(",\"frameNumber\":");
     msg.push_str(&((tdc.counter()/2).to_string()));
     msg.push_str(",\"measurementID:\"Null\",\"dataSize\":");
-    if set.mode == 6 || set.mode == 7 { //ChronoMode
-        msg.push_str(&((set.xspim_size*set.bytedepth*CAM_DESIGN.0).to_string()));
-    } else {
-        match set.bin {
-            true => { msg.push_str(&((set.bytedepth*CAM_DESIGN.0).to_string()))},
-            false => { msg.push_str(&((set.bytedepth*CAM_DESIGN.0*CAM_DESIGN.1).to_string()))},
-        }
-    }
+    msg.push_str(&((set.bytedepth*set.layout.chip_array().0*frame_height(set)).to_string()));
     msg.push_str(",\"bitDepth\":");
     msg.push_str(&((set.bytedepth<<3).to_string()));
     msg.push_str(",\"width\":");
-    msg.push_str(&(CAM_DESIGN.0.to_string()));
+    msg.push_str(&(set.layout.chip_array().0.to_string()));
     msg.push_str(",\"height\":");
-    if set.mode == 6 || set.mode == 7 { //ChronoMode
-        msg.push_str(&(set.xspim_size.to_string()));
-    } else {
-        match set.bin {
-            true=>{msg.push_str(&(1.to_string()))},
-            false=>{msg.push_str(&(CAM_DESIGN.1.to_string()))},
-        }
-    }
+    msg.push_str(&(frame_height(set).to_string()));
     msg.push_str("}\n");
 
     let s: Vec<u8> = msg.into_bytes();
@@ -1,18 +1,17 @@
 //!`spimlib` is a collection of tools to set hyperspectral EELS acquisition.
 
-use crate::packetlib::{Packet, PacketEELS};
+use crate::packetlib::{Packet, PacketEELS, PacketStream, Event, TimeExtension, Timestamp};
 use crate::auxiliar::{Settings, misc::TimepixRead};
-use crate::tdclib::{TdcControl, PeriodicTdcRef};
+use crate::tdclib::{self, TdcControl, PeriodicTdcRef};
 use crate::errorlib::Tp3ErrorKind;
+use crate::controllib::AcquisitionControl;
 use std::time::Instant;
 use std::io::{Write};
 use std::sync::mpsc;
 use std::thread;
-use std::convert::TryInto;
 //use rayon::prelude::*;
 
-const VIDEO_TIME: usize = 5000;
-pub const SPIM_PIXELS: usize = 1025 + 16;
+const VIDEO_TIME: Timestamp = Timestamp::new(5000);
 const BUFFER_SIZE: usize = 16384 * 2;
 
 
@@ -31,28 +30,30 @@ pub trait SpimKind {
     type MyOutput;
 
     fn data(&self) -> &Vec<Self::MyOutput>;
-    fn add_electron_hit(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef);
-    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T);
-    fn upt_line(&self, packet: &PacketEELS, settings: &Settings, line_tdc: &mut PeriodicTdcRef);
+    fn add_electron_hit(&mut self, packet: &PacketEELS, time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef);
+    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T);
+    fn upt_line(&self, packet: &PacketEELS, time: Timestamp, settings: &Settings, line_tdc: &mut PeriodicTdcRef);
     fn check(&self) -> bool;
     fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<usize>;
     fn copy_empty(&self) -> Self;
     fn new() -> Self;
+    ///Called once, with the reference TDC found before the acquisition.
+    fn init_reference<T: TdcControl>(&mut self, _ref_tdc: &T) {}
 }
 
 #[inline]
-pub fn get_return_spimindex(x: usize, dt: usize, spim_tdc: &PeriodicTdcRef, xspim: usize, yspim: usize) -> Option<usize> {
+pub fn get_return_spimindex(x: usize, dt: Timestamp, spim_tdc: &PeriodicTdcRef, xspim: usize, yspim: usize, spim_pixels: usize) -> Option<usize> {
     let val = dt % spim_tdc.period;
     if val >= spim_tdc.low_time {
-        let mut r = dt / spim_tdc.period; //how many periods -> which line to put.
-        let rin = xspim * (val-spim_tdc.low_time) / spim_tdc.high_time; //Column correction. Maybe not even needed.
+        let mut r = (dt / spim_tdc.period) as usize; //how many periods -> which line to put.
+        let rin = ((val-spim_tdc.low_time) * xspim as u64 / spim_tdc.high_time) as usize; //Column correction. Maybe not even needed.
             
             if r > (yspim-1) {
                 if r > 4096 {return None;} //This removes overflow electrons. See add_electron_hit
                 r %= yspim;
             }
             
-            let index = (r * xspim + rin) * SPIM_PIXELS + x;
+            let index = (r * xspim + rin) * spim_pixels + x;
         
             Some(index)
         } else {
@@ -61,18 +62,18 @@ pub fn get_return_spimindex(x: usize, dt: usize, spim_tdc: &PeriodicTdcRef, xspi
 }
 
 #[inline]
-pub fn get_spimindex(x: usize, dt: usize, spim_tdc: &PeriodicTdcRef, xspim: usize, yspim: usize) -> Option<usize> {
+pub fn get_spimindex(x: usize, dt: Timestamp, spim_tdc: &PeriodicTdcRef, xspim: usize, yspim: usize, spim_pixels: usize) -> Option<usize> {
     let val = dt % spim_tdc.period;
     if val < spim_tdc.low_time {
-        let mut r = dt / spim_tdc.period; //how many periods -> which line to put.
-        let rin = xspim * val / spim_tdc.low_time; //Column correction. Maybe not even needed.
+        let mut r = (dt / spim_tdc.period) as usize; //how many periods -> which line to put.
+        let rin = (val * xspim as u64 / spim_tdc.low_time) as usize; //Column correction. Maybe not even needed.
             
             if r > (yspim-1) {
                 if r > 4096 {return None;} //This removes overflow electrons. See add_electron_hit
                 r %= yspim;
             }
             
-            let index = (r * xspim + rin) * SPIM_PIXELS + x;
+            let index = (r * xspim + rin) * spim_pixels + x;
         
             Some(index)
         } else {
@@ -83,32 +84,32 @@ pub fn get_spimindex(x: usize, dt: usize, spim_tdc: &PeriodicTdcRef, xspim: usiz
 ///`Live` is the only current implemented measurement. It outputs list of indices (max `u32`) that
 ///must be incremented.
 pub struct Live {
-    data: Vec<(usize, usize)>,
+    data: Vec<(usize, Timestamp)>,
 }
 
 impl SpimKind for Live {
-    type MyOutput = (usize, usize);
+    type MyOutput = (usize, Timestamp);
 
-    fn data(&self) -> &Vec<(usize, usize)> {
+    fn data(&self) -> &Vec<(usize, Timestamp)> {
         &self.data
     }
 
     #[inline]
-    fn add_electron_hit(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef) {
-        let ele_time = packet.electron_time();
-        self.data.push((packet.x(), ele_time - line_tdc.begin_frame - VIDEO_TIME)); //This added the overflow.
+    fn add_electron_hit(&mut self, packet: &PacketEELS, ele_time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef) {
+        if let Some(x) = settings.layout.x(packet) {
+            self.data.push((x, ele_time.wrapping_sub(line_tdc.begin_frame + VIDEO_TIME))); //Electrons before the frame are wrapped and then removed in build_output.
+        }
     }
     
-    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T) {
-        let tdc_time = packet.tdc_time_norm();
+    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, tdc_time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T) {
         ref_tdc.upt(tdc_time, packet.tdc_counter());
         if tdc_time > line_tdc.begin_frame + VIDEO_TIME {
-            self.data.push((SPIM_PIXELS-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
+            self.data.push((settings.layout.spim_pixels()-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
         }
     }
 
-    fn upt_line(&self, packet: &PacketEELS, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
-        line_tdc.upt(packet.tdc_time_norm(), packet.tdc_counter());
+    fn upt_line(&self, packet: &PacketEELS, tdc_time: Timestamp, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        line_tdc.upt(tdc_time, packet.tdc_counter());
     }
 
     fn check(&self) -> bool {
@@ -125,9 +126,9 @@ impl SpimKind for Live {
         //index = line * xspim + column
         //
         //To find the actuall index value, one multiply this value by the number of signal pixels
-        //(the spectra) because every spatial point has SPIM_PIXELS channels.
+        //(the spectra) because every spatial point has `spim_pixels` channels.
         //
-        //index = index * SPIM_PIXELS
+        //index = index * spim_pixels
         //
         //With this, we place every electron in the first channel of the signal dimension. We must
         //thus add the pixel address to correct reconstruct the spectral image
@@ -137,7 +138,7 @@ impl SpimKind for Live {
         
         let my_vec = self.data.iter()
             .filter_map(|&(x, dt)| {
-                get_spimindex(x, dt, spim_tdc, set.xspim_size, set.yspim_size)
+                get_spimindex(x, dt, spim_tdc, set.xspim_size, set.yspim_size, set.layout.spim_pixels())
             }).collect::<Vec<usize>>();
 
         my_vec
@@ -152,24 +153,157 @@ impl SpimKind for Live {
     }
 }
 
+///`LiveTimeResolved` is the time-resolved `Live` measurement. Only electrons arriving between
+///`time_delay` and `time_delay + time_width` (ns) after a trigger of the reference TDC are kept.
+///With a periodic reference, such as a `SingleTriggerPeriodicTdcRef` laser, each electron is
+///compared to its preceding trigger. Otherwise, to the last one received.
+pub struct LiveTimeResolved {
+    data: Vec<(usize, Timestamp)>,
+    last_ref: Option<Timestamp>,
+    period: Option<Timestamp>,
+}
+
+impl SpimKind for LiveTimeResolved {
+    type MyOutput = (usize, Timestamp);
+
+    fn data(&self) -> &Vec<(usize, Timestamp)> {
+        &self.data
+    }
+
+    #[inline]
+    fn add_electron_hit(&mut self, packet: &PacketEELS, ele_time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef) {
+        let last_ref = match self.last_ref {
+            Some(time) => time,
+            None => return,
+        };
+        let time_delay = Timestamp::from_nanoseconds(settings.time_delay as u64);
+        let time_width = Timestamp::from_nanoseconds(settings.time_width as u64);
+        if tdclib::is_in_gate(ele_time, last_ref, self.period, time_delay, time_width) {
+            if let Some(x) = settings.layout.x(packet) {
+                self.data.push((x, ele_time.wrapping_sub(line_tdc.begin_frame + VIDEO_TIME)));
+            }
+        }
+    }
+    
+    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, tdc_time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T) {
+        ref_tdc.upt(tdc_time, packet.tdc_counter());
+        self.last_ref = Some(tdc_time);
+        self.period = ref_tdc.period();
+        if tdc_time > line_tdc.begin_frame + VIDEO_TIME {
+            self.data.push((settings.layout.spim_pixels()-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
+        }
+    }
+
+    fn upt_line(&self, packet: &PacketEELS, tdc_time: Timestamp, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        line_tdc.upt(tdc_time, packet.tdc_counter());
+    }
+
+    fn check(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    #[inline]
+    fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<usize> {
+        self.data.iter()
+            .filter_map(|&(x, dt)| {
+                get_spimindex(x, dt, spim_tdc, set.xspim_size, set.yspim_size, set.layout.spim_pixels())
+            }).collect::<Vec<usize>>()
+    }
+
+    ///The reference is kept, as the gate of the next electrons depends on it.
+    fn copy_empty(&self) -> Self {
+        LiveTimeResolved{ data: Vec::with_capacity(BUFFER_SIZE / 8), last_ref: self.last_ref, period: self.period }
+    }
+
+    fn new() -> Self {
+        LiveTimeResolved{ data: Vec::with_capacity(BUFFER_SIZE / 8), last_ref: None, period: None }
+    }
+
+    ///A periodic reference already knows its last trigger and its period, so the electrons are
+    ///gated before the first trigger of the acquisition.
+    fn init_reference<T: TdcControl>(&mut self, ref_tdc: &T) {
+        self.period = ref_tdc.period();
+        if self.period.is_some() {
+            self.last_ref = Some(ref_tdc.time());
+        }
+    }
+}
+
+///`LiveTdc` images the reference TDC instead of the electrons. Every TDC hit is placed in the
+///last channel of the spectrum of its scan position.
+pub struct LiveTdc {
+    data: Vec<(usize, Timestamp)>,
+}
+
+impl SpimKind for LiveTdc {
+    type MyOutput = (usize, Timestamp);
+
+    fn data(&self) -> &Vec<(usize, Timestamp)> {
+        &self.data
+    }
+
+    #[inline]
+    fn add_electron_hit(&mut self, _packet: &PacketEELS, _ele_time: Timestamp, _settings: &Settings, _line_tdc: &PeriodicTdcRef) {}
+    
+    fn add_tdc_hit<T: TdcControl>(&mut self, packet: &PacketEELS, tdc_time: Timestamp, settings: &Settings, line_tdc: &PeriodicTdcRef, ref_tdc: &mut T) {
+        ref_tdc.upt(tdc_time, packet.tdc_counter());
+        if tdc_time > line_tdc.begin_frame + VIDEO_TIME {
+            self.data.push((settings.layout.spim_pixels()-1, tdc_time - line_tdc.begin_frame - VIDEO_TIME))
+        }
+    }
+
+    fn upt_line(&self, packet: &PacketEELS, tdc_time: Timestamp, _settings: &Settings, line_tdc: &mut PeriodicTdcRef) {
+        line_tdc.upt(tdc_time, packet.tdc_counter());
+    }
+
+    fn check(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    #[inline]
+    fn build_output(&self, set: &Settings, spim_tdc: &PeriodicTdcRef) -> Vec<usize> {
+        self.data.iter()
+            .filter_map(|&(x, dt)| {
+                get_spimindex(x, dt, spim_tdc, set.xspim_size, set.yspim_size, set.layout.spim_pixels())
+            }).collect::<Vec<usize>>()
+    }
+
+    fn copy_empty(&self) -> Self {
+        LiveTdc{ data: Vec::with_capacity(BUFFER_SIZE / 64) }
+    }
+
+    fn new() -> Self {
+        LiveTdc{ data: Vec::with_capacity(BUFFER_SIZE / 64) }
+    }
+}
+
 ///Reads timepix3 socket and writes in the output socket a list of frequency followed by a list of unique indexes. First TDC must be a periodic reference, while the second can be nothing, periodic tdc or a non periodic tdc.
-pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut spim_tdc: PeriodicTdcRef, mut ref_tdc: T, meas_type: W) -> Result<(), Tp3ErrorKind>
+///Commands are applied by the reading thread. As indexes are accumulated by the client, there is
+///nothing to clear here.
+pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, mut my_settings: Settings, mut spim_tdc: PeriodicTdcRef, mut ref_tdc: T, meas_type: W, mut control: AcquisitionControl) -> Result<(), Tp3ErrorKind>
     where V: 'static + Send + TimepixRead,
           T: 'static + Send + TdcControl,
           W: 'static + Send + SpimKind,
           U: 'static + Send + Write,
 {
     let (tx, rx) = mpsc::channel();
-    let mut last_ci = 0usize;
+    let mut stream = PacketStream::new();
+    let mut time_ext = TimeExtension::new();
     let mut buffer_pack_data = [0; BUFFER_SIZE];
-    let mut list = meas_type.copy_empty();
+    let mut list = meas_type;
+    list.init_reference(&ref_tdc);
     
     thread::spawn(move || {
         while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-            build_spim_data(&mut list, &buffer_pack_data[0..size], &mut last_ci, &my_settings, &mut spim_tdc, &mut ref_tdc);
+            control.apply(&mut my_settings);
+            if control.is_stopped() {println!("Acquisition stopped by the client."); break;}
+            build_spim_data(&mut list, &buffer_pack_data[0..size], &mut stream, &mut time_ext, &my_settings, &mut spim_tdc, &mut ref_tdc);
+            if control.is_paused() {list = list.copy_empty(); continue;} //Lines are followed, but nothing is sent.
+            let next = list.copy_empty();
             if tx.send(list).is_err() {println!("Cannot send data over the thread channel."); break;}
-            list = meas_type.copy_empty();
+            list = next;
         }
+        println!("Packet statistics: {}", stream.statistics());
     });
  
     let start = Instant::now();
@@ -183,27 +317,28 @@ pub fn build_spim<V, T, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Set
     Ok(())
 }
 
-fn build_spim_data<T: TdcControl, W: SpimKind>(list: &mut W, data: &[u8], last_ci: &mut usize, settings: &Settings, line_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T) {
-
-    data.chunks_exact(8).for_each(|x| {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci as usize,
-            _ => {
-                let packet = PacketEELS { chip_index: *last_ci, data: x.try_into().unwrap()};
-                let id = packet.id();
-                match id {
-                    11 => {
-                        list.add_electron_hit(&packet, line_tdc);
-                    },
-                    6 if packet.tdc_type() == line_tdc.id() => {
-                        list.upt_line(&packet, settings, line_tdc);
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id()=> {
-                        list.add_tdc_hit(&packet, line_tdc, ref_tdc);
-                    },
-                    _ => {},
-                };
+fn build_spim_data<T: TdcControl, W: SpimKind>(list: &mut W, data: &[u8], stream: &mut PacketStream, time_ext: &mut TimeExtension, settings: &Settings, line_tdc: &mut PeriodicTdcRef, ref_tdc: &mut T) {
+
+    stream.events(data).for_each(|event| {
+        match event {
+            Event::Electron(packet) => {
+                let time = time_ext.electron_time(&packet);
+                if settings.in_roi(&packet) {
+                    list.add_electron_hit(&packet, time, settings, line_tdc);
+                }
+            },
+            Event::Tdc(packet) if packet.tdc_type() == line_tdc.id() => {
+                let time = time_ext.tdc_time(&packet);
+                list.upt_line(&packet, time, settings, line_tdc);
+            },
+            Event::Tdc(packet) if packet.tdc_type() == ref_tdc.id()=> {
+                let time = time_ext.tdc_time(&packet);
+                list.add_tdc_hit(&packet, time, settings, line_tdc, ref_tdc);
+            },
+            Event::GlobalTime(packet) => {
+                time_ext.upt_global(&packet);
             },
+            _ => {},
         };
     });
 }
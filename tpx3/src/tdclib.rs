@@ -5,11 +5,11 @@
 mod tdcvec {
     use crate::errorlib::Tp3ErrorKind;
     use crate::tdclib::TdcType;
-    use crate::packetlib::{Packet, PacketEELS as Pack};
-    use std::convert::TryInto;
+    use crate::packetlib::{Packet, PacketEELS as Pack, PacketStream, Event, Timestamp};
 
     pub struct TdcSearch<'a> {
-        data: Vec<(usize, TdcType)>,
+        data: Vec<(Timestamp, TdcType)>,
+        stream: PacketStream,
         how_many: usize,
         tdc_choosen: &'a TdcType,
         initial_counter: Option<usize>,
@@ -20,6 +20,7 @@ mod tdcvec {
         pub fn new(tdc_choosen: &'a TdcType, how_many: usize) -> Self {
             TdcSearch{
                 data: Vec::new(),
+                stream: PacketStream::new(),
                 how_many,
                 tdc_choosen,
                 initial_counter: None,
@@ -29,7 +30,7 @@ mod tdcvec {
 
         fn add_tdc(&mut self, packet: &Pack) {
             if let Some(tdc) = TdcType::associate_value_to_enum(packet.tdc_type()) {
-                let time = packet.tdc_time_norm();
+                let time = packet.tdc_timestamp();
                 self.data.push( (time, tdc) );
                 if packet.tdc_type() == self.tdc_choosen.associate_value() {
                     self.last_counter = packet.tdc_counter();
@@ -52,7 +53,7 @@ mod tdcvec {
             } else {Ok(false)}
         }
 
-        fn get_timelist(&self, which: &TdcType) -> Vec<usize> {
+        fn get_timelist(&self, which: &TdcType) -> Vec<Timestamp> {
             let result: Vec<_> = self.data.iter()
                 .filter(|(_time, tdct)| tdct.associate_value() == which.associate_value())
                 .map(|(time, _tdct)| *time)
@@ -60,7 +61,7 @@ mod tdcvec {
             result
         }
         
-        fn get_auto_timelist(&self) -> Vec<usize> {
+        fn get_auto_timelist(&self) -> Vec<Timestamp> {
             let result: Vec<_> = self.data.iter()
                 .filter(|(_time, tdct)| tdct.associate_value() == self.tdc_choosen.associate_value())
                 .map(|(time, _tdct)| *time)
@@ -71,11 +72,11 @@ mod tdcvec {
         fn check_ascending_order(&self) -> Result<(), Tp3ErrorKind> {
             let time_list = self.get_auto_timelist();
             let result = time_list.iter().zip(time_list.iter().skip(1)).find(|(a, b)| a>b);
-            if result.is_some() {Err(Tp3ErrorKind::TdcNotAscendingOrder)}
+            if result.is_some() {Err(Tp3ErrorKind::TdcNotAscendingOrder(*self.tdc_choosen))}
             else {Ok(())}
         }
 
-        pub fn find_high_time(&self) -> Result<usize, Tp3ErrorKind> {
+        pub fn find_high_time(&self) -> Result<Timestamp, Tp3ErrorKind> {
             let fal_tdc_type = match self.tdc_choosen {
                 TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge => TdcType::TdcOneFallingEdge,
                 TdcType::TdcTwoRisingEdge | TdcType::TdcTwoFallingEdge => TdcType::TdcTwoFallingEdge,
@@ -93,31 +94,28 @@ mod tdcvec {
             //let last_fal = fal.pop().expect("Please get at least 01 falling Tdc");
             let last_fal = match fal.pop() {
                 Some(val) => val,
-                None => return Err(Tp3ErrorKind::TdcBadHighTime),
+                None => return Err(Tp3ErrorKind::TdcBadHighTime(*self.tdc_choosen)),
             };
             let last_ris = match ris.pop() {
                 Some(val) => val,
-                None => return Err(Tp3ErrorKind::TdcBadHighTime),
+                None => return Err(Tp3ErrorKind::TdcBadHighTime(*self.tdc_choosen)),
             };
             if last_fal > last_ris {
                 Ok(last_fal - last_ris)
             } else {
                 let new_ris = match ris.pop () {
                     Some(val) => val,
-                    None => return Err(Tp3ErrorKind::TdcBadHighTime),
+                    None => return Err(Tp3ErrorKind::TdcBadHighTime(*self.tdc_choosen)),
                 };
-                Ok(last_fal - new_ris)
+                last_fal.checked_sub(new_ris).ok_or(Tp3ErrorKind::TdcBadHighTime(*self.tdc_choosen))
             }
         }
         
-        pub fn find_period(&self) -> Result<usize, Tp3ErrorKind> {
+        pub fn find_period(&self) -> Result<Timestamp, Tp3ErrorKind> {
             let mut tdc_time = self.get_auto_timelist();
-            let last = tdc_time.pop().expect("Please get at least 02 Tdc's");
-            let before_last = tdc_time.pop().expect("Please get at least 02 Tdc's");
-            if last > before_last {
-                Ok(last - before_last)
-            } else {
-                Err(Tp3ErrorKind::TdcBadPeriod)
+            match (tdc_time.pop(), tdc_time.pop()) {
+                (Some(last), Some(before_last)) if last > before_last => Ok(last - before_last),
+                _ => Err(Tp3ErrorKind::TdcBadPeriod(*self.tdc_choosen)),
             }
         }
         
@@ -128,42 +126,40 @@ mod tdcvec {
             Ok(counter)
         }
 
-        pub fn get_counter_offset(&self) -> usize {
-            self.initial_counter.expect("***Tdc Lib***: Tdc initial counter offset was not found.")
+        pub fn get_counter_offset(&self) -> Result<usize, Tp3ErrorKind> {
+            self.initial_counter.ok_or(Tp3ErrorKind::TdcNoReceived(*self.tdc_choosen))
         }
 
         pub fn get_last_hardware_counter(&self) -> u16 {
             self.last_counter
         }
 
-        pub fn get_lasttime(&self) -> usize {
-            let last_time = self.data.iter()
+        pub fn get_lasttime(&self) -> Result<Timestamp, Tp3ErrorKind> {
+            self.data.iter()
                 .filter(|(_time, tdct)| tdct.associate_value()==self.tdc_choosen.associate_value())
                 .map(|(time, _tdct)| *time)
-                .last().unwrap();
-            last_time
+                .last()
+                .ok_or(Tp3ErrorKind::TdcNoReceived(*self.tdc_choosen))
         }
 
-        pub fn get_begintime(&self) -> usize {
-            let begin_time = self.data.iter()
+        pub fn get_begintime(&self) -> Result<Timestamp, Tp3ErrorKind> {
+            self.data.iter()
                 .filter(|(_time, tdct)| tdct.associate_value()==self.tdc_choosen.associate_value())
                 .map(|(time, _tdct)| *time)
-                .next().unwrap();
-            begin_time
+                .next()
+                .ok_or(Tp3ErrorKind::TdcNoReceived(*self.tdc_choosen))
         }
 
         pub fn search_specific_tdc(&mut self, data: &[u8]) {
-            data.chunks_exact(8).for_each(|x| {
-                match *x {
-                    [84, 80, 88, 51, _, _, _, _] => {},
-                    _ => {
-                        let packet = Pack {chip_index: 0, data: x.try_into().unwrap()};
-                        if packet.id() == 6 && self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
-                            self.add_tdc(&packet);
-                        }
-                    },
-                };
+            let mut stream = std::mem::take(&mut self.stream);
+            stream.events(data).for_each(|event| {
+                if let Event::Tdc(packet) = event {
+                    if self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
+                        self.add_tdc(&packet);
+                    }
+                }
             });
+            self.stream = stream;
         }
 
     }
@@ -171,6 +167,8 @@ mod tdcvec {
 
 
 ///The four types of TDC's.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TdcType {
     TdcOneRisingEdge,
     TdcOneFallingEdge,
@@ -179,19 +177,6 @@ pub enum TdcType {
     NoTdc,
 }
 
-impl Clone for TdcType {
-    fn clone(&self) -> TdcType {
-        match self {
-            TdcType::TdcOneRisingEdge => TdcType::TdcOneRisingEdge,
-            TdcType::TdcOneFallingEdge => TdcType::TdcOneFallingEdge,
-            TdcType::TdcTwoRisingEdge => TdcType::TdcTwoRisingEdge,
-            TdcType::TdcTwoFallingEdge => TdcType::TdcTwoFallingEdge,
-            TdcType::NoTdc => TdcType::NoTdc,
-        }
-        //match self {
-    }
-}
-
 impl TdcType {
     ///Convenient method. Return value is the 4 bits associated to each TDC.
     pub fn associate_value(&self) -> u8 {
@@ -204,7 +189,7 @@ impl TdcType {
         }
     }
 
-    fn associate_str(&self) -> String {
+    pub fn associate_str(&self) -> String {
         match *self {
             TdcType::TdcOneRisingEdge => String::from("Tdc 01 Rising Edge"),
             TdcType::TdcOneFallingEdge => String::from("Tdc 01 Falling Edge"),
@@ -235,16 +220,18 @@ impl TdcType {
     }
 }
 
+use serde::Deserialize;
 use std::time::{Duration, Instant};
 use crate::errorlib::Tp3ErrorKind;
 use crate::auxiliar::misc::TimepixRead;
+use crate::packetlib::Timestamp;
 
 pub trait TdcControl {
     fn id(&self) -> u8;
-    fn upt(&mut self, time: usize, hard_counter: u16);
+    fn upt(&mut self, time: Timestamp, hard_counter: u16);
     fn counter(&self) -> usize;
-    fn time(&self) -> usize;
-    fn period(&self) -> Option<usize>;
+    fn time(&self) -> Timestamp;
+    fn period(&self) -> Option<Timestamp>;
     fn new<T: TimepixRead>(tdc_type: TdcType, sock: &mut T, sp: Option<usize>) -> Result<Self, Tp3ErrorKind> where Self: Sized;
 }
 
@@ -256,11 +243,11 @@ pub struct PeriodicTdcRef {
     last_hard_counter: u16,
     counter_overflow: usize,
     pub ticks_to_frame: Option<usize>,
-    pub begin_frame: usize,
-    pub period: usize,
-    pub high_time: usize,
-    pub low_time: usize,
-    pub time: usize,
+    pub begin_frame: Timestamp,
+    pub period: Timestamp,
+    pub high_time: Timestamp,
+    pub low_time: Timestamp,
+    pub time: Timestamp,
 }
 
 impl TdcControl for PeriodicTdcRef {
@@ -268,7 +255,7 @@ impl TdcControl for PeriodicTdcRef {
         self.tdctype
     }
 
-    fn upt(&mut self, time: usize, hard_counter: u16) {
+    fn upt(&mut self, time: Timestamp, hard_counter: u16) {
         if hard_counter < self.last_hard_counter {
             self.counter_overflow += 1;
         }
@@ -287,11 +274,11 @@ impl TdcControl for PeriodicTdcRef {
         self.counter
     }
 
-    fn time(&self) -> usize {
+    fn time(&self) -> Timestamp {
         self.time
     }
 
-    fn period(&self) -> Option<usize> {
+    fn period(&self) -> Option<Timestamp> {
         Some(self.period)
     }
 
@@ -302,7 +289,7 @@ impl TdcControl for PeriodicTdcRef {
 
         println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
         loop {
-            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived)}
+            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived(tdc_type))}
             if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
                 tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
                 if tdc_search.check_tdc()? {break;}
@@ -310,13 +297,13 @@ impl TdcControl for PeriodicTdcRef {
         }
         println!("***Tdc Lib***: {} has been found.", tdc_type.associate_str());
         let _counter = tdc_search.get_counter()?;
-        let counter_offset = tdc_search.get_counter_offset();
+        let counter_offset = tdc_search.get_counter_offset()?;
         let _last_hard_counter = tdc_search.get_last_hardware_counter();
-        let begin_time = tdc_search.get_begintime();
-        let last_time = tdc_search.get_lasttime();
+        let begin_time = tdc_search.get_begintime()?;
+        let last_time = tdc_search.get_lasttime()?;
         let high_time = tdc_search.find_high_time()?;
         let period = tdc_search.find_period()?;
-        let low_time = period - high_time;
+        let low_time = period.checked_sub(high_time).ok_or(Tp3ErrorKind::TdcBadHighTime(tdc_type))?;
 
         let per_ref = Self {
             tdctype: tdc_type.associate_value(),
@@ -346,6 +333,32 @@ impl PeriodicTdcRef {
     }
 }
 
+///The trigger preceding `time` of a reference whose last trigger is `last`. For periodic
+///references, it is found even for events older than the last trigger. Otherwise, only events
+///after `last` have one.
+pub fn preceding_trigger(time: Timestamp, last: Timestamp, period: Option<Timestamp>) -> Option<Timestamp> {
+    match period {
+        Some(period) if last > time => {
+            let xper = (last - time) / period + 1;
+            Some(last.saturating_sub(period * xper))
+        },
+        Some(period) => {
+            let xper = (time - last) / period;
+            Some(last + period * xper)
+        },
+        None if time >= last => Some(last),
+        None => None,
+    }
+}
+
+///Whether `time` is between `delay` and `delay + width` after its preceding trigger.
+pub fn is_in_gate(time: Timestamp, last: Timestamp, period: Option<Timestamp>, delay: Timestamp, width: Timestamp) -> bool {
+    match preceding_trigger(time, last, period) {
+        Some(trigger) => time > trigger + delay && time < trigger + delay + width,
+        None => false,
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct SingleTriggerPeriodicTdcRef {
     tdctype: u8,
@@ -353,9 +366,9 @@ pub struct SingleTriggerPeriodicTdcRef {
     counter_offset: usize,
     last_hard_counter: u16,
     counter_overflow: usize,
-    pub begin_frame: usize,
-    pub period: usize,
-    pub time: usize,
+    pub begin_frame: Timestamp,
+    pub period: Timestamp,
+    pub time: Timestamp,
 }
 
 impl TdcControl for SingleTriggerPeriodicTdcRef {
@@ -363,7 +376,7 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
         self.tdctype
     }
 
-    fn upt(&mut self, time: usize, hard_counter: u16) {
+    fn upt(&mut self, time: Timestamp, hard_counter: u16) {
         if hard_counter < self.last_hard_counter {
             self.counter_overflow += 1;
         }
@@ -376,11 +389,11 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
         self.counter
     }
 
-    fn time(&self) -> usize {
+    fn time(&self) -> Timestamp {
         self.time
     }
 
-    fn period(&self) -> Option<usize> {
+    fn period(&self) -> Option<Timestamp> {
         Some(self.period)
     }
 
@@ -391,7 +404,7 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
 
         println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
         loop {
-            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived)}
+            if start.elapsed() > Duration::from_secs(10) {return Err(Tp3ErrorKind::TdcNoReceived(tdc_type))}
             if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
                 tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
                 if tdc_search.check_tdc()? {break;}
@@ -399,13 +412,13 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
         }
         println!("***Tdc Lib***: {} has been found.", tdc_type.associate_str());
         let counter = tdc_search.get_counter()?;
-        let counter_offset = tdc_search.get_counter_offset();
+        let counter_offset = tdc_search.get_counter_offset()?;
         let last_hard_counter = tdc_search.get_last_hardware_counter();
-        let begin_time = tdc_search.get_begintime();
-        let last_time = tdc_search.get_lasttime();
+        let begin_time = tdc_search.get_begintime()?;
+        let last_time = tdc_search.get_lasttime()?;
         let period = tdc_search.find_period()?;
         
-        println!("***Tdc Lib***: Creating a new Tdc reference from {}. Number of detected triggers is {}. Last trigger time (ns) is {}. Period (ns) is {}.", tdc_type.associate_str(), counter, last_time, period);
+        println!("***Tdc Lib***: Creating a new Tdc reference from {}. Number of detected triggers is {}. Last trigger time is {}. Period is {}.", tdc_type.associate_str(), counter, last_time, period);
         Ok(Self {
             tdctype: tdc_type.associate_value(),
             counter,
@@ -423,7 +436,7 @@ impl TdcControl for SingleTriggerPeriodicTdcRef {
 pub struct NonPeriodicTdcRef {
     pub tdctype: u8,
     pub counter: usize,
-    pub time: usize,
+    pub time: Timestamp,
 }
 
 impl TdcControl for NonPeriodicTdcRef {
@@ -431,7 +444,7 @@ impl TdcControl for NonPeriodicTdcRef {
         self.tdctype
     }
 
-    fn upt(&mut self, time: usize, _: u16) {
+    fn upt(&mut self, time: Timestamp, _: u16) {
         self.time = time;
         self.counter+=1;
     }
@@ -440,11 +453,11 @@ impl TdcControl for NonPeriodicTdcRef {
         self.counter
     }
 
-    fn time(&self) -> usize {
+    fn time(&self) -> Timestamp {
         self.time
     }
 
-    fn period(&self) -> Option<usize> {
+    fn period(&self) -> Option<Timestamp> {
         None
     }
     
@@ -452,7 +465,7 @@ impl TdcControl for NonPeriodicTdcRef {
         Ok(Self {
             tdctype: tdc_type.associate_value(),
             counter: 0,
-            time: 0,
+            time: Timestamp::zero(),
         })
     }
     
@@ -460,17 +473,20 @@ impl TdcControl for NonPeriodicTdcRef {
 
 pub mod isi_box {
     use std::net::{TcpListener, TcpStream};
-    use std::io::{Read, Write};
+    use std::io::{self, Read, Write};
     use std::sync::{Arc, Mutex};
     use std::{thread, time};
-    use crate::spimlib::SPIM_PIXELS;
+    use crate::detectorlib::DetectorLayout;
+    use crate::errorlib::Tp3ErrorKind;
     
-    fn transform_by_channel(v: &[u8], channel: u32) {
+    fn transform_by_channel(v: &[u8], channel: u32, layout: &DetectorLayout) {
+        let spim_pixels = layout.spim_pixels() as u32;
+        let first_channel = layout.chip_array().0 as u32;
         unsafe {
             let temp_slice = std::slice::from_raw_parts_mut(
                 v.as_ptr() as *mut u32,
                 (v.len() * std::mem::size_of::<u8>()) / std::mem::size_of::<u32>());
-            temp_slice.iter_mut().for_each(|x| *x = (*x * SPIM_PIXELS as u32) + 1025 + channel);
+            temp_slice.iter_mut().for_each(|x| *x = (*x * spim_pixels) + first_channel + channel);
         }
     }
 
@@ -487,18 +503,20 @@ pub mod isi_box {
         sockets: Vec<TcpStream>,
         ext_socket: Option<TcpStream>,
         nchannels: u32,
+        layout: DetectorLayout,
     }
 
     impl IsiBoxHandler {
-        pub fn bind_and_connect(&mut self) {
-            let isi_listener = TcpListener::bind("127.0.0.1:9592").expect("Could not bind to IsiBox.");
+        pub fn bind_and_connect(&mut self, address: &str) -> Result<(), Tp3ErrorKind> {
+            let isi_listener = TcpListener::bind(address).map_err(|e| Tp3ErrorKind::SocketBind(address.to_string(), e))?;
             for _ in 0..self.nchannels {
-                let (sock, _addr) = isi_listener.accept().expect("Could not connect to IsiBox.");
+                let (sock, _addr) = isi_listener.accept().map_err(|e| Tp3ErrorKind::SocketAccept("IsiBox", e))?;
                 //println!("IsiBox connected at {:?} and {:?}.", addr, sock);
                 self.sockets.push(sock);
             }
-            let (sock, _addr) = isi_listener.accept().expect("Could not connect to IsiBox external socket.");
+            let (sock, _addr) = isi_listener.accept().map_err(|e| Tp3ErrorKind::SocketAccept("IsiBox external socket", e))?;
             self.ext_socket = Some(sock);
+            Ok(())
         }
 
         pub fn configure_scan_parameters(&self, xscan: u32, yscan: u32, pixel_time: u32) {
@@ -513,18 +531,22 @@ pub mod isi_box {
             };
         }
 
-        pub fn send_to_external_socket(&self) {
+        pub fn send_to_external_socket(&self) -> Result<(), Tp3ErrorKind> {
             let nvec_arclist = Arc::clone(&self.nvec_list);
             let mut num = nvec_arclist.lock().unwrap();
             if (*num).len() > 0 {
-                if (self.ext_socket.as_ref().expect("The external sockets is not present")).write(&*num).is_err() {println!("Could not send data through the external socket.")}
+                let mut sock = self.ext_socket.as_ref()
+                    .ok_or_else(|| Tp3ErrorKind::SocketWrite("IsiBox external socket", io::Error::from(io::ErrorKind::NotConnected)))?;
+                sock.write(&*num).map_err(|e| Tp3ErrorKind::SocketWrite("IsiBox external socket", e))?;
             }
             println!("data sent size is: {}", (*num).len());
             (*num).clear();
+            Ok(())
         }
 
         pub fn start_index_threads(&mut self) {
             let nchannels = self.nchannels;
+            let layout = self.layout;
             let mut channel_index = nchannels-1;
             
             for _ in 0..nchannels {
@@ -536,7 +558,7 @@ pub mod isi_box {
                         match val.read(&mut buffer) {
                             Ok(size) => {
                                 let mut num = nvec_arclist.lock().unwrap();
-                                transform_by_channel(&buffer[0..size], channel_index);
+                                transform_by_channel(&buffer[0..size], channel_index, &layout);
                                 buffer[0..size].iter().for_each(|&x| (*num).push(x));
                             },
                             Err(_) => {
@@ -549,12 +571,13 @@ pub mod isi_box {
                 channel_index-=1;
             }
         }
-        pub fn new(nchannels: u32) -> Self {
+        pub fn new(nchannels: u32, layout: DetectorLayout) -> Self {
             Self {
                 nvec_list: Arc::new(Mutex::new(Vec::new())),
                 sockets: Vec::new(),
                 ext_socket: None,
                 nchannels,
+                layout,
             }
         }
     }
@@ -0,0 +1,60 @@
+//!Commands received during a live acquisition, fed through `AcquisitionControl::from_channel`.
+
+mod common;
+
+use common::{ScriptedRead, SharedOutput};
+use timepix3::auxiliar::Settings;
+use timepix3::configlib::Tp3Config;
+use timepix3::controllib::{AcquisitionControl, Command};
+use timepix3::detectorlib::DetectorLayout;
+use timepix3::modelib;
+use timepix3::packetlib::Timestamp;
+use timepix3::simulationlib::Tpx3Writer;
+use timepix3::tdclib::TdcType;
+use std::sync::mpsc;
+
+const PERIOD: u64 = 64_000; //Frame period, in units of 640 MHz.
+const ELECTRONS_PER_LINE: usize = 10;
+const X: usize = 100;
+
+///The frame TDC of `line`, preceded by its electrons.
+fn line_buffer(layout: DetectorLayout, line: u64, electrons: usize) -> Vec<u8> {
+    let mut writer = Tpx3Writer::new(Vec::new(), layout);
+    for index in 0..electrons {
+        writer.write_electron(X, 128, 100, Timestamp::new(line * PERIOD - PERIOD / 2 + index as u64)).unwrap();
+    }
+    common::write_frame_tdc(&mut writer, line, line * PERIOD, PERIOD / 4);
+    writer.finish().unwrap()
+}
+
+#[test]
+fn fast_chrono_pause_resume() {
+    let layout = DetectorLayout::eels();
+    let settings = Settings {xspim_size: 5, spim_tdc: TdcType::TdcTwoFallingEdge, ..common::settings(6, layout)};
+    let (tx, rx) = mpsc::channel();
+    //The first three frames are used to find the frame TDC. The chrono is complete with the
+    //frame 6, which arrives while paused. It must be sent once resumed.
+    let mut setup = Vec::new();
+    (0..3).for_each(|line| setup.extend(line_buffer(layout, line, 0)));
+    let mut buffers = vec![(setup, None)];
+    for line in 3..10 {
+        let command = match line {
+            5 => Some(Command::Pause),
+            8 => Some(Command::Resume),
+            _ => None,
+        };
+        buffers.push((line_buffer(layout, line, ELECTRONS_PER_LINE), command));
+    }
+    let output = SharedOutput::default();
+    let pack = ScriptedRead::new(buffers, tx);
+    modelib::run(settings, Box::new(pack), Box::new(output.clone()), AcquisitionControl::from_channel(rx), &Tp3Config::default()).unwrap();
+
+    let width = layout.chip_array().0;
+    let frame = output.values();
+    assert_eq!(frame.len(), settings.xspim_size * width + 1, "Exactly one chrono must be sent.");
+    let counts = common::frame_counts(&frame, width);
+    //The electrons before the frame 3 fall in the line 0, as the line counter starts with the
+    //acquisition. The ones received while paused are discarded, and the ones after the resume
+    //are beyond the last line.
+    assert_eq!(counts, vec![(0, X, ELECTRONS_PER_LINE as u32), (3, X, ELECTRONS_PER_LINE as u32)]);
+}
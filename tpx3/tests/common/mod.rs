@@ -0,0 +1,119 @@
+//!Fixtures shared by the integration tests. Each test file uses only a part of them.
+#![allow(dead_code)]
+
+use timepix3::auxiliar::{Settings, OutputFormat, misc::TimepixRead};
+use timepix3::controllib::Command;
+use timepix3::detectorlib::DetectorLayout;
+use timepix3::packetlib::{Timestamp, Clock640MHz};
+use timepix3::simulationlib::Tpx3Writer;
+use timepix3::tdclib::TdcType;
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+
+///Gives one buffer per read, and sends the command attached to it just before.
+pub struct ScriptedRead {
+    buffers: Vec<(Vec<u8>, Option<Command>)>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl ScriptedRead {
+    pub fn new(buffers: Vec<(Vec<u8>, Option<Command>)>, commands: mpsc::Sender<Command>) -> Self {
+        ScriptedRead {buffers, commands}
+    }
+
+    ///Buffers without any command.
+    pub fn from_buffers(buffers: Vec<Vec<u8>>, commands: mpsc::Sender<Command>) -> Self {
+        Self::new(buffers.into_iter().map(|data| (data, None)).collect(), commands)
+    }
+}
+
+impl Read for ScriptedRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffers.is_empty() {
+            return Ok(0);
+        }
+        let (data, command) = self.buffers.remove(0);
+        if let Some(command) = command {
+            self.commands.send(command).unwrap();
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl TimepixRead for ScriptedRead {}
+
+///The output of a live mode, still readable once the mode has taken its copy.
+#[derive(Clone, Default)]
+pub struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    ///The frames sent, as values of 4 bytes.
+    pub fn values(&self) -> Vec<u32> {
+        self.0.lock().unwrap().chunks_exact(4)
+            .map(|value| u32::from_le_bytes(value.try_into().unwrap()))
+            .collect()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///The nonzero values (line, column, count) of a frame `width` pixels wide, without its last value.
+pub fn frame_counts(frame: &[u32], width: usize) -> Vec<(usize, usize, u32)> {
+    frame[..frame.len() - 1].iter().enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(index, count)| (index / width, index % width, *count))
+        .collect()
+}
+
+///Closes `line` with the frame TDC at `time`, high during `high_time` (in units of 640 MHz). The
+///hardware counter counts both edges.
+pub fn write_frame_tdc(writer: &mut Tpx3Writer<Vec<u8>>, line: u64, time: u64, high_time: u64) {
+    writer.write_tdc(&TdcType::TdcOneRisingEdge, 2 * line as u16, Timestamp::<Clock640MHz>::new(time).convert()).unwrap();
+    writer.write_tdc(&TdcType::TdcOneFallingEdge, 2 * line as u16 + 1, Timestamp::<Clock640MHz>::new(time + high_time).convert()).unwrap();
+}
+
+///Settings of a live `mode` in 4 bytes, with the frame TDC on the rising edge of the first TDC and
+///the photons on the rising edge of the second.
+pub fn settings(mode: u8, layout: DetectorLayout) -> Settings {
+    Settings {
+        bin: false,
+        bytedepth: 4,
+        cumul: false,
+        mode,
+        xspim_size: 1,
+        yspim_size: 1,
+        xscan_size: 1,
+        yscan_size: 1,
+        time_delay: 0,
+        time_width: 1000,
+        spimoverscanx: 1,
+        spimoverscany: 1,
+        layout,
+        frame_tdc: TdcType::TdcOneRisingEdge,
+        spim_tdc: TdcType::TdcOneFallingEdge,
+        ref_tdc: TdcType::TdcTwoRisingEdge,
+        laser_tdc: TdcType::TdcTwoRisingEdge,
+        roi: None,
+        output: OutputFormat::Frame,
+    }
+}
+
+///A directory for the test `name`, unique to this process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("tpx3_{}_{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
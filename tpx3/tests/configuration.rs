@@ -0,0 +1,151 @@
+//!Defaults of the configuration and precedence of its sources: defaults, TOML file, `TPX3_*`
+//!environment variables and flags.
+
+use timepix3::configlib::{Tp3Config, TdcConfig, OutputFormat};
+use timepix3::tdclib::TdcType;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+///The environment is shared by the tests of this file.
+static ENV: Mutex<()> = Mutex::new(());
+
+const VARIABLES: [&str; 5] = ["TPX3_CONFIG", "TPX3_TP3_ADDRESS", "TPX3_COINCIDENCE_WIDTH", "TPX3_TDC_SPIM", "TPX3_COINCIDENCE_CHANNELS"];
+
+fn config_file(name: &str, content: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("tpx3_configuration_{}_{}.toml", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn args(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+fn clear_env() {
+    VARIABLES.iter().for_each(|variable| env::remove_var(variable));
+}
+
+fn same(a: TdcType, b: TdcType) -> bool {
+    a.associate_value() == b.associate_value()
+}
+
+#[test]
+fn tdc_defaults() {
+    let tdc = TdcConfig::default();
+    assert!(same(tdc.frame, TdcType::TdcOneRisingEdge));
+    assert!(same(tdc.spim, TdcType::TdcOneFallingEdge));
+    assert!(same(tdc.reference, TdcType::TdcTwoRisingEdge));
+    assert!(same(tdc.laser, TdcType::TdcTwoRisingEdge));
+    let config = Tp3Config::default();
+    assert!(same(config.tdc.reference, tdc.reference));
+    assert_eq!(config.coincidence.photon_tdcs(&config.tdc).iter().map(TdcType::associate_value).collect::<Vec<u8>>(), vec![tdc.reference.associate_value()]);
+    assert_eq!(config.coincidence.output_format, OutputFormat::Text);
+}
+
+#[test]
+fn file_then_environment_then_flags() {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    clear_env();
+    let path = config_file("precedence", r#"
+        tp3_address = "127.0.0.1:1000"
+        debug_address = "127.0.0.1:1001"
+
+        [tdc]
+        frame = "tdc_two_falling_edge"
+        spim = "tdc_two_rising_edge"
+
+        [coincidence]
+        width = 30.0
+        search_len = 10
+    "#);
+    env::set_var("TPX3_CONFIG", &path);
+    env::set_var("TPX3_TP3_ADDRESS", "127.0.0.1:2000");
+    env::set_var("TPX3_COINCIDENCE_WIDTH", "40.0");
+    env::set_var("TPX3_TDC_SPIM", "tdc_one_rising_edge");
+    let loaded = Tp3Config::load(&args(&["tp3_coin", "data.tpx3", "--tp3-address", "127.0.0.1:3000", "--tdc-spim", "tdc_one_falling_edge", "1"]));
+    clear_env();
+    fs::remove_file(&path).unwrap();
+
+    let (config, remaining) = loaded.unwrap();
+    assert_eq!(remaining, args(&["tp3_coin", "data.tpx3", "1"]));
+    //Flags win over the environment, which wins over the file, which wins over the defaults.
+    assert_eq!(config.tp3_address, "127.0.0.1:3000");
+    assert!(same(config.tdc.spim, TdcType::TdcOneFallingEdge));
+    assert_eq!(config.coincidence.width, 40.0);
+    assert_eq!(config.debug_address, "127.0.0.1:1001");
+    assert!(same(config.tdc.frame, TdcType::TdcTwoFallingEdge));
+    assert_eq!(config.coincidence.search_len, 10);
+    assert!(same(config.tdc.reference, TdcType::TdcTwoRisingEdge));
+    assert_eq!(config.coincidence.delay, Tp3Config::default().coincidence.delay);
+}
+
+#[test]
+fn config_flag_over_environment() {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    clear_env();
+    let from_env = config_file("env", "tp3_address = \"127.0.0.1:1000\"");
+    let from_flag = config_file("flag", "tp3_address = \"127.0.0.1:2000\"");
+    env::set_var("TPX3_CONFIG", &from_env);
+    let loaded = Tp3Config::load(&args(&["debug", "--config", from_flag.to_str().unwrap()]));
+    clear_env();
+    fs::remove_file(&from_env).unwrap();
+    fs::remove_file(&from_flag).unwrap();
+
+    let (config, remaining) = loaded.unwrap();
+    assert_eq!(config.tp3_address, "127.0.0.1:2000");
+    assert_eq!(remaining, args(&["debug"]));
+}
+
+#[test]
+fn list_values() {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    clear_env();
+    env::set_var("TPX3_COINCIDENCE_CHANNELS", "tdc_two_rising_edge, tdc_two_falling_edge");
+    let loaded = Tp3Config::load(&args(&["tp3_coin", "--coincidence-output-format", "npy", "--simulation-coincidence-fraction", "0.25"]));
+    clear_env();
+
+    let (config, _) = loaded.unwrap();
+    assert_eq!(config.coincidence.channels.iter().map(TdcType::associate_value).collect::<Vec<u8>>(),
+        vec![TdcType::TdcTwoRisingEdge.associate_value(), TdcType::TdcTwoFallingEdge.associate_value()]);
+    assert_eq!(config.coincidence.output_format, OutputFormat::Npy);
+    assert_eq!(config.simulation.coincidence_fraction, 0.25);
+}
+
+#[test]
+fn bad_arguments() {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    clear_env();
+    assert!(Tp3Config::load(&args(&["tp3_coin", "--unknown-key", "1"])).is_err());
+    assert!(Tp3Config::load(&args(&["tp3_coin", "--tdc-frame"])).is_err());
+    assert!(Tp3Config::load(&args(&["tp3_coin", "--tdc-frame", "tdc_three_rising_edge"])).is_err());
+    assert!(Tp3Config::load(&args(&["tp3_coin", "--coincidence-width", "wide"])).is_err());
+    let bad_file = config_file("bad", "unknown_key = 1");
+    env::set_var("TPX3_CONFIG", &bad_file);
+    let loaded = Tp3Config::load(&args(&["tp3_coin"]));
+    clear_env();
+    fs::remove_file(&bad_file).unwrap();
+    assert!(loaded.is_err());
+}
+
+#[test]
+fn binary_defaults() {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    clear_env();
+    let defaults = [("tdc.reference", "tdc_two_falling_edge")];
+    let (config, _) = Tp3Config::load_with_defaults(&args(&["debug"]), &defaults).unwrap();
+    assert!(same(config.tdc.reference, TdcType::TdcTwoFallingEdge));
+    let (config, _) = Tp3Config::load_with_defaults(&args(&["debug", "--tdc-reference", "tdc_two_rising_edge"]), &defaults).unwrap();
+    assert!(same(config.tdc.reference, TdcType::TdcTwoRisingEdge));
+
+    //A key set by the file overrides the default of the binary, the others keep it.
+    let path = config_file("binary", "[tdc]\nreference = \"tdc_one_rising_edge\"");
+    env::set_var("TPX3_CONFIG", &path);
+    let loaded = Tp3Config::load_with_defaults(&args(&["debug"]), &defaults);
+    let other = Tp3Config::load_with_defaults(&args(&["debug"]), &[("tdc.laser", "tdc_one_falling_edge")]);
+    clear_env();
+    fs::remove_file(&path).unwrap();
+    assert!(same(loaded.unwrap().0.tdc.reference, TdcType::TdcOneRisingEdge));
+    assert!(same(other.unwrap().0.tdc.laser, TdcType::TdcOneFallingEdge));
+}
@@ -0,0 +1,162 @@
+//!The control protocol: framed JSON settings with their reply, and the legacy 16-byte message.
+
+use timepix3::auxiliar::Settings;
+use timepix3::configlib::Tp3Config;
+use timepix3::controllib::{self, ControlMessage, KIND_SETTINGS, KIND_REPLY, KIND_COMMAND, PROTOCOL_VERSION};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::tdclib::TdcType;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+
+///The client side of the control socket. Its messages are given at most `step` bytes per read,
+///and the replies are kept in `output`.
+struct Client {
+    input: Vec<u8>,
+    position: usize,
+    step: usize,
+    output: Vec<u8>,
+}
+
+impl Client {
+    fn new(input: Vec<u8>, step: usize) -> Self {
+        Client {input, position: 0, step, output: Vec::new()}
+    }
+
+    ///The status and the message of the reply.
+    fn reply(&self) -> (String, Value) {
+        match controllib::read_message(&mut self.output.as_slice()).unwrap() {
+            ControlMessage::Framed {kind: KIND_REPLY, payload} => {
+                let reply: Value = serde_json::from_slice(&payload).unwrap();
+                assert_eq!(reply["version"], PROTOCOL_VERSION);
+                (reply["status"].as_str().unwrap().to_string(), reply)
+            },
+            _ => panic!("The reply must be a framed message of kind KIND_REPLY."),
+        }
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.step.min(buf.len()).min(self.input.len() - self.position);
+        buf[..size].copy_from_slice(&self.input[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn framed(kind: u8, payload: &str) -> Vec<u8> {
+    let mut msg = Vec::new();
+    controllib::write_message(&mut msg, kind, payload.as_bytes()).unwrap();
+    msg
+}
+
+fn receive(input: Vec<u8>, step: usize) -> (Result<Settings, Tp3ErrorKind>, Client) {
+    let mut client = Client::new(input, step);
+    let settings = controllib::receive_settings(&mut client, &Tp3Config::default());
+    (settings, client)
+}
+
+fn same(a: TdcType, b: TdcType) -> bool {
+    a.associate_value() == b.associate_value()
+}
+
+#[test]
+fn framed_settings_round_trip() {
+    let payload = r#"{"mode": 2, "bytedepth": 2, "xspim": 64, "yspim": 32, "xscan": 256, "yscan": 64,
+        "time_delay": 150, "time_width": 30, "ref_tdc": "tdc_two_falling_edge", "roi": {"x": [10, 20], "y": [0, 255]}, "output": "frame"}"#;
+    //The message arrives a few bytes at a time.
+    let (settings, client) = receive(framed(KIND_SETTINGS, payload), 3);
+    let settings = settings.unwrap();
+    assert_eq!((settings.mode, settings.bytedepth, settings.bin, settings.cumul), (2, 2, false, false));
+    assert_eq!((settings.xspim_size, settings.yspim_size, settings.spimoverscanx, settings.spimoverscany), (64, 32, 4, 2));
+    assert_eq!((settings.time_delay, settings.time_width), (150, 30));
+    assert!(same(settings.ref_tdc, TdcType::TdcTwoFallingEdge));
+    //Absent TDCs are the ones of the configuration.
+    assert!(same(settings.frame_tdc, Tp3Config::default().tdc.frame));
+    assert_eq!(settings.roi.map(|roi| (roi.x, roi.y)), Some(((10, 20), (0, 255))));
+
+    let (status, reply) = client.reply();
+    assert_eq!(status, "ok");
+    assert_eq!(reply["mode"], 2);
+    assert_eq!(client.output.len(), 8 + u16::from_be_bytes([client.output[6], client.output[7]]) as usize, "A single reply is sent.");
+}
+
+#[test]
+fn legacy_message() {
+    //bin, bytedepth (2 is 4 bytes), cumul, mode, then the big-endian xspim, yspim, xscan, yscan,
+    //time delay and time width.
+    let data = vec![1, 2, 1, 0, 0, 64, 0, 32, 1, 0, 0, 64, 0, 150, 0, 30];
+    let (settings, client) = receive(data, 5);
+    let settings = settings.unwrap();
+    assert_eq!((settings.bin, settings.bytedepth, settings.cumul, settings.mode), (true, 4, true, 0));
+    assert_eq!((settings.xspim_size, settings.yspim_size, settings.xscan_size, settings.yscan_size), (64, 32, 256, 64));
+    assert_eq!((settings.spimoverscanx, settings.spimoverscany), (4, 2));
+    assert_eq!((settings.time_delay, settings.time_width), (150, 30));
+    assert!(same(settings.ref_tdc, Tp3Config::default().tdc.reference));
+    assert!(client.output.is_empty(), "Old clients get no reply.");
+
+    let (settings, _) = receive(vec![3, 2, 1, 0, 0, 64, 0, 32, 1, 0, 0, 64, 0, 150, 0, 30], 16);
+    assert!(matches!(settings, Err(Tp3ErrorKind::SetBin(3))));
+}
+
+#[test]
+fn partial_messages() {
+    let mut message = framed(KIND_SETTINGS, r#"{"mode": 0}"#);
+    message.truncate(message.len() - 2);
+    for input in [message, framed(KIND_SETTINGS, "{}")[..6].to_vec(), vec![0, 2, 0, 0, 0, 64, 0, 32], vec![0, 2]] {
+        let (settings, client) = receive(input, 4);
+        assert!(matches!(settings, Err(Tp3ErrorKind::SocketRead(..))), "{:?}", settings);
+        assert!(client.output.is_empty());
+    }
+}
+
+#[test]
+fn refused_settings() {
+    let payloads = [
+        r#"{"bytedepth": 3}"#,
+        r#"{"time_width": 0}"#,
+        r#"{"xspim": 0}"#,
+        r#"{"mode": 200}"#,
+        r#"{"frame_tdc": "tdc_two_rising_edge", "ref_tdc": "tdc_two_rising_edge"}"#,
+        r#"{"spim_tdc": "tdc_two_falling_edge", "laser_tdc": "tdc_two_falling_edge"}"#,
+        r#"{"roi": {"x": [0, 2000], "y": [0, 10]}}"#,
+        r#"{"roi": {"x": [20, 10], "y": [0, 10]}}"#,
+        r#"{"remove_clusters": true}"#,
+        r#"{"unknown": 1}"#,
+        r#"{"mode": "#,
+    ];
+    for payload in payloads.iter() {
+        let (settings, client) = receive(framed(KIND_SETTINGS, payload), 64);
+        let error = settings.expect_err(payload);
+        let (status, reply) = client.reply();
+        assert_eq!(status, "error", "{}", payload);
+        assert_eq!(reply["message"], error.to_string());
+    }
+}
+
+#[test]
+fn refused_frames() {
+    let mut version = framed(KIND_SETTINGS, "{}");
+    version[4] = PROTOCOL_VERSION + 1;
+    let (settings, client) = receive(version, 64);
+    assert!(matches!(settings, Err(Tp3ErrorKind::SetProtocolVersion(_))));
+    assert_eq!(client.reply().0, "error");
+
+    let (settings, client) = receive(vec![b'T', b'P', b'X', b'C', PROTOCOL_VERSION, KIND_SETTINGS, 0, 0], 64);
+    assert!(matches!(settings, Err(Tp3ErrorKind::SetConfigSize(0))));
+    assert_eq!(client.reply().0, "error");
+
+    let (settings, client) = receive(framed(KIND_COMMAND, r#"{"command": "stop"}"#), 64);
+    assert!(matches!(settings, Err(Tp3ErrorKind::SetMessageKind(KIND_COMMAND))));
+    assert_eq!(client.reply().0, "error");
+}
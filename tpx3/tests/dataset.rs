@@ -0,0 +1,109 @@
+//!Reading a `Dataset` as a single stream of packets.
+
+mod common;
+
+use timepix3::postlib::dataset::{Dataset, natural_cmp, read_packets};
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+///Gives at most `step` bytes per read.
+struct ShortRead {
+    data: Vec<u8>,
+    position: usize,
+    step: usize,
+}
+
+impl Read for ShortRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.step.min(buf.len()).min(self.data.len() - self.position);
+        buf[..size].copy_from_slice(&self.data[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+#[test]
+fn read_whole_packets() {
+    let data: Vec<u8> = (0..=255).cycle().take(8 * 100 + 5).collect();
+    let mut reader = ShortRead {data: data.clone(), position: 0, step: 3};
+    let mut buffer = vec![0; 8 * 16];
+    let mut read = Vec::new();
+    let mut sizes = Vec::new();
+    loop {
+        let size = read_packets(&mut reader, &mut buffer).unwrap();
+        if size == 0 {break;}
+        sizes.push(size);
+        read.extend_from_slice(&buffer[..size]);
+    }
+    assert_eq!(read, data);
+    //Only the end of the data is not a whole number of packets.
+    assert!(sizes[..sizes.len() - 1].iter().all(|size| size % 8 == 0));
+    assert_eq!(sizes.last().unwrap() % 8, 5);
+}
+
+#[test]
+fn natural_order() {
+    assert_eq!(natural_cmp("f_2", "f_10"), Ordering::Less);
+    assert_eq!(natural_cmp("raw10.tpx3", "raw9.tpx3"), Ordering::Greater);
+    assert_eq!(natural_cmp("raw000012.tpx3", "raw12.tpx3"), Ordering::Equal);
+    assert_eq!(natural_cmp("a2b10", "a2b9"), Ordering::Greater);
+    assert_eq!(natural_cmp("raw", "raw1"), Ordering::Less);
+    assert_eq!(natural_cmp("b1", "a2"), Ordering::Greater);
+}
+
+#[test]
+fn folder_order_and_gaps() {
+    let dir = common::temp_dir("dataset_gaps");
+    for name in ["raw10.tpx3", "raw2.tpx3", "raw1.tpx3", "raw5.tpx3", "notes.txt"] {
+        fs::write(dir.join(name), [0u8; 8]).unwrap();
+    }
+    let dataset = Dataset::new(dir.to_str().unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let dataset = dataset.unwrap();
+    let names: Vec<String> = dataset.files().iter().map(|file| file.file_name().unwrap().to_string_lossy().to_string()).collect();
+    assert_eq!(names, vec!["raw1.tpx3", "raw2.tpx3", "raw5.tpx3", "raw10.tpx3"]);
+    assert_eq!(dataset.gaps(), &[(3, 4), (6, 9)]);
+}
+
+#[test]
+fn empty_folder() {
+    let dir = common::temp_dir("dataset_empty");
+    let dataset = Dataset::new(dir.to_str().unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(dataset.is_err());
+}
+
+#[test]
+fn read_across_files() {
+    //File lengths are not whole numbers of packets, so packets are split between files.
+    let dir = common::temp_dir("dataset_across");
+    let data: Vec<u8> = (0..=255).cycle().take(8 * 20).collect();
+    let files: Vec<PathBuf> = [(0, 13), (13, 14), (14, 100), (100, 160)].iter().enumerate()
+        .map(|(index, &(start, end))| {
+            let file = dir.join(format!("raw{}.tpx3", index));
+            fs::write(&file, &data[start..end]).unwrap();
+            file
+        })
+        .collect();
+    let dataset = Dataset::from_files(files.clone(), "across").unwrap();
+    let mut reader = dataset.open().unwrap();
+    let mut buffer = vec![0; 8 * 4];
+    let mut read = Vec::new();
+    let mut current = Vec::new();
+    loop {
+        let size = read_packets(&mut reader, &mut buffer).unwrap();
+        if size == 0 {break;}
+        assert_eq!(size % 8, 0);
+        read.extend_from_slice(&buffer[..size]);
+        current.push(reader.current().to_path_buf());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(read, data);
+    //The first read goes through the two shortest files.
+    assert_eq!(current.first(), Some(&files[2]));
+    assert_eq!(current.last(), Some(&files[3]));
+}
@@ -0,0 +1,92 @@
+//!Layouts read from a TOML file, and pixels whose chip is not in the layout.
+
+use timepix3::detectorlib::{DetectorLayout, ChipLayout, Orientation, CHIP_SIZE};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::packetlib::{InversePacket, PacketEELS, Timestamp};
+use std::env;
+use std::fs;
+
+fn layout_file(name: &str, content: &str) -> Result<DetectorLayout, Tp3ErrorKind> {
+    let path = env::temp_dir().join(format!("tpx3_layout_{}_{}.toml", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    let layout = DetectorLayout::from_file(path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    layout
+}
+
+#[test]
+fn gap_and_orientation() {
+    let layout = layout_file("gap", r#"
+        gap = 4
+        extra_columns = 2
+        extra_channels = 0
+
+        [[chip]]
+        position = [0, 0]
+
+        [[chip]]
+        position = [1, 0]
+        orientation = 90
+
+        [[chip]]
+        position = [0, 1]
+        orientation = 180
+        flip_x = true
+        offset = [1, 2]
+    "#).unwrap();
+    let last = CHIP_SIZE - 1;
+    assert_eq!(layout.nchips(), 3);
+    assert_eq!(layout.size(), (2 * CHIP_SIZE + 4, 2 * CHIP_SIZE + 4 + 2));
+    assert_eq!(layout.chip_array(), (2 * CHIP_SIZE + 4 + 2, 2 * CHIP_SIZE + 4 + 2));
+    assert_eq!(layout.spim_pixels(), 2 * CHIP_SIZE + 4 + 2);
+
+    let second = layout.chip(1).unwrap();
+    assert_eq!((second.orientation, second.offset), (Orientation::Rotate90, (CHIP_SIZE + 4, 0)));
+    //A rotation of 90 degrees clockwise sends the first row to the last column.
+    assert_eq!(second.map(0, 0), (CHIP_SIZE + 4 + last, 0));
+    assert_eq!(second.map(last, 0), (CHIP_SIZE + 4 + last, last));
+    let third = layout.chip(2).unwrap();
+    assert_eq!(third.offset, (1, CHIP_SIZE + 4 + 2));
+    //Rotated by 180 degrees, then flipped horizontally.
+    assert_eq!(third.map(0, 0), (1, CHIP_SIZE + 4 + 2 + last));
+    assert!(layout.chip(3).is_none());
+}
+
+#[test]
+fn map_unmap_round_trip() {
+    let orientations = [Orientation::Rotate0, Orientation::Rotate90, Orientation::Rotate180, Orientation::Rotate270];
+    for (index, orientation) in orientations.iter().enumerate() {
+        for (flip_x, flip_y) in [(false, false), (true, false), (false, true), (true, true)] {
+            let chip = ChipLayout { orientation: *orientation, flip_x, flip_y, offset: (index * 300, 7) };
+            for (x, y) in [(0, 0), (CHIP_SIZE - 1, 0), (0, CHIP_SIZE - 1), (17, 200), (CHIP_SIZE - 1, CHIP_SIZE - 1)] {
+                let (x_cam, y_cam) = chip.map(x, y);
+                assert_eq!(chip.unmap(x_cam, y_cam), Some((x, y)), "{:?} at ({}, {})", chip, x, y);
+            }
+            assert_eq!(chip.unmap(index * 300 + CHIP_SIZE, 7), None);
+            assert_eq!(chip.unmap(index * 300, 6), None);
+        }
+    }
+}
+
+#[test]
+fn bad_layouts() {
+    assert!(matches!(layout_file("overlap", "[[chip]]\nposition = [0, 0]\n[[chip]]\nposition = [0, 0]\noffset = [100, 0]"), Err(Tp3ErrorKind::LayoutBadChip(_))));
+    assert!(matches!(layout_file("same", "[[chip]]\nposition = [1, 1]\n[[chip]]\nposition = [1, 1]"), Err(Tp3ErrorKind::LayoutBadChip(_))));
+    assert!(matches!(layout_file("orientation", "[[chip]]\nposition = [0, 0]\norientation = 45"), Err(Tp3ErrorKind::LayoutBadChip(_))));
+    assert!(matches!(layout_file("empty", "chip = []"), Err(Tp3ErrorKind::LayoutBadChip(_))));
+    assert!(matches!(layout_file("format", "[[chip]]\nposition = 0"), Err(Tp3ErrorKind::LayoutBadFormat(..))));
+    let touching = [ChipLayout::new((0, 0), false, false), ChipLayout::new((1, 0), false, false), ChipLayout::new((0, 1), false, false)];
+    assert!(DetectorLayout::new(&touching, 0, 0).is_ok());
+}
+
+#[test]
+fn chip_not_in_layout() {
+    let layout = DetectorLayout::new(&[ChipLayout::new((0, 0), false, false), ChipLayout::new((1, 0), false, false)], 1, 0).unwrap();
+    let data = InversePacket::electron_packet(10, 20, 0, Timestamp::zero());
+    assert_eq!(layout.x_y(&PacketEELS { chip_index: 1, data: &data }), Some((CHIP_SIZE + 10, 20)));
+    for ci in [2, 3, 7] {
+        let packet = PacketEELS { chip_index: ci, data: &data };
+        assert_eq!(layout.x_y(&packet), None);
+        assert_eq!(layout.x(&packet), None);
+    }
+}
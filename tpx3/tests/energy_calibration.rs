@@ -0,0 +1,72 @@
+//!Energy of a hit from its ToT, inverting `ToT = a E + b - c / (E - t)` for each pixel.
+
+mod common;
+
+use timepix3::calibrationlib::{EnergyCalibration, PIXELS_PER_CHIP};
+use timepix3::errorlib::Tp3ErrorKind;
+use std::fs;
+
+const DEAD_PIXEL: usize = 3;
+
+///The a, b, c and t maps of a chip, different for each pixel.
+fn maps() -> [Vec<f32>; 4] {
+    let a = (0..PIXELS_PER_CHIP).map(|i| if i == DEAD_PIXEL {0.0} else {1.5 + (i % 7) as f32 * 0.1}).collect();
+    let b = (0..PIXELS_PER_CHIP).map(|i| 20.0 + (i % 11) as f32).collect();
+    let c = (0..PIXELS_PER_CHIP).map(|i| 150.0 + (i % 13) as f32 * 10.0).collect();
+    let t = (0..PIXELS_PER_CHIP).map(|i| 1.0 + (i % 5) as f32 * 0.5).collect();
+    [a, b, c, t]
+}
+
+///The ToT of a hit of `energy` keV, rounded as the detector does.
+fn tot(maps: &[Vec<f32>; 4], index: usize, energy: f32) -> u16 {
+    let [a, b, c, t] = [maps[0][index], maps[1][index], maps[2][index], maps[3][index]];
+    (a * energy + b - c / (energy - t)).round() as u16
+}
+
+fn check_inversion(calibration: &EnergyCalibration, maps: &[Vec<f32>; 4]) {
+    for index in [0, 1, 2, 100, 256 * 128 + 77, PIXELS_PER_CHIP - 1] {
+        for energy in [20.0, 25.0, 59.5, 120.0, 300.0] {
+            let found = calibration.energy_from_tot(index, tot(maps, index, energy));
+            //The ToT is rounded, and dE/dToT is below 1/a above the threshold.
+            assert!((found - energy).abs() <= 0.5 / maps[0][index] + 1e-3, "pixel {}: {} keV found for {} keV", index, found, energy);
+        }
+    }
+    assert_eq!(calibration.energy_from_tot(DEAD_PIXEL, 100), 0.0);
+    //Pixels of chips that are not calibrated keep their ToT.
+    assert_eq!(calibration.energy_from_tot(PIXELS_PER_CHIP, 100), 100.0);
+}
+
+#[test]
+fn energy_from_tot() {
+    let maps = maps();
+    let [a, b, c, t] = maps.clone();
+    check_inversion(&EnergyCalibration::new(a, b, c, t).unwrap(), &maps);
+    assert_eq!(EnergyCalibration::empty().energy_from_tot(10, 42), 42.0);
+}
+
+#[test]
+fn from_files() {
+    let dir = common::temp_dir("energy_calibration");
+    let maps = maps();
+    let paths: Vec<String> = ["a", "b", "c", "t"].iter().zip(maps.iter()).map(|(name, map)| {
+        let path = dir.join(format!("{}.txt", name));
+        //Rows of the chip, separated by spaces and commas.
+        let content = map.chunks(256)
+            .map(|row| row.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", "))
+            .collect::<Vec<String>>()
+            .join("\n");
+        fs::write(&path, content).unwrap();
+        path.display().to_string()
+    }).collect();
+    let paths = [paths[0].as_str(), paths[1].as_str(), paths[2].as_str(), paths[3].as_str()];
+    check_inversion(&EnergyCalibration::from_files(paths).unwrap(), &maps);
+
+    fs::write(paths[2], "1.0 x").unwrap();
+    assert!(matches!(EnergyCalibration::from_files(paths), Err(Tp3ErrorKind::CalibrationBadFormat(_))));
+    fs::write(paths[2], "1.0 2.0").unwrap();
+    assert!(matches!(EnergyCalibration::from_files(paths), Err(Tp3ErrorKind::CalibrationBadFormat(_))));
+    fs::remove_file(paths[2]).unwrap();
+    assert!(matches!(EnergyCalibration::from_files(paths), Err(Tp3ErrorKind::CalibrationNoReadFile(..))));
+    assert!(EnergyCalibration::from_files_or_default(paths).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
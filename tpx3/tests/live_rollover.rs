@@ -0,0 +1,84 @@
+//!Live spectra whose electrons and references straddle the rollover of the electron time.
+
+mod common;
+
+use common::{ScriptedRead, SharedOutput};
+use timepix3::auxiliar::Settings;
+use timepix3::configlib::Tp3Config;
+use timepix3::controllib::AcquisitionControl;
+use timepix3::detectorlib::DetectorLayout;
+use timepix3::modelib;
+use timepix3::packetlib::{Timestamp, Clock640MHz, Clock3840MHz, ELECTRON_ROLLOVER};
+use timepix3::simulationlib::Tpx3Writer;
+use timepix3::tdclib::TdcType;
+use std::sync::mpsc;
+
+const LASER: u64 = 6_400; //Laser period, in units of 640 MHz.
+const TRIGGERS_PER_LINE: u64 = 10;
+const PERIOD: u64 = LASER * TRIGGERS_PER_LINE; //Frame period, in units of 640 MHz.
+const LINES: u64 = 10;
+const WRAPPED_TRIGGER: u64 = 65; //The trigger whose electron arrives after the rollover.
+const X: usize = 100;
+
+fn tdc_time(time: u64) -> Timestamp<Clock3840MHz> {
+    Timestamp::<Clock640MHz>::new(time).convert()
+}
+
+///The time of the first trigger, chosen so the trigger `WRAPPED_TRIGGER` is just before the rollover.
+fn start() -> u64 {
+    ELECTRON_ROLLOVER - WRAPPED_TRIGGER * LASER - 100
+}
+
+///The laser triggers of `line`, each followed by an electron `offset` after it, and the frame TDC
+///closing the line.
+fn line_buffer(layout: DetectorLayout, line: u64, offset: u64) -> Vec<u8> {
+    let mut writer = Tpx3Writer::new(Vec::new(), layout);
+    for trigger in line * TRIGGERS_PER_LINE..(line + 1) * TRIGGERS_PER_LINE {
+        let time = start() + trigger * LASER;
+        writer.write_tdc(&TdcType::TdcTwoRisingEdge, trigger as u16, tdc_time(time)).unwrap();
+        writer.write_electron(X, 128, 100, Timestamp::new(time + offset)).unwrap();
+    }
+    common::write_frame_tdc(&mut writer, line, start() + (line + 1) * PERIOD - 50, 25);
+    writer.finish().unwrap()
+}
+
+///Runs `settings` over all the lines, and gives the nonzero values (line, column, count) of each
+///frame of `height` lines sent.
+fn run(settings: Settings, offset: u64, height: usize) -> Vec<Vec<(usize, usize, u32)>> {
+    let layout = settings.layout;
+    let buffers = (0..LINES).map(|line| line_buffer(layout, line, offset)).collect();
+    let output = SharedOutput::default();
+    let (tx, rx) = mpsc::channel();
+    modelib::run(settings, Box::new(ScriptedRead::from_buffers(buffers, tx)), Box::new(output.clone()), AcquisitionControl::from_channel(rx), &Tp3Config::default()).unwrap();
+
+    let width = layout.chip_array().0;
+    output.values().chunks_exact(height * width + 1)
+        .map(|frame| common::frame_counts(frame, width))
+        .collect()
+}
+
+#[test]
+fn time_delay_across_rollover() {
+    let settings = Settings {xspim_size: 10, time_width: 10_000, ..common::settings(8, DetectorLayout::eels())};
+    //Each electron arrives 3.1 us after its trigger, so in the fourth bin of 1 us.
+    let frames = run(settings, 3 * 640 + 64, settings.xspim_size);
+    assert!(frames.len() >= 3, "The lines around the rollover must be sent.");
+    for frame in frames {
+        assert_eq!(frame, vec![(3, X, TRIGGERS_PER_LINE as u32)]);
+    }
+}
+
+#[test]
+fn live_coincidence_across_rollover() {
+    let layout = DetectorLayout::eels();
+    let width = layout.chip_array().0;
+    let settings = Settings {xspim_size: 10, time_width: 200, ..common::settings(9, layout)};
+    //Each electron arrives 64 ticks after its photon. The lines are the spectrum (with the photon
+    //count in the last column), the coincident spectrum and the histogram of the time differences.
+    let frames = run(settings, 64, 3);
+    assert!(frames.len() >= 3, "The lines around the rollover must be sent.");
+    let count = TRIGGERS_PER_LINE as u32;
+    for frame in frames {
+        assert_eq!(frame, vec![(0, X, count), (0, width - 1, count), (1, X, count), (2, width / 2 + 64, count)]);
+    }
+}
@@ -0,0 +1,73 @@
+//!`.npy` files as NumPy reads them: magic string, header dictionary and little-endian data.
+
+mod common;
+
+use timepix3::npylib::{write_npy, NpyWriter};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+///The type string and the shape of the header, and the data following it.
+fn read_npy(path: &Path) -> (String, Vec<usize>, Vec<u8>) {
+    let bytes = fs::read(path).unwrap();
+    assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+    let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    assert_eq!(10 + len, 128, "The data must start at 128 bytes.");
+    let dict = std::str::from_utf8(&bytes[10..10 + len]).unwrap();
+    assert!(dict.ends_with('\n'));
+    let dict = dict.trim_end();
+    assert!(dict.starts_with('{') && dict.ends_with('}'), "{}", dict);
+
+    let value = |key: &str| {
+        let start = dict.find(&format!("'{}': ", key)).unwrap() + key.len() + 4;
+        let end = match &dict[start..start + 1] {
+            "(" => start + dict[start..].find(')').unwrap() + 1,
+            _ => start + dict[start..].find(',').unwrap(),
+        };
+        dict[start..end].to_string()
+    };
+    assert_eq!(value("fortran_order"), "False");
+    let descr = value("descr").trim_matches('\'').to_string();
+    let shape = value("shape").trim_matches(|c| c == '(' || c == ')').split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().unwrap())
+        .collect();
+    (descr, shape, bytes[10 + len..].to_vec())
+}
+
+#[test]
+fn write_round_trip() {
+    let dir = common::temp_dir("npy_write");
+    let data: Vec<u32> = (0..24).map(|x| x * 1_000_003).collect();
+    write_npy(&dir.join("u32.npy"), &data, &[2, 3, 4]).unwrap();
+    let (descr, shape, bytes) = read_npy(&dir.join("u32.npy"));
+    assert_eq!((descr.as_str(), shape), ("<u4", vec![2, 3, 4]));
+    assert_eq!(bytes.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect::<Vec<u32>>(), data);
+
+    let data = [-1.5f64, 0.0, 1e300];
+    write_npy(&dir.join("f64.npy"), &data, &[3]).unwrap();
+    let (descr, shape, bytes) = read_npy(&dir.join("f64.npy"));
+    assert_eq!((descr.as_str(), shape), ("<f8", vec![3]));
+    assert_eq!(bytes.chunks_exact(8).map(|x| f64::from_le_bytes(x.try_into().unwrap())).collect::<Vec<f64>>(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn appended_array() {
+    let dir = common::temp_dir("npy_append");
+    let path = dir.join("times.npy");
+    let mut writer = NpyWriter::create::<u64>(&path).unwrap();
+    let (_, shape, bytes) = read_npy(&path);
+    assert_eq!((shape, bytes.len()), (vec![0], 0));
+
+    let chunks: [&[u64]; 3] = [&[1, 2, 3], &[], &[u64::MAX, 40]];
+    for chunk in chunks.iter() {
+        writer.append(chunk).unwrap();
+        let (descr, shape, _) = read_npy(&path);
+        assert_eq!((descr.as_str(), shape), ("<u8", vec![writer.len()]));
+    }
+    let (_, _, bytes) = read_npy(&path);
+    assert_eq!(bytes.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect::<Vec<u64>>(), chunks.concat());
+    fs::remove_dir_all(&dir).unwrap();
+}
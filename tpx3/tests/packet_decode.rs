@@ -0,0 +1,120 @@
+//!Decoding of the packets that are not hits: global time, control and extra timestamps, the
+//!`PacketStatistics` counting them, and the global time followed by `TimeExtension`.
+
+use timepix3::packetlib::*;
+use timepix3::tdclib::TdcType;
+
+const GLOBAL_TIME_LOW: u64 = 0x44 << 56;
+const GLOBAL_TIME_HIGH: u64 = 0x45 << 56;
+const END_OF_SEQUENTIAL_COMMAND: u64 = 0x71A0 << 48;
+const END_OF_READOUT: u64 = 0x71B0 << 48;
+
+fn electron(time: u64) -> u64 {
+    u64::from_le_bytes(InversePacket::electron_packet(10, 20, 30, Timestamp::new(time)))
+}
+
+fn tdc(time: u64) -> u64 {
+    u64::from_le_bytes(InversePacket::tdc_packet(&TdcType::TdcTwoRisingEdge, 0, Timestamp::<Clock640MHz>::new(time).convert()))
+}
+
+///Chunk of `packets` sent by the chip `ci`, with its `TPX3` header.
+fn chunk(ci: u8, packets: &[u64]) -> Vec<u8> {
+    let mut data = vec![b'T', b'P', b'X', b'3', ci, 0];
+    data.extend_from_slice(&(8 * packets.len() as u16).to_le_bytes());
+    packets.iter().for_each(|packet| data.extend_from_slice(&packet.to_le_bytes()));
+    data
+}
+
+fn kind(raw: u64) -> PacketKind {
+    PacketEELS { chip_index: 0, data: &raw.to_le_bytes() }.kind()
+}
+
+#[test]
+fn packet_kinds() {
+    assert_eq!(kind(GLOBAL_TIME_LOW | 0xDEAD_BEEF << 16 | 0xFFFF), PacketKind::GlobalTimeLow { time: 0xDEAD_BEEF });
+    assert_eq!(kind(GLOBAL_TIME_HIGH | 0xCAFE << 16 | 0xFFFF), PacketKind::GlobalTimeHigh { time: 0xCAFE });
+    assert_eq!(kind(0x4F << 56), PacketKind::Unknown { id: 4, sub: 15 });
+    assert_eq!(kind(END_OF_READOUT), PacketKind::Control(ControlKind::EndOfReadout));
+    assert_eq!(kind(END_OF_SEQUENTIAL_COMMAND), PacketKind::Control(ControlKind::EndOfSequentialCommand));
+    assert_eq!(kind(0x7290 << 48), PacketKind::Control(ControlKind::Acknowledge { command: 0x0290 }));
+    assert_eq!(kind(0x51 << 56 | 0x12_3456_789A_BCDE), PacketKind::ExtraTimestamp { sub: 1, time: 0x12_3456_789A_BCDE });
+    assert_eq!(kind(0x3A << 56), PacketKind::Unknown { id: 3, sub: 10 });
+    assert_eq!(kind(electron(1_000)), PacketKind::Pixel);
+    assert!(matches!(kind(tdc(1_000)), PacketKind::Tdc { tdc_type, counter: 0, .. } if tdc_type == TdcType::TdcTwoRisingEdge.associate_value()));
+}
+
+///The variant of each event, in order.
+fn variants(stream: &mut PacketStream, data: &[u8]) -> Vec<&'static str> {
+    stream.events(data).map(|event| match event {
+        Event::Electron(_) => "electron",
+        Event::Tdc(_) => "tdc",
+        Event::GlobalTime(_) => "global",
+        Event::Control(_) => "control",
+        Event::ExtraTimestamp(_) => "extra",
+        Event::Other(_) => "other",
+    }).collect()
+}
+
+#[test]
+fn statistics() {
+    let mut data = chunk(0, &[electron(100), electron(200), tdc(300), GLOBAL_TIME_LOW, GLOBAL_TIME_HIGH, electron(400), END_OF_READOUT]);
+    data.extend(chunk(2, &[electron(500), END_OF_READOUT, 0x51 << 56, 0x3A << 56, END_OF_SEQUENTIAL_COMMAND, END_OF_READOUT]));
+    data.extend(chunk(1, &[]));
+    let expected = ["electron", "electron", "tdc", "global", "global", "electron", "control",
+        "electron", "control", "extra", "other", "control", "control"];
+
+    let mut whole = PacketStream::new();
+    assert_eq!(variants(&mut whole, &data), expected);
+    //The same stream, split in buffers that cut the packets and headers anywhere.
+    let mut split = PacketStream::new();
+    let events = data.chunks(5).flat_map(|buffer| variants(&mut split, buffer)).collect::<Vec<&str>>();
+    assert_eq!(events, expected);
+
+    for stream in [whole, split] {
+        let statistics = stream.statistics();
+        assert_eq!(stream.chip_index(), 1);
+        assert_eq!(statistics.chunks, 3);
+        assert_eq!((statistics.pixel(), statistics.tdc(), statistics.global_time(), statistics.control(), statistics.extra_timestamp(), statistics.unknown()), (4, 1, 2, 4, 1, 1));
+        assert_eq!(statistics.total(), expected.len());
+        assert_eq!(statistics.end_of_readout, vec![1, 0, 2]);
+        assert!(statistics.to_string().starts_with("Chunks: 3. Pixels: 4. Counting pixels: 0. Tdcs: 1. Global times: 2."), "{}", statistics);
+    }
+}
+
+///The global time packets of `global`, in units of 40 MHz.
+fn global_time(global: u64) -> [u64; 2] {
+    [GLOBAL_TIME_LOW | (global & 0xFFFF_FFFF) << 16, GLOBAL_TIME_HIGH | (global >> 32) << 16]
+}
+
+#[test]
+fn global_time_across_gap() {
+    let start = 1_000;
+    let first_global = 0xFFFF_F000; //The gap also carries the low word into the high one.
+    for gap in [ELECTRON_ROLLOVER * 3 / 2, ELECTRON_ROLLOVER * 5 / 2 + 12_345] {
+        //Nothing but the global time arrives during the gap, which is longer than a rollover.
+        let mut packets = vec![electron(start)];
+        packets.extend(global_time(first_global));
+        packets.extend(global_time(first_global + gap / 16));
+        packets.push(electron(start + gap + 64));
+        let data = chunk(0, &packets);
+
+        let mut time_ext = TimeExtension::new();
+        let mut without_global = TimeExtension::new();
+        let mut times = Vec::new();
+        let mut stream = PacketStream::new();
+        for event in stream.events(&data) {
+            match event {
+                Event::Electron(packet) => {
+                    times.push(time_ext.electron_time(&packet));
+                    without_global.electron_time(&packet);
+                },
+                Event::GlobalTime(packet) => time_ext.upt_global(&packet),
+                _ => {},
+            }
+        }
+        assert_eq!(times, vec![Timestamp::new(start), Timestamp::new(start + gap + 64)]);
+        assert_eq!(time_ext.current(), Timestamp::new(start + gap + 64));
+        //The epochs skipped are only known from the global time.
+        assert_ne!(without_global.current(), time_ext.current());
+    }
+}
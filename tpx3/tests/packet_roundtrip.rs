@@ -0,0 +1,222 @@
+//!Encode→decode round trips of the TPX3 packets, through `InversePacket` and the `Packet` accessors.
+
+use timepix3::packetlib::*;
+use timepix3::tdclib::TdcType;
+use timepix3::detectorlib::{DetectorLayout, ChipLayout, Orientation};
+use timepix3::simulationlib::Tpx3Writer;
+use std::convert::TryInto;
+use proptest::prelude::*;
+
+const TDC_COARSE_ROLLOVER: u64 = 1 << 35;
+
+///Chip index, position (electrons only) and extended time of a decoded event.
+type DecodedEvent = (usize, Option<(usize, usize)>, Timestamp);
+
+fn tdc_types() -> [TdcType; 4] {
+    [TdcType::TdcOneRisingEdge, TdcType::TdcOneFallingEdge, TdcType::TdcTwoRisingEdge, TdcType::TdcTwoFallingEdge]
+}
+
+fn split(array: &[u8; 16]) -> (usize, [u8; 8]) {
+    assert_eq!(&array[0..4], b"TPX3");
+    assert_eq!(array[6..8], [8, 0]);
+    (array[4] as usize, array[8..16].try_into().unwrap())
+}
+
+proptest! {
+    #[test]
+    fn electron_packet_round_trip(ci in 0..4usize, x_raw in 0..256usize, y_raw in 0..256usize, tot in 0..1024u16, time in 0..4 * ELECTRON_ROLLOVER) {
+        let data = InversePacket::electron_packet(x_raw, y_raw, tot, Timestamp::new(time));
+        let packet = PacketEELS { chip_index: ci, data: &data };
+        prop_assert_eq!(packet.kind(), PacketKind::Pixel);
+        prop_assert_eq!(packet.ci(), ci);
+        prop_assert_eq!(packet.x_raw(), x_raw);
+        prop_assert_eq!(packet.y_raw(), y_raw);
+        prop_assert_eq!(packet.tot(), tot);
+        prop_assert_eq!(packet.electron_time() as u64, time % ELECTRON_ROLLOVER);
+        prop_assert_eq!(packet.spidr() as u64, (time % ELECTRON_ROLLOVER) / 262_144);
+        prop_assert_eq!(packet.toa() as u64, (time % 262_144) / 16);
+        prop_assert_eq!(!packet.ftoa() as u64 & 15, time % 16);
+    }
+
+    #[test]
+    fn legacy_electron_array_round_trip(x in 0..1024usize, y in 0..256usize, time in 0..26_843_545_600usize) {
+        let inverse = InversePacket::new_inverse_electron(x, y, time);
+        let (ci, data) = split(&inverse.create_electron_array());
+        let packet = PacketEELS { chip_index: ci, data: &data };
+        let (spidr, toa, ftoa) = inverse.time_to_ticks();
+        prop_assert_eq!(packet.x(), Some(x));
+        prop_assert_eq!(packet.y(), Some(y));
+        prop_assert_eq!(packet.tot(), 1023);
+        prop_assert_eq!(packet.electron_time(), (spidr & 65_535) * 262_144 + toa * 16 + ftoa);
+        //Times are given in ns and decoded in units of 640 MHz (1.5625 ns).
+        prop_assert!((packet.electron_time() as f64 * 1.5625 - time as f64).abs() < 1.5625);
+    }
+
+    #[test]
+    fn tdc_packet_round_trip(kind in 0..4usize, counter in any::<u16>(), time in 0..2 * TDC_COARSE_ROLLOVER * 12) {
+        let kind = tdc_types()[kind];
+        let data = InversePacket::tdc_packet(&kind, counter, Timestamp::new(time));
+        let packet = PacketEELS { chip_index: 0, data: &data };
+        let wrapped = time % (TDC_COARSE_ROLLOVER * 12);
+        prop_assert_eq!(packet.id(), 6);
+        prop_assert_eq!(packet.tdc_type(), kind.associate_value());
+        prop_assert_eq!(packet.tdc_counter(), counter & 4_095);
+        prop_assert_eq!(packet.tdc_coarse() as u64, wrapped / 12);
+        prop_assert_eq!(packet.tdc_fine() as u64, wrapped % 12);
+        prop_assert_eq!(packet.tdc_time_abs() as u64, wrapped);
+        prop_assert_eq!(packet.tdc_time_norm() as u64, (wrapped / 6) % ELECTRON_ROLLOVER);
+        prop_assert_eq!(packet.kind(), PacketKind::Tdc {
+            tdc_type: kind.associate_value(),
+            counter: counter & 4_095,
+            coarse: (wrapped / 12) as usize,
+            fine: (wrapped % 12) as usize,
+        });
+    }
+
+    #[test]
+    fn legacy_tdc_array_round_trip(kind in 0..4usize, counter in 0..10_000usize, time in 0..100_000_000_000usize) {
+        let kind = tdc_types()[kind];
+        let inverse = InversePacket::new_inverse_tdc(time);
+        let (ct, ft) = inverse.tdc_time_to_ticks();
+        let (ci, data) = split(&inverse.create_tdc_array(counter, kind));
+        let packet = PacketEELS { chip_index: ci, data: &data };
+        prop_assert_eq!(packet.tdc_type(), kind.associate_value());
+        prop_assert_eq!(packet.tdc_counter() as usize, counter & 4_095);
+        prop_assert_eq!(packet.tdc_coarse(), ct);
+        prop_assert_eq!(packet.tdc_fine(), ft);
+        prop_assert_eq!(packet.tdc_time(), ct * 2 + ft / 6);
+        prop_assert_eq!(packet.tdc_time_norm(), (ct * 2 + ft / 6) % ELECTRON_ROLLOVER as usize);
+        //Times are given in ns and decoded in units of 640 MHz (1.5625 ns).
+        prop_assert!((packet.tdc_time() as f64 * 1.5625 - time as f64).abs() < 3.125);
+    }
+
+    #[test]
+    fn stream_round_trip(mut times in proptest::collection::vec(0..10 * ELECTRON_ROLLOVER, 1..200), x in 0..1024usize, y in 0..256usize) {
+        times.sort_unstable();
+        //Consecutive hits must be closer than half a rollover to be unwrapped.
+        times.iter_mut().fold(0, |last, time| {*time = (*time).min(last + ELECTRON_ROLLOVER / 2 - 1); *time});
+        let layout = DetectorLayout::eels();
+        let mut writer = Tpx3Writer::new(Vec::new(), layout);
+        for (index, time) in times.iter().enumerate() {
+            writer.write_electron((x + index) % 1024, y, 100, Timestamp::new(*time)).unwrap();
+            writer.write_tdc(&TdcType::TdcTwoRisingEdge, index as u16, Timestamp::<Clock640MHz>::new(*time).convert()).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let mut stream = PacketStream::new();
+        let mut time_ext = TimeExtension::new();
+        let events: Vec<DecodedEvent> = stream.events(&bytes).map(|event| match event {
+            Event::Electron(packet) => (packet.ci(), layout.x_y(&packet), time_ext.electron_time(&packet)),
+            Event::Tdc(packet) => (packet.ci(), None, time_ext.tdc_time(&packet)),
+            _ => panic!("Unexpected event."),
+        }).collect();
+        prop_assert_eq!(events.len(), 2 * times.len());
+        for (index, (time, pair)) in times.iter().zip(events.chunks_exact(2)).enumerate() {
+            let position = ((x + index) % 1024, y);
+            prop_assert_eq!(pair[0], (layout.chip_address(position.0, position.1).unwrap().0, Some(position), Timestamp::new(*time)));
+            prop_assert_eq!(pair[1], (pair[0].0, None, Timestamp::new(*time)));
+        }
+    }
+
+    #[test]
+    fn stream_split_round_trip(times in proptest::collection::vec(0..ELECTRON_ROLLOVER, 1..100), x in 0..1024usize, cuts in proptest::collection::vec(0..64usize, 0..50)) {
+        let layout = DetectorLayout::eels();
+        let mut writer = Tpx3Writer::new(Vec::new(), layout);
+        for (index, time) in times.iter().enumerate() {
+            writer.write_electron((x + 257 * index) % 1024, 0, 100, Timestamp::new(*time)).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let decode = |stream: &mut PacketStream, data: &[u8]| stream.events(data)
+            .map(|event| match event {
+                Event::Electron(packet) => (packet.ci(), *packet.data),
+                _ => panic!("Unexpected event."),
+            })
+            .collect::<Vec<(usize, [u8; 8])>>();
+        let expected = decode(&mut PacketStream::new(), &bytes);
+        //Buffers of any length, cutting through packets and chunk headers.
+        let mut stream = PacketStream::new();
+        let mut events = Vec::new();
+        let mut rest = &bytes[..];
+        for cut in cuts {
+            let (buffer, next) = rest.split_at(cut.min(rest.len()));
+            events.extend(decode(&mut stream, buffer));
+            rest = next;
+        }
+        events.extend(decode(&mut stream, rest));
+        prop_assert_eq!(stream.pending(), 0);
+        prop_assert_eq!(events, expected);
+    }
+}
+
+#[test]
+fn every_pixel_of_every_chip() {
+    let orientations = [Orientation::Rotate0, Orientation::Rotate90, Orientation::Rotate180, Orientation::Rotate270];
+    let rotated = orientations.iter().enumerate()
+        .map(|(index, orientation)| ChipLayout { orientation: *orientation, flip_x: index % 2 == 0, flip_y: index / 2 == 0, offset: (index * 260, 3) })
+        .collect::<Vec<ChipLayout>>();
+    for layout in [DetectorLayout::eels(), DetectorLayout::diffraction(), DetectorLayout::new(&rotated, 0, 0).unwrap()] {
+        let mut image = vec![false; layout.size().0 * layout.size().1];
+        for ci in 0..layout.nchips() {
+            for y_raw in 0..256 {
+                for x_raw in 0..256 {
+                    let data = InversePacket::electron_packet(x_raw, y_raw, 0, Timestamp::zero());
+                    let packet = PacketEELS { chip_index: ci, data: &data };
+                    assert_eq!((packet.x_raw(), packet.y_raw()), (x_raw, y_raw));
+                    let (x, y) = layout.x_y(&packet).unwrap();
+                    assert_eq!(layout.chip_address(x, y), Some((ci, x_raw, y_raw)));
+                    assert!(!image[y * layout.size().0 + x], "Two pixels mapped to ({}, {}).", x, y);
+                    image[y * layout.size().0 + x] = true;
+                }
+            }
+        }
+        assert_eq!(image.iter().filter(|pixel| **pixel).count(), layout.nchips() * 256 * 256);
+    }
+}
+
+#[test]
+fn electron_time_rollover() {
+    for time in [0, 15, 16, 262_143, 262_144, ELECTRON_ROLLOVER - 1, ELECTRON_ROLLOVER, ELECTRON_ROLLOVER + 1] {
+        let data = InversePacket::electron_packet(0, 0, 0, Timestamp::new(time));
+        let packet = PacketEELS { chip_index: 0, data: &data };
+        assert_eq!(packet.electron_time() as u64, time % ELECTRON_ROLLOVER);
+    }
+}
+
+#[test]
+fn tot_range() {
+    for tot in 0..1024 {
+        let data = InversePacket::electron_packet(255, 255, tot, Timestamp::new(ELECTRON_ROLLOVER - 1));
+        let packet = PacketEELS { chip_index: 0, data: &data };
+        assert_eq!(packet.tot(), tot);
+        assert_eq!((packet.x_raw(), packet.y_raw()), (255, 255));
+        assert_eq!(packet.electron_time() as u64, ELECTRON_ROLLOVER - 1);
+    }
+}
+
+#[test]
+fn tdc_counter_rollover() {
+    for kind in tdc_types().iter() {
+        for (counter, expected) in [(0, 0), (4_095, 4_095), (4_096, 0), (4_097, 1), (u16::MAX, 4_095)] {
+            let data = InversePacket::tdc_packet(kind, counter, Timestamp::new(12));
+            let packet = PacketEELS { chip_index: 0, data: &data };
+            assert_eq!(packet.tdc_counter(), expected);
+            assert_eq!(packet.tdc_type(), kind.associate_value());
+            assert_eq!(TdcType::associate_value_to_enum(packet.tdc_type()).map(|kind| kind.associate_value()), Some(kind.associate_value()));
+        }
+    }
+}
+
+#[test]
+fn tdc_coarse_rollover() {
+    let last = TDC_COARSE_ROLLOVER * 12 - 1;
+    for (time, coarse, fine) in [(last, TDC_COARSE_ROLLOVER - 1, 11), (last + 1, 0, 0), (last + 12, 0, 11)] {
+        let data = InversePacket::tdc_packet(&TdcType::TdcOneFallingEdge, 0, Timestamp::new(time));
+        let packet = PacketEELS { chip_index: 0, data: &data };
+        assert_eq!((packet.tdc_coarse() as u64, packet.tdc_fine() as u64), (coarse, fine));
+    }
+    //The normalized TDC time follows the electron rollover, not the coarse counter one.
+    for time in [ELECTRON_ROLLOVER * 6 - 6, ELECTRON_ROLLOVER * 6, ELECTRON_ROLLOVER * 6 + 6] {
+        let data = InversePacket::tdc_packet(&TdcType::TdcOneFallingEdge, 0, Timestamp::new(time));
+        let packet = PacketEELS { chip_index: 0, data: &data };
+        assert_eq!(packet.tdc_time_norm() as u64, (time / 6) % ELECTRON_ROLLOVER);
+    }
+}
@@ -0,0 +1,36 @@
+//!Period and high time of a `PeriodicTdcRef`, found from the first TDCs received.
+
+use timepix3::detectorlib::DetectorLayout;
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::packetlib::{Timestamp, Clock640MHz};
+use timepix3::simulationlib::Tpx3Writer;
+use timepix3::tdclib::{TdcControl, TdcType, PeriodicTdcRef};
+
+///Creates the reference of `TdcOneRisingEdge` from TDCs (rising edge, time in units of 640 MHz).
+fn reference(edges: &[(bool, u64)]) -> Result<PeriodicTdcRef, Tp3ErrorKind> {
+    let mut writer = Tpx3Writer::new(Vec::new(), DetectorLayout::eels());
+    for (counter, &(rising, time)) in edges.iter().enumerate() {
+        let kind = if rising {TdcType::TdcOneRisingEdge} else {TdcType::TdcOneFallingEdge};
+        writer.write_tdc(&kind, counter as u16, Timestamp::<Clock640MHz>::new(time).convert()).unwrap();
+    }
+    let data = writer.finish().unwrap();
+    PeriodicTdcRef::new(TdcType::TdcOneRisingEdge, &mut Box::new(data.as_slice()), None)
+}
+
+#[test]
+fn period_and_high_time() {
+    let tdc = reference(&[(true, 1_000), (false, 1_030), (true, 1_100), (false, 1_130), (true, 1_200), (false, 1_230)]).unwrap();
+    assert_eq!(tdc.period, Timestamp::new(100));
+    assert_eq!(tdc.high_time, Timestamp::new(30));
+    assert_eq!(tdc.low_time, Timestamp::new(70));
+}
+
+#[test]
+fn noisy_edges() {
+    //The last two rising edges are closer than the high time.
+    let short_period = reference(&[(true, 1_000), (true, 1_100), (true, 1_110), (false, 1_150)]);
+    assert!(matches!(short_period, Err(Tp3ErrorKind::TdcBadHighTime(_))), "{:?}", short_period);
+    //The falling edge comes before the rising edge it should close.
+    let early_falling = reference(&[(false, 1_050), (true, 1_100), (true, 1_200), (true, 1_300)]);
+    assert!(matches!(early_falling, Err(Tp3ErrorKind::TdcBadHighTime(_))), "{:?}", early_falling);
+}
@@ -0,0 +1,150 @@
+//!The cluster removal and the coincidence search split the electrons over the rayon threads. Their
+//!results must not depend on the number of threads.
+
+mod common;
+
+use timepix3::auxiliar::ConfigAcquisition;
+use timepix3::calibrationlib::EnergyCalibration;
+use timepix3::clusterlib::cluster::{CollectionElectron, SingleElectron};
+use timepix3::configlib::Tp3Config;
+use timepix3::detectorlib::DetectorLayout;
+use timepix3::packetlib::{InversePacket, PacketEELS, Timestamp, Clock640MHz};
+use timepix3::postlib::coincidence::{ElectronData, search_coincidence};
+use timepix3::postlib::dataset::Dataset;
+use timepix3::simulationlib::Tpx3Writer;
+use timepix3::tdclib::TdcType;
+use std::fs;
+use std::path::Path;
+
+const THREADS: [usize; 4] = [1, 2, 3, 8];
+const PHOTON_PERIOD: u64 = 1_000; //In units of 640 MHz.
+const DELAY: u64 = 103; //Default delay of the photons, in units of 640 MHz.
+
+fn with_threads<T: Send>(threads: usize, f: impl FnOnce() -> T + Send) -> T {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap().install(f)
+}
+
+///Deterministic pseudo-random numbers.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) % max
+    }
+}
+
+///Hits (x, y, time) of a cluster at a random position, starting at `time`. Clusters overlap in
+///time, so segment boundaries are often searched among their hits.
+fn cluster(rng: &mut Lcg, time: u64) -> Vec<(usize, usize, u64)> {
+    let (x, y) = (2 + rng.next(1_000) as usize, 2 + rng.next(250) as usize);
+    let hits = 1 + rng.next(6) as usize;
+    (0..hits).map(|hit| (x + hit % 2, y + hit / 2 % 2, time + hit as u64 * rng.next(100))).collect()
+}
+
+fn electrons(rng: &mut Lcg, nclusters: usize) -> Vec<(usize, usize, u64)> {
+    let mut time = 1_000;
+    let mut hits = Vec::new();
+    for _ in 0..nclusters {
+        time += rng.next(400);
+        hits.extend(cluster(rng, time));
+    }
+    hits.sort_by_key(|&(_, _, time)| time);
+    hits
+}
+
+fn collection(hits: &[(usize, usize, u64)], layout: &DetectorLayout) -> CollectionElectron {
+    let calibration = EnergyCalibration::empty();
+    let mut collection = CollectionElectron::new();
+    for &(x, y, time) in hits {
+        let (ci, x_raw, y_raw) = layout.chip_address(x, y).unwrap();
+        let data = InversePacket::electron_packet(x_raw, y_raw, 10, Timestamp::new(time));
+        let packet = PacketEELS {chip_index: ci, data: &data};
+        collection.add_electron(SingleElectron::new(&packet, Timestamp::new(time), None, 0, layout, &calibration).unwrap());
+    }
+    collection
+}
+
+#[test]
+fn clean_any_thread_count() {
+    let layout = DetectorLayout::eels();
+    let hits = electrons(&mut Lcg(7), 20_000);
+    let clean = |threads| with_threads(threads, || {
+        let mut collection = collection(&hits, &layout);
+        collection.clean();
+        collection.values()
+            .map(|se| (se.time(), se.x(), se.y(), se.tot(), se.cluster_size()))
+            .collect::<Vec<_>>()
+    });
+    let sequential = clean(1);
+    assert!(sequential.len() < hits.len(), "Clusters must be found.");
+    for threads in &THREADS[1..] {
+        assert_eq!(clean(*threads), sequential, "{} threads", threads);
+    }
+}
+
+///A dataset whose electrons come in clusters, half of them with a photon `DELAY` after them.
+fn write_dataset(path: &Path, layout: DetectorLayout) {
+    let mut rng = Lcg(11);
+    let mut writer = Tpx3Writer::new(Vec::new(), layout);
+    for index in 0..4 {
+        writer.write_tdc(&TdcType::TdcOneFallingEdge, index, Timestamp::<Clock640MHz>::new(100 + index as u64).convert()).unwrap();
+    }
+    let mut events: Vec<(u64, Option<(usize, usize)>)> = Vec::new();
+    for index in 0..20_000 {
+        let time = 1_000 + index * PHOTON_PERIOD;
+        events.extend(cluster(&mut rng, time).into_iter().map(|(x, y, time)| (time, Some((x, y)))));
+        if rng.next(2) == 0 {
+            events.push((time + DELAY + rng.next(8), None));
+        }
+    }
+    events.sort_by_key(|&(time, _)| time);
+    for (counter, (time, hit)) in events.into_iter().enumerate() {
+        match hit {
+            Some((x, y)) => writer.write_electron(x, y, 10, Timestamp::new(time)).unwrap(),
+            None => writer.write_tdc(&TdcType::TdcTwoRisingEdge, counter as u16, Timestamp::<Clock640MHz>::new(time).convert()).unwrap(),
+        }
+    }
+    fs::write(path, writer.finish().unwrap()).unwrap();
+}
+
+///The content of every output of the coincidence search, by file name.
+fn coincidences(file: &Path, output_dir: &Path, threads: usize) -> Vec<(String, Vec<u8>)> {
+    let mut config = Tp3Config::default();
+    config.coincidence.output_dir = output_dir.display().to_string();
+    let args: Vec<String> = ["tp3_coin", &file.display().to_string(), "0", "1", "1"].iter().map(|arg| arg.to_string()).collect();
+    let config_set = ConfigAcquisition::new(&args, &config).unwrap();
+    let dataset = Dataset::new(config_set.file()).unwrap();
+    let mut coinc_data = ElectronData::new(&config_set).unwrap();
+    with_threads(threads, || search_coincidence(&dataset, &mut coinc_data)).unwrap();
+    coinc_data.output_spectrum(true).unwrap();
+    coinc_data.output_corr_spectrum(true).unwrap();
+    coinc_data.output_acc_spectrum(true).unwrap();
+    coinc_data.output_g2().unwrap();
+    coinc_data.output_relative_time().unwrap();
+    coinc_data.output_dispersive().unwrap();
+    coinc_data.output_non_dispersive().unwrap();
+    coinc_data.output_channel().unwrap();
+
+    let mut outputs: Vec<(String, Vec<u8>)> = fs::read_dir(output_dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| (path.file_name().unwrap().to_string_lossy().to_string(), fs::read(&path).unwrap()))
+        .collect();
+    outputs.sort();
+    fs::remove_dir_all(output_dir).unwrap();
+    outputs
+}
+
+#[test]
+fn coincidence_any_thread_count() {
+    let dir = common::temp_dir("thread_count");
+    let file = dir.join("data.tpx3");
+    write_dataset(&file, Tp3Config::default().layout().unwrap());
+    let sequential = coincidences(&file, &dir.join("1"), 1);
+    let relative_time = &sequential.iter().find(|(name, _)| name == "tH.txt").unwrap().1;
+    assert!(relative_time.len() > 1_000, "Coincidences must be found.");
+    for threads in &THREADS[1..] {
+        assert!(coincidences(&file, &dir.join(threads.to_string()), *threads) == sequential, "{} threads", threads);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}